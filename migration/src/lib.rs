@@ -1,12 +1,18 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20230612_000001_create_parent_chunk_table;
+mod m20230620_000001_create_embedding_cache_table;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20230612_000001_create_parent_chunk_table::Migration),
+            Box::new(m20230620_000001_create_embedding_cache_table::Migration),
+        ]
     }
 }
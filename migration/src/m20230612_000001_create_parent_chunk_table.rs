@@ -0,0 +1,64 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Index::Table)
+                    .add_column(ColumnDef::new(Index::ChunkingParams).json())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(ParentChunk::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ParentChunk::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ParentChunk::IndexName).string().not_null())
+                    .col(ColumnDef::new(ParentChunk::DocumentId).string().not_null())
+                    .col(ColumnDef::new(ParentChunk::Text).text().not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ParentChunk::Table).to_owned())
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Index::Table)
+                    .drop_column(Index::ChunkingParams)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum Index {
+    Table,
+    ChunkingParams,
+}
+
+#[derive(Iden)]
+enum ParentChunk {
+    Table,
+    Id,
+    IndexName,
+    DocumentId,
+    Text,
+}
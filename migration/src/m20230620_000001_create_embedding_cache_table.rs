@@ -0,0 +1,55 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(EmbeddingCache::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(EmbeddingCache::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(EmbeddingCache::Model).string().not_null())
+                    .col(ColumnDef::new(EmbeddingCache::Embedding).binary().not_null())
+                    .col(
+                        ColumnDef::new(EmbeddingCache::LastUsedAt)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_embedding_cache_last_used_at")
+                    .table(EmbeddingCache::Table)
+                    .col(EmbeddingCache::LastUsedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(EmbeddingCache::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum EmbeddingCache {
+    Table,
+    Id,
+    Model,
+    Embedding,
+    LastUsedAt,
+}
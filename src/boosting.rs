@@ -0,0 +1,197 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// An adjustment of the similarity score of search results, based on their metadata.
+/// Boosts multiply the score, and leave it unchanged when a result doesn't have the field.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreBoost {
+    /// Halves the score every `half_life_secs` between a timestamp and now. Timestamps are
+    /// either seconds since the unix epoch or RFC 3339 strings.
+    TimeDecay { field: String, half_life_secs: f64 },
+
+    /// Multiplies the score by `factor` when the field is equal to `value`.
+    Match {
+        field: String,
+        value: String,
+        factor: f32,
+    },
+
+    /// Multiplies the score by the numeric value of the field.
+    Weight { field: String },
+}
+
+/// The factor that a boost multiplied the score of a result by.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BoostFactor {
+    pub boost: String,
+    pub field: String,
+    pub factor: f32,
+}
+
+/// The components that the final score of a result is the product of.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScoreComponents {
+    pub similarity: f32,
+    pub boosts: Vec<BoostFactor>,
+}
+
+impl ScoreBoost {
+    fn name(&self) -> &'static str {
+        match self {
+            ScoreBoost::TimeDecay { .. } => "time_decay",
+            ScoreBoost::Match { .. } => "match",
+            ScoreBoost::Weight { .. } => "weight",
+        }
+    }
+
+    fn field(&self) -> &str {
+        match self {
+            ScoreBoost::TimeDecay { field, .. } => field,
+            ScoreBoost::Match { field, .. } => field,
+            ScoreBoost::Weight { field } => field,
+        }
+    }
+
+    /// Returns the factor the score of a result with the given metadata is multiplied by.
+    pub fn factor(&self, metadata: &serde_json::Value, now: DateTime<Utc>) -> f32 {
+        let value = match metadata.get(self.field()) {
+            Some(value) => value,
+            None => return 1.0,
+        };
+        match self {
+            ScoreBoost::TimeDecay { half_life_secs, .. } => match to_timestamp(value) {
+                Some(timestamp) if *half_life_secs > 0.0 => {
+                    let now = now.timestamp_millis() as f64 / 1000.0;
+                    let age = (now - timestamp).max(0.0);
+                    0.5_f64.powf(age / half_life_secs) as f32
+                }
+                _ => 1.0,
+            },
+            ScoreBoost::Match {
+                value: expected,
+                factor,
+                ..
+            } => {
+                let matches = match value {
+                    serde_json::Value::String(s) => s == expected,
+                    other => serde_json::from_str::<serde_json::Value>(expected)
+                        .is_ok_and(|expected| expected == *other),
+                };
+                if matches {
+                    *factor
+                } else {
+                    1.0
+                }
+            }
+            ScoreBoost::Weight { .. } => to_number(value).map(|w| w as f32).unwrap_or(1.0),
+        }
+    }
+}
+
+fn to_number(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::String(s) => s.trim().parse().ok(),
+        other => other.as_f64(),
+    }
+}
+
+fn to_timestamp(value: &serde_json::Value) -> Option<f64> {
+    if let Some(seconds) = to_number(value) {
+        return Some(seconds);
+    }
+    let datetime = DateTime::parse_from_rfc3339(value.as_str()?).ok()?;
+    Some(datetime.timestamp_millis() as f64 / 1000.0)
+}
+
+/// Applies the boosts to the similarity score of a result, and returns the final score
+/// along with its components.
+pub fn apply_boosts(
+    similarity: f32,
+    metadata: &serde_json::Value,
+    boosts: &[ScoreBoost],
+    now: DateTime<Utc>,
+) -> (f32, ScoreComponents) {
+    let mut score = similarity;
+    let mut components = ScoreComponents {
+        similarity,
+        boosts: Vec::with_capacity(boosts.len()),
+    };
+    for boost in boosts {
+        let factor = boost.factor(metadata, now);
+        score *= factor;
+        components.boosts.push(BoostFactor {
+            boost: boost.name().into(),
+            field: boost.field().into(),
+            factor,
+        });
+    }
+    (score, components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_time_decay() {
+        let boost = ScoreBoost::TimeDecay {
+            field: "published_at".into(),
+            half_life_secs: 86400.0,
+        };
+        let day_old = json!({"published_at": "2023-05-31T00:00:00Z"});
+        assert_eq!(boost.factor(&day_old, now()), 0.5);
+
+        let two_days_old = json!({ "published_at": (now().timestamp() - 2 * 86400).to_string() });
+        assert_eq!(boost.factor(&two_days_old, now()), 0.25);
+
+        assert_eq!(boost.factor(&json!({}), now()), 1.0);
+    }
+
+    #[test]
+    fn test_match_and_weight() {
+        let metadata = json!({"source": "docs", "popularity": "1.5"});
+        let matching = ScoreBoost::Match {
+            field: "source".into(),
+            value: "docs".into(),
+            factor: 2.0,
+        };
+        let not_matching = ScoreBoost::Match {
+            field: "source".into(),
+            value: "tickets".into(),
+            factor: 2.0,
+        };
+        let weight = ScoreBoost::Weight {
+            field: "popularity".into(),
+        };
+        assert_eq!(matching.factor(&metadata, now()), 2.0);
+        assert_eq!(not_matching.factor(&metadata, now()), 1.0);
+        assert_eq!(weight.factor(&metadata, now()), 1.5);
+    }
+
+    #[test]
+    fn test_apply_boosts() {
+        let metadata = json!({"source": "docs", "popularity": "3"});
+        let boosts = vec![
+            ScoreBoost::Match {
+                field: "source".into(),
+                value: "docs".into(),
+                factor: 2.0,
+            },
+            ScoreBoost::Weight {
+                field: "popularity".into(),
+            },
+        ];
+        let (score, components) = apply_boosts(0.5, &metadata, &boosts, now());
+        assert_eq!(score, 3.0);
+        assert_eq!(components.similarity, 0.5);
+        assert_eq!(components.boosts.len(), 2);
+        assert_eq!(components.boosts[1].factor, 3.0);
+    }
+}
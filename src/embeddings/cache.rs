@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+use md5::{Digest, Md5};
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, Database, DatabaseConnection, DbErr, EntityTrait, PaginatorTrait,
+    QueryFilter, QueryOrder, QuerySelect, Schema, Set,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::entity::embedding_cache;
+use crate::entity::embedding_cache::Entity as EmbeddingCacheEntity;
+use crate::EmbeddingModel;
+
+/// Maximum number of keys looked up or deleted in one query, below the limit of SQLite on
+/// the number of parameters of a statement.
+const KEYS_PER_QUERY: usize = 500;
+
+/// Counters of the lookups of the cache, since the server started.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+    pub entries: u64,
+}
+
+/// A cache of embeddings in a SQL database, keyed by model, fingerprint of the settings of
+/// the model and hash of the text. Beyond `max_entries`, the least recently used embeddings
+/// are evicted in batches of a tenth of the cache.
+pub struct EmbeddingCache {
+    conn: DatabaseConnection,
+    max_entries: u64,
+    /// Upper bound of the number of entries, counted again when it exceeds `max_entries`.
+    entries: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Returns a hash of the settings of a model that determine its embeddings, so that the
+/// embeddings cached before the model was reconfigured are not served.
+pub fn model_fingerprint(model: &EmbeddingModel) -> String {
+    fingerprint(&json!({
+        "model": model.model_kind,
+        "path": model.path,
+        "architecture": model.architecture,
+        "dimensions": model.dimensions,
+        "max_sequence_length": model.max_sequence_length,
+        "pooling": model.pooling,
+        "onnx": model.onnx,
+        "openai_compatible": model
+            .openai_compatible
+            .as_ref()
+            .map(|c| (&c.base_url, &c.model)),
+    }))
+}
+
+/// Returns a hash of the fingerprints of the models of a fallback chain.
+pub fn chain_fingerprint(fingerprints: &[String]) -> String {
+    fingerprint(&fingerprints)
+}
+
+fn fingerprint<T: Serialize>(settings: &T) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(serde_json::to_vec(settings).unwrap_or_default());
+    format!("{:x}", hasher.finalize())
+}
+
+impl EmbeddingCache {
+    /// Connects to the database of the cache, and creates its table unless it exists, so
+    /// a local database needs no migration.
+    pub async fn new(db_url: &str, max_entries: u64) -> Result<Self, DbErr> {
+        let conn = Database::connect(db_url).await?;
+        let backend = conn.get_database_backend();
+        let mut create_table = Schema::new(backend).create_table_from_entity(EmbeddingCacheEntity);
+        create_table.if_not_exists();
+        conn.execute(backend.build(&create_table)).await?;
+        let entries = EmbeddingCacheEntity::find().count(&conn).await?;
+        Ok(Self {
+            conn,
+            max_entries,
+            entries: AtomicU64::new(entries),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    fn key(model: &str, fingerprint: &str, text: &str) -> String {
+        let mut hasher = Md5::new();
+        hasher.update(text);
+        format!("{}:{}:{:x}", model, fingerprint, hasher.finalize())
+    }
+
+    /// Returns the cached embedding of each input, if any, and marks them as used.
+    pub async fn get(
+        &self,
+        model: &str,
+        fingerprint: &str,
+        inputs: &[String],
+    ) -> Result<Vec<Option<Vec<f32>>>, DbErr> {
+        let keys: Vec<String> = inputs
+            .iter()
+            .map(|i| Self::key(model, fingerprint, i))
+            .collect();
+        let mut cached = HashMap::new();
+        for chunk in keys.chunks(KEYS_PER_QUERY) {
+            let entries = EmbeddingCacheEntity::find()
+                .filter(embedding_cache::Column::Id.is_in(chunk.to_vec()))
+                .all(&self.conn)
+                .await?;
+            cached.extend(entries.into_iter().map(|e| (e.id, e.embedding)));
+        }
+        if !cached.is_empty() {
+            let used: Vec<String> = cached.keys().cloned().collect();
+            for chunk in used.chunks(KEYS_PER_QUERY) {
+                EmbeddingCacheEntity::update_many()
+                    .col_expr(
+                        embedding_cache::Column::LastUsedAt,
+                        Expr::value(Utc::now().timestamp_millis()),
+                    )
+                    .filter(embedding_cache::Column::Id.is_in(chunk.to_vec()))
+                    .exec(&self.conn)
+                    .await?;
+            }
+        }
+        let embeddings: Vec<Option<Vec<f32>>> = keys
+            .iter()
+            .map(|key| cached.get(key).map(|bytes| decode(bytes)))
+            .collect();
+        let hits = embeddings.iter().filter(|e| e.is_some()).count() as u64;
+        self.hits.fetch_add(hits, Ordering::Relaxed);
+        self.misses
+            .fetch_add(embeddings.len() as u64 - hits, Ordering::Relaxed);
+        Ok(embeddings)
+    }
+
+    /// Stores the embedding of each input, then evicts the least recently used embeddings
+    /// if the cache is full.
+    pub async fn put(
+        &self,
+        model: &str,
+        fingerprint: &str,
+        inputs: &[String],
+        embeddings: &[Vec<f32>],
+    ) -> Result<(), DbErr> {
+        let now = Utc::now().timestamp_millis();
+        // Duplicate inputs would conflict twice with the same row in a single insert
+        let entries: HashMap<String, embedding_cache::ActiveModel> = inputs
+            .iter()
+            .zip(embeddings)
+            .map(|(input, embedding)| {
+                let key = Self::key(model, fingerprint, input);
+                let entry = embedding_cache::ActiveModel {
+                    id: Set(key.clone()),
+                    model: Set(model.to_string()),
+                    embedding: Set(encode(embedding)),
+                    last_used_at: Set(now),
+                };
+                (key, entry)
+            })
+            .collect();
+        let entries: Vec<embedding_cache::ActiveModel> = entries.into_values().collect();
+        for chunk in entries.chunks(KEYS_PER_QUERY / 4) {
+            EmbeddingCacheEntity::insert_many(chunk.to_vec())
+                .on_conflict(
+                    OnConflict::column(embedding_cache::Column::Id)
+                        .update_columns([
+                            embedding_cache::Column::Embedding,
+                            embedding_cache::Column::LastUsedAt,
+                        ])
+                        .to_owned(),
+                )
+                .exec(&self.conn)
+                .await?;
+        }
+        // Entries that replaced existing ones are counted too, until the next eviction
+        let entries = self
+            .entries
+            .fetch_add(entries.len() as u64, Ordering::Relaxed)
+            + entries.len() as u64;
+        if entries > self.max_entries {
+            self.evict().await?;
+        }
+        Ok(())
+    }
+
+    /// Evicts the least recently used embeddings beyond a tenth below `max_entries`, so
+    /// that the cache is only counted and evicted again after as many embeddings are added.
+    async fn evict(&self) -> Result<(), DbErr> {
+        let entries = EmbeddingCacheEntity::find().count(&self.conn).await?;
+        let retained = self.max_entries - self.max_entries / 10;
+        if entries <= retained {
+            self.entries.store(entries, Ordering::Relaxed);
+            return Ok(());
+        }
+        let evicted: Vec<String> = EmbeddingCacheEntity::find()
+            .select_only()
+            .column(embedding_cache::Column::Id)
+            .order_by_asc(embedding_cache::Column::LastUsedAt)
+            .limit(entries - retained)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+        for chunk in evicted.chunks(KEYS_PER_QUERY) {
+            EmbeddingCacheEntity::delete_many()
+                .filter(embedding_cache::Column::Id.is_in(chunk.to_vec()))
+                .exec(&self.conn)
+                .await?;
+        }
+        self.entries.store(retained, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns the hits and misses of the cache, and the number of embeddings it holds.
+    pub async fn stats(&self) -> Result<CacheStats, DbErr> {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+        Ok(CacheStats {
+            hits,
+            misses,
+            hit_rate: match lookups {
+                0 => 0.0,
+                _ => hits as f64 / lookups as f64,
+            },
+            entries: EmbeddingCacheEntity::find().count(&self.conn).await?,
+        })
+    }
+}
+
+fn encode(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|e| e.to_le_bytes()).collect()
+}
+
+fn decode(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used() {
+        let cache = EmbeddingCache::new("sqlite::memory:", 2).await.unwrap();
+        let inputs: Vec<String> = vec!["a".into(), "b".into()];
+        cache
+            .put("model", "v1", &inputs, &[vec![1.0, 0.5], vec![2.0, 0.5]])
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.get("other", "v1", &inputs[..1]).await.unwrap(),
+            vec![None]
+        );
+        assert_eq!(
+            cache.get("model", "v2", &inputs[..1]).await.unwrap(),
+            vec![None]
+        );
+
+        // Using `a` leaves `b` as the least recently used embedding
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        assert_eq!(
+            cache.get("model", "v1", &inputs[..1]).await.unwrap(),
+            vec![Some(vec![1.0, 0.5])]
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        cache
+            .put("model", "v1", &["c".into()], &[vec![3.0, 0.5]])
+            .await
+            .unwrap();
+        let embeddings = cache
+            .get("model", "v1", &["a".into(), "b".into(), "c".into()])
+            .await
+            .unwrap();
+        assert_eq!(
+            embeddings,
+            vec![Some(vec![1.0, 0.5]), None, Some(vec![3.0, 0.5])]
+        );
+
+        let stats = cache.stats().await.unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (3, 3, 2));
+        assert_eq!(stats.hit_rate, 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_cache_evicts_in_batches() {
+        let cache = EmbeddingCache::new("sqlite::memory:", 20).await.unwrap();
+        let inputs: Vec<String> = (0..21).map(|i| i.to_string()).collect();
+        let embeddings = vec![vec![1.0]; inputs.len()];
+        cache
+            .put("model", "v1", &inputs[..20], &embeddings[..20])
+            .await
+            .unwrap();
+        assert_eq!(cache.stats().await.unwrap().entries, 20);
+        cache
+            .put("model", "v1", &inputs[20..], &embeddings[20..])
+            .await
+            .unwrap();
+        assert_eq!(cache.stats().await.unwrap().entries, 18);
+    }
+
+    #[test]
+    fn test_model_fingerprint() {
+        let model = EmbeddingModel::default();
+        let pooled = EmbeddingModel {
+            pooling: Some(crate::PoolingKind::Cls),
+            ..model.clone()
+        };
+        let named = EmbeddingModel {
+            name: Some("minilm".into()),
+            ..model.clone()
+        };
+        assert_ne!(model_fingerprint(&model), model_fingerprint(&pooled));
+        assert_eq!(model_fingerprint(&model), model_fingerprint(&named));
+    }
+}
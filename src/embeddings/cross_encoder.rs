@@ -0,0 +1,124 @@
+use rust_bert::bert::{BertConfig, BertForSequenceClassification};
+use rust_bert::Config;
+use rust_tokenizers::tokenizer::{BertTokenizer, Tokenizer, TruncationStrategy};
+use rust_tokenizers::vocab::{BertVocab, Vocab};
+use tch::{nn, no_grad, Device, Kind, Tensor};
+
+use super::server_config::{DeviceKind, EmbeddingModel, EmbeddingModelKind};
+use super::EmbeddingGeneratorError;
+
+/// Maximum number of tokens of a (query, passage) pair, longer pairs are truncated.
+const MAX_SEQUENCE_LENGTH: usize = 512;
+
+/// Files of a cross-encoder model in its local directory. Cross-encoder repositories
+/// don't publish rust-bert weights, so `rust_model.ot` is converted from the PyTorch weights.
+pub const MODEL_FILES: [&str; 3] = ["config.json", "vocab.txt", "rust_model.ot"];
+
+/// A cross-encoder that scores the relevance of passages to a query by encoding
+/// each (query, passage) pair together, which is slower but more accurate than
+/// comparing embeddings of the query and the passages.
+pub struct CrossEncoder {
+    tokenizer: BertTokenizer,
+    model: BertForSequenceClassification,
+    device: Device,
+    // The model's weights live in the var store, so it must be kept alive.
+    _var_store: nn::VarStore,
+}
+
+impl CrossEncoder {
+    /// Loads a cross-encoder model on its device, from its local directory.
+    pub fn new(model: &EmbeddingModel) -> Result<Self, EmbeddingGeneratorError> {
+        if !matches!(model.model_kind, EmbeddingModelKind::MsMarcoMiniLmL6V2) {
+            return Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+                "`{}` is not a cross-encoder model",
+                model.model_kind
+            )));
+        }
+        let path = model
+            .path
+            .as_ref()
+            .ok_or(EmbeddingGeneratorError::ConfigurationError(
+                "path".into(),
+                model.name(),
+            ))?;
+        let device = match model.device_kind {
+            DeviceKind::Gpu => Device::cuda_if_available(),
+            _ => Device::Cpu,
+        };
+        let [config_path, vocab_path, weights_path] =
+            MODEL_FILES.map(|file| std::path::Path::new(path).join(file));
+
+        let config = BertConfig::from_file(config_path);
+        let tokenizer = BertTokenizer::from_file(vocab_path, true, true)
+            .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))?;
+        let mut var_store = nn::VarStore::new(device);
+        let model = BertForSequenceClassification::new(var_store.root(), &config)
+            .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))?;
+        var_store
+            .load(weights_path)
+            .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))?;
+        Ok(Self {
+            tokenizer,
+            model,
+            device,
+            _var_store: var_store,
+        })
+    }
+
+    /// Returns the relevance score of each passage to the query. Higher is more relevant.
+    pub fn score(
+        &self,
+        query: &str,
+        passages: &[String],
+    ) -> Result<Vec<f32>, EmbeddingGeneratorError> {
+        if passages.is_empty() {
+            return Ok(vec![]);
+        }
+        let pairs: Vec<(&str, &str)> = passages.iter().map(|p| (query, p.as_str())).collect();
+        let tokenized = self.tokenizer.encode_pair_list(
+            &pairs,
+            MAX_SEQUENCE_LENGTH,
+            &TruncationStrategy::LongestFirst,
+            0,
+        );
+        let max_len = tokenized
+            .iter()
+            .map(|t| t.token_ids.len())
+            .max()
+            .unwrap_or(0);
+        let pad_id = self.tokenizer.vocab().token_to_id(BertVocab::pad_value());
+
+        let mut input_ids = Vec::with_capacity(tokenized.len());
+        let mut token_type_ids = Vec::with_capacity(tokenized.len());
+        let mut attention_mask = Vec::with_capacity(tokenized.len());
+        for input in tokenized {
+            let len = input.token_ids.len();
+            let mut ids = input.token_ids;
+            ids.resize(max_len, pad_id);
+            let mut segments: Vec<i64> = input.segment_ids.into_iter().map(i64::from).collect();
+            segments.resize(max_len, 0);
+            let mut mask = vec![1i64; len];
+            mask.resize(max_len, 0);
+            input_ids.push(Tensor::of_slice(&ids));
+            token_type_ids.push(Tensor::of_slice(&segments));
+            attention_mask.push(Tensor::of_slice(&mask));
+        }
+        let input_ids = Tensor::stack(&input_ids, 0).to(self.device);
+        let token_type_ids = Tensor::stack(&token_type_ids, 0).to(self.device);
+        let attention_mask = Tensor::stack(&attention_mask, 0).to(self.device);
+
+        let output = no_grad(|| {
+            self.model.forward_t(
+                Some(&input_ids),
+                Some(&attention_mask),
+                Some(&token_type_ids),
+                None,
+                None,
+                false,
+            )
+        });
+        // The models have a single output label, whose logit is the relevance score
+        let logits = output.logits.view(-1).to_kind(Kind::Float).to(Device::Cpu);
+        Vec::<f32>::try_from(logits).map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))
+    }
+}
@@ -0,0 +1,394 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tracing::warn;
+
+use super::server_config::{CircuitBreakerConfig, FallbackChainConfig};
+use super::{
+    EmbeddingGenerator, EmbeddingGeneratorError, EmbeddingGeneratorTS, ModelStatus, Tokens,
+};
+
+/// Counts the consecutive failures of a model, and skips the model for a while once they
+/// reach the threshold. The model is called again once the breaker is no longer open, and a
+/// single failure opens it again until a request succeeds.
+struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    fn allows_requests(&self) -> bool {
+        match self
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .open_until
+        {
+            Some(open_until) => Instant::now() >= open_until,
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner) = BreakerState::default();
+    }
+
+    /// Records a failure, and returns whether it opened the breaker.
+    fn record_failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.consecutive_failures += 1;
+        if state.consecutive_failures < self.config.failure_threshold.max(1) {
+            return false;
+        }
+        state.open_until = Some(Instant::now() + Duration::from_millis(self.config.open_ms));
+        true
+    }
+}
+
+struct Provider {
+    model: String,
+    generator: EmbeddingGeneratorTS,
+    breaker: CircuitBreaker,
+}
+
+/// A model name served by an ordered list of models generating compatible embeddings.
+/// Embeddings are generated by the first model that serves them, skipping the models whose
+/// circuit breaker is open. Only models that are unavailable, overloaded or too slow are
+/// failed over, while other errors, such as invalid inputs, are returned as is. Texts are
+/// tokenized by the first model.
+pub struct FallbackChain {
+    name: String,
+    providers: Vec<Provider>,
+    timeout: Option<Duration>,
+}
+
+impl FallbackChain {
+    /// Creates the chain of the configuration, from the generator of each of its models.
+    pub fn new(config: &FallbackChainConfig, generators: Vec<EmbeddingGeneratorTS>) -> Self {
+        let providers = config
+            .models
+            .iter()
+            .zip(generators)
+            .map(|(model, generator)| Provider {
+                model: model.clone(),
+                generator,
+                breaker: CircuitBreaker::new(config.circuit_breaker.clone()),
+            })
+            .collect();
+        Self {
+            name: config.name.clone(),
+            providers,
+            timeout: config.timeout_ms.map(Duration::from_millis),
+        }
+    }
+
+    /// Returns whether an error is a failure of the model or its provider that another model
+    /// may not have, rather than a failure of the request.
+    fn is_transient(err: &EmbeddingGeneratorError) -> bool {
+        matches!(
+            err,
+            EmbeddingGeneratorError::Unavailable(..)
+                | EmbeddingGeneratorError::Overloaded(_)
+                | EmbeddingGeneratorError::ProviderError(..)
+        )
+    }
+
+    fn first(&self) -> &Provider {
+        &self.providers[0]
+    }
+
+    async fn try_generate(
+        &self,
+        provider: &Provider,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        let request = provider
+            .generator
+            .generate_embeddings(inputs, provider.model.clone());
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request).await.map_err(|_| {
+                EmbeddingGeneratorError::Unavailable(
+                    provider.model.clone(),
+                    format!("no response in {:?}", timeout),
+                )
+            })?,
+            None => request.await,
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingGenerator for FallbackChain {
+    async fn generate_embeddings(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        let mut last_error = None;
+        for provider in &self.providers {
+            if !provider.breaker.allows_requests() {
+                continue;
+            }
+            match self.try_generate(provider, inputs.clone()).await {
+                Ok(embeddings) => {
+                    provider.breaker.record_success();
+                    return Ok(embeddings);
+                }
+                Err(e) if !Self::is_transient(&e) => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "model `{}` of `{}` failed, falling back: {}",
+                        provider.model, self.name, e
+                    );
+                    if provider.breaker.record_failure() {
+                        warn!(
+                            "skipping model `{}` of `{}` for a while",
+                            provider.model, self.name
+                        );
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(EmbeddingGeneratorError::Unavailable(
+            self.name.clone(),
+            match last_error {
+                Some(e) => e.to_string(),
+                None => "every model is failing".into(),
+            },
+        ))
+    }
+
+    fn dimensions(&self, _model: String) -> Result<u64, EmbeddingGeneratorError> {
+        self.first()
+            .generator
+            .dimensions(self.first().model.clone())
+    }
+
+    fn max_input_tokens(&self, _model: String) -> Option<usize> {
+        self.first()
+            .generator
+            .max_input_tokens(self.first().model.clone())
+    }
+
+    /// The chain is ready when one of its models is, or is loaded on demand.
+    fn status(&self, _model: String) -> Result<ModelStatus, EmbeddingGeneratorError> {
+        let mut statuses = Vec::new();
+        for provider in &self.providers {
+            let status = provider.generator.status(provider.model.clone())?;
+            if status.is_available() {
+                return Ok(status);
+            }
+            statuses.push(status);
+        }
+        Ok(statuses.swap_remove(0))
+    }
+
+    async fn tokenize_text(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Vec<String>>, EmbeddingGeneratorError> {
+        let first = self.first();
+        first
+            .generator
+            .tokenize_text(inputs, first.model.clone())
+            .await
+    }
+
+    async fn tokenize_encode(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Vec<i64>>, EmbeddingGeneratorError> {
+        let first = self.first();
+        first
+            .generator
+            .tokenize_encode(inputs, first.model.clone())
+            .await
+    }
+
+    async fn tokenize_decode(
+        &self,
+        inputs: Vec<Vec<i64>>,
+        _model: String,
+    ) -> Result<Vec<String>, EmbeddingGeneratorError> {
+        let first = self.first();
+        first
+            .generator
+            .tokenize_decode(inputs, first.model.clone())
+            .await
+    }
+
+    async fn tokenize_encode_text(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Tokens>, EmbeddingGeneratorError> {
+        let first = self.first();
+        first
+            .generator
+            .tokenize_encode_text(inputs, first.model.clone())
+            .await
+    }
+
+    async fn count_tokens(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<usize>, EmbeddingGeneratorError> {
+        let first = self.first();
+        first
+            .generator
+            .count_tokens(inputs, first.model.clone())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::MockGenerator;
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn chain(generators: Vec<Arc<MockGenerator>>, timeout_ms: Option<u64>) -> FallbackChain {
+        let config = FallbackChainConfig {
+            name: "chain".into(),
+            models: (0..generators.len())
+                .map(|i| format!("model-{}", i))
+                .collect(),
+            timeout_ms,
+            circuit_breaker: CircuitBreakerConfig {
+                failure_threshold: 2,
+                open_ms: 100,
+            },
+        };
+        let generators = generators.into_iter().map(|g| g as _).collect();
+        FallbackChain::new(&config, generators)
+    }
+
+    #[tokio::test]
+    async fn test_fallback_with_circuit_breaker() {
+        let primary = Arc::new(MockGenerator {
+            failing: AtomicBool::new(true),
+            ..Default::default()
+        });
+        let secondary = Arc::new(MockGenerator::default());
+        let chain = chain(vec![primary.clone(), secondary.clone()], None);
+        let embed = || chain.generate_embeddings(vec!["ab".into()], "chain".into());
+
+        // The primary is skipped once it failed twice in a row
+        for _ in 0..3 {
+            assert_eq!(embed().await.unwrap(), vec![vec![2.0]]);
+        }
+        assert_eq!(primary.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(secondary.calls.load(Ordering::SeqCst), 3);
+
+        // Then called again once the breaker closes
+        primary.failing.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(embed().await.unwrap(), vec![vec![2.0]]);
+        assert_eq!(primary.calls.load(Ordering::SeqCst), 3);
+        assert_eq!(secondary.calls.load(Ordering::SeqCst), 3);
+
+        secondary.failing.store(true, Ordering::SeqCst);
+        primary.failing.store(true, Ordering::SeqCst);
+        let err = embed().await.unwrap_err();
+        assert!(matches!(err, EmbeddingGeneratorError::Unavailable(name, _) if name == "chain"));
+    }
+
+    #[tokio::test]
+    async fn test_no_fallback_on_request_errors() {
+        let primary = Arc::new(MockGenerator {
+            rejecting: AtomicBool::new(true),
+            ..Default::default()
+        });
+        let secondary = Arc::new(MockGenerator::default());
+        let chain = chain(vec![primary.clone(), secondary.clone()], None);
+        for _ in 0..3 {
+            let err = chain
+                .generate_embeddings(vec!["ab".into()], "chain".into())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, EmbeddingGeneratorError::ModelError(_)));
+        }
+        // The errors don't open the breaker of the primary
+        assert_eq!(primary.calls.load(Ordering::SeqCst), 3);
+        assert_eq!(secondary.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_on_rejected_key() {
+        use crate::embeddings::openai_compatible::{testing::mock_server, OpenAICompatible};
+        use crate::server_config::{EmbeddingModel, EmbeddingModelKind, OpenAICompatibleConfig};
+
+        let addr = mock_server("/embeddings", |headers, _| {
+            headers.get("authorization").map(|v| v.as_bytes()) == Some(b"Bearer valid")
+        })
+        .await;
+        let provider = |name: &str, api_key: &str| {
+            let model = EmbeddingModel {
+                model_kind: EmbeddingModelKind::OpenAICompatible,
+                name: Some(name.into()),
+                dimensions: Some(2),
+                openai_compatible: Some(OpenAICompatibleConfig {
+                    base_url: addr.clone(),
+                    model: None,
+                    api_key: Some(api_key.into()),
+                    auth_header: None,
+                    timeout_ms: 1000,
+                }),
+                ..Default::default()
+            };
+            Arc::new(OpenAICompatible::new(&model).unwrap()) as EmbeddingGeneratorTS
+        };
+        let config = FallbackChainConfig {
+            name: "chain".into(),
+            models: vec!["revoked".into(), "valid".into()],
+            timeout_ms: None,
+            circuit_breaker: CircuitBreakerConfig::default(),
+        };
+        let chain = FallbackChain::new(
+            &config,
+            vec![provider("revoked", "revoked"), provider("valid", "valid")],
+        );
+
+        // The first provider responds with 401, so the second one embeds the inputs
+        let embeddings = chain
+            .generate_embeddings(vec!["abc".into()], "chain".into())
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![3.0, 1.0]]);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_on_timeout() {
+        let slow = Arc::new(MockGenerator {
+            delay: Some(Duration::from_secs(5)),
+            ..Default::default()
+        });
+        let fast = Arc::new(MockGenerator::default());
+        let chain = chain(vec![slow, fast.clone()], Some(50));
+        let embeddings = chain
+            .generate_embeddings(vec!["abc".into()], "chain".into())
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![3.0]]);
+        assert_eq!(fast.calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,195 @@
+use async_trait::async_trait;
+
+use super::server_config::EmbeddingModel;
+use super::{EmbeddingGenerator, EmbeddingGeneratorError};
+
+/// Number of dimensions of the embeddings of a hash model, unless configured.
+pub const DEFAULT_DIMENSIONS: u64 = 384;
+
+/// Length of the character n-grams of each word.
+const NGRAM_LENGTH: usize = 3;
+
+/// A model that needs no weights and no network, meant for tests. The embedding of a text
+/// is the sum of its hashed words and character trigrams of its words, scaled to unit length,
+/// so the same text always has the same embedding, and texts sharing words are close.
+/// Texts are tokenized into characters, whose ids are their code points.
+pub struct HashEmbeddings {
+    dimensions: u64,
+    max_sequence_length: Option<usize>,
+}
+
+impl HashEmbeddings {
+    pub fn new(model: &EmbeddingModel) -> Self {
+        Self {
+            dimensions: model.dimensions.unwrap_or(DEFAULT_DIMENSIONS).max(1),
+            max_sequence_length: model.max_sequence_length,
+        }
+    }
+
+    fn embed(&self, text: &str) -> Vec<f32> {
+        let mut embedding = vec![0.0; self.dimensions as usize];
+        let text = text.to_lowercase();
+        for word in text.split(|c: char| !c.is_alphanumeric()) {
+            if word.is_empty() {
+                continue;
+            }
+            let chars: Vec<char> = format!("<{}>", word).chars().collect();
+            let ngrams = chars
+                .windows(NGRAM_LENGTH.min(chars.len()))
+                .map(|ngram| ngram.iter().collect::<String>());
+            for feature in std::iter::once(word.to_string()).chain(ngrams) {
+                let hash = fnv1a(feature.as_bytes());
+                let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+                embedding[(hash % self.dimensions) as usize] += sign;
+            }
+        }
+        let norm = embedding.iter().map(|e| e * e).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|e| *e /= norm);
+        }
+        embedding
+    }
+}
+
+/// The 64-bit FNV-1a hash, which unlike the hasher of the standard library is stable
+/// across platforms and Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[async_trait]
+impl EmbeddingGenerator for HashEmbeddings {
+    async fn generate_embeddings(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        // Longer inputs are truncated to their first tokens, like the ones of other models
+        Ok(inputs
+            .iter()
+            .map(|input| match self.max_sequence_length {
+                Some(max_tokens) => self.embed(&input.chars().take(max_tokens).collect::<String>()),
+                None => self.embed(input),
+            })
+            .collect())
+    }
+
+    fn dimensions(&self, _model: String) -> Result<u64, EmbeddingGeneratorError> {
+        Ok(self.dimensions)
+    }
+
+    fn max_input_tokens(&self, _model: String) -> Option<usize> {
+        self.max_sequence_length
+    }
+
+    async fn tokenize_text(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Vec<String>>, EmbeddingGeneratorError> {
+        Ok(inputs
+            .iter()
+            .map(|input| input.chars().map(String::from).collect())
+            .collect())
+    }
+
+    async fn tokenize_encode(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Vec<i64>>, EmbeddingGeneratorError> {
+        Ok(inputs
+            .iter()
+            .map(|input| input.chars().map(|c| c as i64).collect())
+            .collect())
+    }
+
+    async fn tokenize_decode(
+        &self,
+        inputs: Vec<Vec<i64>>,
+        _model: String,
+    ) -> Result<Vec<String>, EmbeddingGeneratorError> {
+        inputs
+            .iter()
+            .map(|tokens| {
+                tokens
+                    .iter()
+                    .map(|&token| {
+                        u32::try_from(token)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| {
+                                EmbeddingGeneratorError::ModelError(format!(
+                                    "invalid token `{}`",
+                                    token
+                                ))
+                            })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    async fn count_tokens(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<usize>, EmbeddingGeneratorError> {
+        Ok(inputs.iter().map(|input| input.chars().count()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_config::EmbeddingModelKind;
+
+    fn cosine(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(a, b)| a * b).sum()
+    }
+
+    #[tokio::test]
+    async fn test_hash_embeddings() {
+        let model = HashEmbeddings::new(&EmbeddingModel {
+            model_kind: EmbeddingModelKind::Hash,
+            dimensions: Some(64),
+            ..Default::default()
+        });
+        let inputs = vec![
+            "The sixers lost".to_string(),
+            "the Sixers lost!".to_string(),
+            "embiid is the mvp".to_string(),
+        ];
+        let embeddings = model
+            .generate_embeddings(inputs.clone(), "hash".into())
+            .await
+            .unwrap();
+        assert_eq!(embeddings[0].len(), 64);
+        assert_eq!(embeddings[0], embeddings[1]);
+        assert!((cosine(&embeddings[0], &embeddings[0]) - 1.0).abs() < 1e-5);
+        assert!(cosine(&embeddings[0], &embeddings[2]) < 0.5);
+        // Stable across runs and platforms
+        assert_eq!(fnv1a(b"sixers"), 0x2044812433058c81);
+
+        let tokens = model
+            .tokenize_encode(inputs.clone(), "hash".into())
+            .await
+            .unwrap();
+        assert_eq!(tokens[0].len(), 15);
+        let counts = model
+            .count_tokens(inputs.clone(), "hash".into())
+            .await
+            .unwrap();
+        assert_eq!(counts, vec![15, 16, 17]);
+        let tokenized = model
+            .tokenize_encode_text(inputs.clone(), "hash".into())
+            .await
+            .unwrap();
+        assert_eq!(tokenized[2].ids, tokens[2]);
+        assert_eq!(tokenized[2].texts.concat(), inputs[2]);
+        let decoded = model.tokenize_decode(tokens, "hash".into()).await.unwrap();
+        assert_eq!(decoded, inputs);
+    }
+}
@@ -1,18 +1,40 @@
+mod cache;
+mod cross_encoder;
+mod fallback;
+mod hash;
+mod model_dir;
+mod onnx;
 mod openai;
+mod openai_compatible;
+mod post_processing;
+mod rate_limit;
 mod sentence_transformers;
+mod tiktoken;
+mod tokenizer;
 
 use super::server_config::{
     self, EmbeddingModelKind::AllMiniLmL12V2, EmbeddingModelKind::AllMiniLmL6V2,
-    EmbeddingModelKind::OpenAIAda02, EmbeddingModelKind::T5Base,
+    EmbeddingModelKind::Custom, EmbeddingModelKind::Hash, EmbeddingModelKind::MsMarcoMiniLmL6V2,
+    EmbeddingModelKind::OpenAIAda02, EmbeddingModelKind::OpenAICompatible,
+    EmbeddingModelKind::T5Base, LongInputPolicy,
 };
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 use thiserror::Error;
-use tracing::info;
+use tracing::{info, warn};
 
+pub use cache::{CacheStats, EmbeddingCache};
+pub use post_processing::{check_post_processing, post_process, post_processed_dimensions};
+
+use fallback::FallbackChain;
+use hash::HashEmbeddings;
+use onnx::OnnxModels;
 use openai::OpenAI;
 use sentence_transformers::SentenceTransformerModels;
+use tokenizer::StandaloneTokenizer;
 
 /// An enumeration of possible errors that can occur while generating text embeddings.
 #[derive(Error, Debug)]
@@ -33,11 +55,90 @@ pub enum EmbeddingGeneratorError {
     #[error("internal error: `{0}`")]
     InternalError(String),
 
+    /// An error that occurs when the queue of requests of a model is full.
+    #[error("model `{0}` is overloaded, retry later")]
+    Overloaded(String),
+
+    /// An error that occurs when a model can't serve a request, or doesn't serve it in time.
+    #[error("model `{0}` is unavailable: `{1}`")]
+    Unavailable(String, String),
+
+    /// An error that occurs when the provider serving a model fails a request for reasons of
+    /// its own, such as an API key it rejects or an HTTP error.
+    #[error("provider of model `{0}` failed: `{1}`")]
+    ProviderError(String, String),
+
+    /// An error that occurs when an input has more tokens than a model accepts, and the
+    /// long input policy is to fail.
+    #[error("input {1} has {2} tokens, more than the {3} tokens of model `{0}`")]
+    InputTooLong(String, usize, usize, usize),
+
     /// An error that occurs when the required configuration is missing for a model.
     #[error("configuration `{0}`, missing for model `{1}`")]
     ConfigurationError(String, String),
 }
 
+/// The embeddings of texts, with the indexes of the texts that were truncated to the
+/// maximum number of tokens of the model, and the number of tokens of each text if they
+/// were counted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Embeddings {
+    pub embeddings: Vec<Vec<f32>>,
+    pub truncated: Vec<usize>,
+    pub tokens: Option<Vec<usize>>,
+}
+
+/// The tokens of a text, as their ids in the vocabulary of a tokenizer and their texts,
+/// both from the same encoding of the text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tokens {
+    pub ids: Vec<i64>,
+    pub texts: Vec<String>,
+}
+
+/// The readiness of a model to serve requests.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum ModelStatus {
+    /// The model is being loaded.
+    Loading,
+    /// The model serves requests.
+    Ready,
+    /// The model failed to load or crashed, with the error. It's loaded again after a while.
+    Failed(String),
+    /// The model isn't in memory, and is loaded on its next request.
+    Unloaded,
+}
+
+impl ModelStatus {
+    /// Whether the model serves requests, right away or once it's loaded on demand.
+    pub fn is_available(&self) -> bool {
+        matches!(self, ModelStatus::Ready | ModelStatus::Unloaded)
+    }
+}
+
+/// The memory taken by a model that is loaded in memory on demand.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelResidency {
+    /// Whether the model is loaded in memory.
+    pub resident: bool,
+    /// Memory in megabytes the model takes once loaded.
+    pub memory_mb: u64,
+    /// Seconds since the model was last loaded or requested.
+    pub idle_secs: u64,
+}
+
+/// The role of texts that are embedded, which models trained with instructions embed with
+/// different templates.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingRole {
+    /// Search queries.
+    Query,
+    /// Texts added to indexes, which queries are matched against.
+    Document,
+}
+
 pub type EmbeddingGeneratorTS = Arc<dyn EmbeddingGenerator + Sync + Send>;
 
 /// A trait that defines the interface for generating text embeddings.
@@ -60,6 +161,17 @@ pub trait EmbeddingGenerator {
         model: String,
     ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError>;
 
+    /// Generates text embeddings for texts in the given role, applying the query or document
+    /// template of the model. Generators without templates embed the texts as they are.
+    async fn generate_role_embeddings(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+        _role: EmbeddingRole,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        self.generate_embeddings(inputs, model).await
+    }
+
     /// Returns the number of dimensions of the embeddings generated by the specified model.
     ///
     /// # Arguments
@@ -72,6 +184,39 @@ pub trait EmbeddingGenerator {
     ///   if an error occurs (e.g., the model is not found).
     fn dimensions(&self, model: String) -> Result<u64, EmbeddingGeneratorError>;
 
+    /// Returns the maximum number of tokens of an input of the specified model, if it has
+    /// one. The model itself truncates longer inputs.
+    fn max_input_tokens(&self, _model: String) -> Option<usize> {
+        None
+    }
+
+    /// Returns the readiness of the specified model. Models are ready unless the generator
+    /// loads them in the background.
+    fn status(&self, _model: String) -> Result<ModelStatus, EmbeddingGeneratorError> {
+        Ok(ModelStatus::Ready)
+    }
+
+    /// Returns the memory taken by the specified model, if the generator loads it in memory
+    /// on demand.
+    fn residency(&self, _model: String) -> Option<ModelResidency> {
+        None
+    }
+
+    /// Loads the specified model in memory, if the generator loads models on demand, and
+    /// waits until it's ready. Other models are always loaded.
+    async fn load_model(&self, model: String) -> Result<(), EmbeddingGeneratorError> {
+        self.status(model).map(|_| ())
+    }
+
+    /// Unloads the specified model from memory once its queued requests are served, and
+    /// returns whether it was loaded. Only models loaded on demand can be unloaded.
+    fn unload_model(&self, model: String) -> Result<bool, EmbeddingGeneratorError> {
+        Err(EmbeddingGeneratorError::InternalError(format!(
+            "model `{}` can't be unloaded",
+            model
+        )))
+    }
+
     // Tokenizes a list of inputs using the specified model.
     // This is for splitters to use to split inputs while respecting token boundaries.
     async fn tokenize_text(
@@ -91,6 +236,217 @@ pub trait EmbeddingGenerator {
         inputs: Vec<Vec<i64>>,
         model: String,
     ) -> Result<Vec<String>, EmbeddingGeneratorError>;
+
+    /// Tokenizes each input into the ids of its tokens and their texts. Unless the generator
+    /// gets both from one encoding, the inputs are encoded and each token is decoded alone.
+    async fn tokenize_encode_text(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<Tokens>, EmbeddingGeneratorError> {
+        let ids = self.tokenize_encode(inputs, model.clone()).await?;
+        let single_ids = ids.iter().flatten().map(|&id| vec![id]).collect();
+        let mut texts = self.tokenize_decode(single_ids, model).await?.into_iter();
+        Ok(ids
+            .into_iter()
+            .map(|ids| Tokens {
+                texts: texts.by_ref().take(ids.len()).collect(),
+                ids,
+            })
+            .collect())
+    }
+
+    /// Counts the tokens of each input with the tokenizer of the specified model, by
+    /// encoding them unless the generator has a cheaper way.
+    async fn count_tokens(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<usize>, EmbeddingGeneratorError> {
+        Ok(self
+            .tokenize_encode(inputs, model)
+            .await?
+            .iter()
+            .map(Vec::len)
+            .collect())
+    }
+
+    /// Scores the relevance of each passage to the query using the specified cross-encoder
+    /// model. Higher scores are more relevant.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query the passages are scored against.
+    /// * `passages` - The passages to be scored.
+    /// * `model` - The name of the cross-encoder model to be used for scoring.
+    ///
+    /// # Returns
+    ///
+    /// * A result containing a score for each passage if successful, or an
+    ///   `EmbeddingGeneratorError` if the model is not a cross-encoder served by this generator.
+    async fn rerank(
+        &self,
+        _query: String,
+        _passages: Vec<String>,
+        model: String,
+    ) -> Result<Vec<f32>, EmbeddingGeneratorError> {
+        Err(EmbeddingGeneratorError::ModelNotFound(model))
+    }
+}
+
+/// Checks that the directory of a model that runs locally has its weights, and that offline
+/// servers don't download any model, so that missing weights fail when the server starts.
+fn check_local_model(
+    model: &server_config::EmbeddingModel,
+    offline: bool,
+) -> Result<(), EmbeddingGeneratorError> {
+    // Remote models have no weights, and ONNX models are always loaded from files
+    if matches!(model.device_kind, server_config::DeviceKind::Remote)
+        || matches!(model.model_kind, OpenAIAda02 | OpenAICompatible | Hash)
+        || model.onnx.is_some()
+    {
+        return Ok(());
+    }
+    let model_files: Vec<&str> =
+        match model.model_kind {
+            MsMarcoMiniLmL6V2 => {
+                if model.path.is_none() {
+                    return Err(EmbeddingGeneratorError::ConfigurationError(
+                        "path".into(),
+                        model.name(),
+                    ));
+                }
+                cross_encoder::MODEL_FILES.to_vec()
+            }
+            Custom => {
+                let architecture = model.architecture.as_ref().ok_or(
+                    EmbeddingGeneratorError::ConfigurationError(
+                        "architecture".into(),
+                        model.name(),
+                    ),
+                )?;
+                if model.path.is_none() {
+                    return Err(EmbeddingGeneratorError::ConfigurationError(
+                        "path".into(),
+                        model.name(),
+                    ));
+                }
+                model_dir::required_files(architecture)
+            }
+            _ => sentence_transformers::MODEL_FILES.to_vec(),
+        };
+    match &model.path {
+        Some(path) => {
+            for file in model_files {
+                if !Path::new(path).join(file).exists() {
+                    return Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+                        "`{}` of model `{}` is missing from `{}`",
+                        file,
+                        model.name(),
+                        path
+                    )));
+                }
+            }
+            Ok(())
+        }
+        None if offline => Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+            "model `{}` has no local `path`, and the server is offline",
+            model.name()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Returns the text in the template, in place of `{text}`, or after the template if it has
+/// no `{text}`.
+fn apply_template(template: &str, text: &str) -> String {
+    if template.contains("{text}") {
+        template.replace("{text}", text)
+    } else {
+        format!("{}{}", template, text)
+    }
+}
+
+/// Maximum number of special tokens a tokenizer adds to a text, such as `[CLS]` and `[SEP]`.
+const MAX_SPECIAL_TOKENS: usize = 2;
+
+/// Splits each input into the texts it's embedded as, according to the policy for inputs
+/// with more than `max_tokens` tokens as counted in `counts`, and returns them with the
+/// indexes of the inputs that were truncated. Inputs are split with the tokenizer of the
+/// model in the router, the one that counted them.
+async fn fit_inputs(
+    router: &EmbeddingRouter,
+    inputs: Vec<String>,
+    counts: &[usize],
+    model: &str,
+    max_tokens: usize,
+    policy: LongInputPolicy,
+) -> Result<(Vec<Vec<String>>, Vec<usize>), EmbeddingGeneratorError> {
+    let long: Vec<usize> = (0..inputs.len())
+        .filter(|&i| counts[i] > max_tokens)
+        .collect();
+    let mut parts: Vec<Vec<String>> = inputs.into_iter().map(|input| vec![input]).collect();
+    if long.is_empty() {
+        return Ok((parts, vec![]));
+    }
+    // Models truncate the tail of longer inputs themselves, so they're only reported
+    match policy {
+        LongInputPolicy::Error => {
+            return Err(EmbeddingGeneratorError::InputTooLong(
+                model.to_string(),
+                long[0],
+                counts[long[0]],
+                max_tokens,
+            ))
+        }
+        LongInputPolicy::TruncateTail => return Ok((parts, long)),
+        LongInputPolicy::TruncateHead | LongInputPolicy::SlidingWindow => {}
+    }
+    let long_inputs: Vec<String> = long.iter().map(|&i| parts[i][0].clone()).collect();
+    let tokens = router
+        .tokenize_encode(long_inputs, model.to_string())
+        .await?;
+    // Parts are decoded without the special tokens, which the model adds back
+    let part_tokens = max_tokens.saturating_sub(MAX_SPECIAL_TOKENS).max(1);
+    let mut truncated = Vec::new();
+    let mut split = Vec::new();
+    let mut split_tokens: Vec<Vec<i64>> = Vec::new();
+    for (i, tokens) in long.into_iter().zip(tokens) {
+        if policy == LongInputPolicy::TruncateHead {
+            split_tokens.push(tokens[tokens.len().saturating_sub(part_tokens)..].to_vec());
+            truncated.push(i);
+        } else {
+            split_tokens.extend(tokens.chunks(part_tokens).map(<[i64]>::to_vec));
+        }
+        split.push((i, split_tokens.len()));
+    }
+    let mut texts = router
+        .tokenize_decode(split_tokens, model.to_string())
+        .await?
+        .into_iter();
+    let mut decoded = 0;
+    for (i, end) in split {
+        parts[i] = texts.by_ref().take(end - decoded).collect();
+        decoded = end;
+    }
+    Ok((parts, truncated))
+}
+
+/// Averages the embeddings of the parts of a text, keeping unit length if they have it.
+fn mean_pool(embeddings: Vec<Vec<f32>>) -> Vec<f32> {
+    let norm = |e: &[f32]| e.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let normalized = embeddings.iter().all(|e| (norm(e) - 1.0).abs() < 1e-3);
+    let mut mean = vec![0.0; embeddings.first().map_or(0, Vec::len)];
+    for embedding in &embeddings {
+        for (m, v) in mean.iter_mut().zip(embedding) {
+            *m += v / embeddings.len() as f32;
+        }
+    }
+    let mean_norm = norm(&mean);
+    if normalized && mean_norm > 0.0 {
+        mean.iter_mut().for_each(|m| *m /= mean_norm);
+    }
+    mean
 }
 
 /// A struct that represents a router for generating text embeddings using different models.
@@ -102,6 +458,18 @@ pub struct EmbeddingRouter {
     router: HashMap<String, EmbeddingGeneratorTS>,
 
     model_names: Vec<String>,
+
+    templates: HashMap<String, server_config::TemplatesConfig>,
+
+    long_input_policies: HashMap<String, LongInputPolicy>,
+
+    post_processing: HashMap<String, server_config::PostProcessingConfig>,
+
+    tokenizers: HashMap<String, Arc<StandaloneTokenizer>>,
+
+    fingerprints: HashMap<String, String>,
+
+    cache: Option<EmbeddingCache>,
 }
 
 impl EmbeddingRouter {
@@ -121,26 +489,63 @@ impl EmbeddingRouter {
     pub fn new(config: Arc<server_config::ServerConfig>) -> Result<Self, EmbeddingGeneratorError> {
         let mut router: HashMap<String, EmbeddingGeneratorTS> = HashMap::new();
         let mut sentence_transformers: Vec<server_config::EmbeddingModel> = Vec::new();
+        let mut onnx_models: Vec<server_config::EmbeddingModel> = Vec::new();
         let mut model_names = Vec::new();
+        let mut templates = HashMap::new();
+        let mut long_input_policies = HashMap::new();
+        let mut post_processing = HashMap::new();
+        let mut tokenizers = HashMap::new();
+        let mut fingerprints = HashMap::new();
         for model in config.available_models.clone() {
-            model_names.push(model.model_kind.to_string());
-            info!(
-                "loading embedding model: {:?}",
-                model.model_kind.to_string()
-            );
+            if let (Custom | OpenAICompatible, None) = (&model.model_kind, &model.name) {
+                return Err(EmbeddingGeneratorError::ConfigurationError(
+                    "name".into(),
+                    model.model_kind.to_string(),
+                ));
+            }
+            model_names.push(model.name());
+            fingerprints.insert(model.name(), cache::model_fingerprint(&model));
+            if let Some(model_templates) = &model.templates {
+                templates.insert(model.name(), model_templates.clone());
+            }
+            if let Some(policy) = model.long_input_policy {
+                long_input_policies.insert(model.name(), policy);
+            }
+            if let Some(model_post_processing) = &model.post_processing {
+                check_post_processing(model_post_processing)?;
+                post_processing.insert(model.name(), model_post_processing.clone());
+            }
+            if let Some(tokenizer) = &model.tokenizer {
+                info!("loading tokenizer of embedding model: {:?}", model.name());
+                let tokenizer = Arc::new(StandaloneTokenizer::new(tokenizer)?);
+                tokenizers.insert(model.name(), tokenizer);
+            }
+            info!("loading embedding model: {:?}", model.name());
+            check_local_model(&model, config.offline)?;
+            if model.onnx.is_some() {
+                onnx_models.push(model.clone());
+                continue;
+            }
             match model.model_kind {
-                AllMiniLmL12V2 | T5Base | AllMiniLmL6V2 => {
+                AllMiniLmL12V2 | T5Base | AllMiniLmL6V2 | MsMarcoMiniLmL6V2 | Custom => {
                     sentence_transformers.push(model.clone());
                 }
                 OpenAIAda02 => {
-                    let openai_config = config.openai.clone().ok_or(
+                    let openai_config = model.openai.clone().or(config.openai.clone()).ok_or(
                         EmbeddingGeneratorError::ConfigurationError(
                             "openai".into(),
                             "openai_config".into(),
                         ),
                     )?;
                     let openai_ada02 = OpenAI::new(openai_config, model.clone())?;
-                    router.insert(model.model_kind.to_string(), Arc::new(openai_ada02));
+                    router.insert(model.name(), Arc::new(openai_ada02));
+                }
+                OpenAICompatible => {
+                    let openai_compatible = openai_compatible::OpenAICompatible::new(&model)?;
+                    router.insert(model.name(), Arc::new(openai_compatible));
+                }
+                Hash => {
+                    router.insert(model.name(), Arc::new(HashEmbeddings::new(&model)));
                 }
                 _ => {
                     return Err(EmbeddingGeneratorError::InternalError(format!(
@@ -150,21 +555,310 @@ impl EmbeddingRouter {
                 }
             }
         }
+        if let Some(threads) = config.intra_op_threads {
+            sentence_transformers::set_intra_op_threads(threads);
+        }
         let sentence_transformer_router = Arc::new(SentenceTransformerModels::new(
             sentence_transformers.clone(),
+            config.allow_unavailable_models,
+            &config.model_loading,
         )?);
         for st in sentence_transformers {
-            router.insert(
-                st.model_kind.to_string(),
-                sentence_transformer_router.clone(),
+            router.insert(st.name(), sentence_transformer_router.clone());
+        }
+        let onnx_router = Arc::new(OnnxModels::new(onnx_models.clone())?);
+        for model in onnx_models {
+            router.insert(model.name(), onnx_router.clone());
+        }
+        for chain in &config.fallback_chains {
+            let chain_router = Self::fallback_chain(chain, &router)?;
+            router.insert(chain.name.clone(), Arc::new(chain_router));
+            model_names.push(chain.name.clone());
+            // The models of a chain are compatible, so they share the settings of the first
+            if let Some(chain_templates) = chain.models.first().and_then(|m| templates.get(m)) {
+                templates.insert(chain.name.clone(), chain_templates.clone());
+            }
+            if let Some(&policy) = chain
+                .models
+                .first()
+                .and_then(|m| long_input_policies.get(m))
+            {
+                long_input_policies.insert(chain.name.clone(), policy);
+            }
+            if let Some(chain_post_processing) =
+                chain.models.first().and_then(|m| post_processing.get(m))
+            {
+                post_processing.insert(chain.name.clone(), chain_post_processing.clone());
+            }
+            if let Some(tokenizer) = chain.models.first().and_then(|m| tokenizers.get(m)) {
+                tokenizers.insert(chain.name.clone(), tokenizer.clone());
+            }
+            let chain_fingerprints: Vec<String> = chain
+                .models
+                .iter()
+                .filter_map(|m| fingerprints.get(m).cloned())
+                .collect();
+            fingerprints.insert(
+                chain.name.clone(),
+                cache::chain_fingerprint(&chain_fingerprints),
             );
         }
         Ok(Self {
             router,
             model_names,
+            templates,
+            long_input_policies,
+            post_processing,
+            tokenizers,
+            fingerprints,
+            cache: None,
         })
     }
 
+    /// Creates a fallback chain of models already in the router, which must generate
+    /// embeddings of the same dimensions.
+    fn fallback_chain(
+        chain: &server_config::FallbackChainConfig,
+        router: &HashMap<String, EmbeddingGeneratorTS>,
+    ) -> Result<FallbackChain, EmbeddingGeneratorError> {
+        if chain.models.is_empty() {
+            return Err(EmbeddingGeneratorError::ConfigurationError(
+                "models".into(),
+                chain.name.clone(),
+            ));
+        }
+        if router.contains_key(&chain.name) {
+            return Err(EmbeddingGeneratorError::InternalError(format!(
+                "fallback chain `{}` has the name of a model",
+                chain.name
+            )));
+        }
+        let mut generators = Vec::new();
+        let mut dimensions = None;
+        for model in &chain.models {
+            let generator = router
+                .get(model)
+                .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+            // Models that are still loading are checked by their own expected dimensions
+            if let Ok(model_dimensions) = generator.dimensions(model.clone()) {
+                if *dimensions.get_or_insert(model_dimensions) != model_dimensions {
+                    return Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+                        "models of fallback chain `{}` generate embeddings of different dimensions",
+                        chain.name
+                    )));
+                }
+            }
+            generators.push(generator.clone());
+        }
+        Ok(FallbackChain::new(chain, generators))
+    }
+
+    /// Serves embeddings from the cache when it has them, and stores the ones generated.
+    pub fn with_cache(self, cache: EmbeddingCache) -> Self {
+        Self {
+            cache: Some(cache),
+            ..self
+        }
+    }
+
+    /// Returns the statistics of the cache of embeddings, if there is one.
+    pub async fn cache_stats(&self) -> Result<Option<CacheStats>, EmbeddingGeneratorError> {
+        match &self.cache {
+            Some(cache) => cache
+                .stats()
+                .await
+                .map(Some)
+                .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Generates the embeddings of texts with a model, applying the template of the model
+    /// for the role of the texts, if any. Inputs longer than the model accepts are handled
+    /// with `long_input_policy`, or else the policy of the model, and the truncated ones
+    /// are reported, along with the number of tokens of every input with `usage`.
+    /// Embeddings are post-processed as configured for the model.
+    pub async fn embed(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+        role: Option<EmbeddingRole>,
+        long_input_policy: Option<LongInputPolicy>,
+        usage: bool,
+    ) -> Result<Embeddings, EmbeddingGeneratorError> {
+        let embedding_model = self
+            .router
+            .get(&model)
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+        let template = role.and_then(|role| {
+            let templates = self.templates.get(&model)?;
+            match role {
+                EmbeddingRole::Query => templates.query.as_ref(),
+                EmbeddingRole::Document => templates.document.as_ref(),
+            }
+        });
+        let inputs: Vec<String> = match template {
+            Some(template) => inputs
+                .iter()
+                .map(|input| apply_template(template, input))
+                .collect(),
+            None => inputs,
+        };
+        let tokens = match usage {
+            true => Some(self.count_tokens(inputs.clone(), model.clone()).await?),
+            false => None,
+        };
+        let mut embeddings = match embedding_model.max_input_tokens(model.clone()) {
+            Some(max_tokens) => {
+                let counts = match &tokens {
+                    Some(tokens) => tokens.clone(),
+                    None => self.count_long_tokens(&inputs, &model, max_tokens).await?,
+                };
+                let policy = long_input_policy
+                    .or(self.long_input_policies.get(&model).copied())
+                    .unwrap_or_default();
+                let (embeddings, truncated) = self
+                    .generate_fitted(embedding_model, inputs, &counts, &model, max_tokens, policy)
+                    .await?;
+                Embeddings {
+                    embeddings,
+                    truncated,
+                    tokens,
+                }
+            }
+            None => Embeddings {
+                embeddings: self
+                    .generate_cached(embedding_model, inputs, model.clone())
+                    .await?,
+                truncated: vec![],
+                tokens,
+            },
+        };
+        if let Some(config) = self.post_processing.get(&model) {
+            post_process(config, &mut embeddings.embeddings);
+        }
+        Ok(embeddings)
+    }
+
+    /// Counts the tokens of the inputs that may have more than `max_tokens` tokens, and
+    /// returns 0 for the others. Every token spans at least a byte, so only the inputs with
+    /// more bytes are tokenized.
+    async fn count_long_tokens(
+        &self,
+        inputs: &[String],
+        model: &str,
+        max_tokens: usize,
+    ) -> Result<Vec<usize>, EmbeddingGeneratorError> {
+        let long: Vec<usize> = (0..inputs.len())
+            .filter(|&i| inputs[i].len() + MAX_SPECIAL_TOKENS > max_tokens)
+            .collect();
+        let mut counts = vec![0; inputs.len()];
+        if long.is_empty() {
+            return Ok(counts);
+        }
+        let long_inputs = long.iter().map(|&i| inputs[i].clone()).collect();
+        let long_counts = self.count_tokens(long_inputs, model.to_string()).await?;
+        for (i, count) in long.into_iter().zip(long_counts) {
+            counts[i] = count;
+        }
+        Ok(counts)
+    }
+
+    /// Generates embeddings of inputs that may have more than the `max_tokens` tokens of the
+    /// model, according to the long input policy, and returns them with the indexes of the
+    /// inputs that were truncated.
+    async fn generate_fitted(
+        &self,
+        embedding_model: &EmbeddingGeneratorTS,
+        inputs: Vec<String>,
+        counts: &[usize],
+        model: &str,
+        max_tokens: usize,
+        policy: LongInputPolicy,
+    ) -> Result<(Vec<Vec<f32>>, Vec<usize>), EmbeddingGeneratorError> {
+        let (parts, truncated) =
+            fit_inputs(self, inputs, counts, model, max_tokens, policy).await?;
+        let lengths: Vec<usize> = parts.iter().map(Vec::len).collect();
+        let embeddings = self
+            .generate_cached(
+                embedding_model,
+                parts.into_iter().flatten().collect(),
+                model.to_string(),
+            )
+            .await?;
+        let expected: usize = lengths.iter().sum();
+        if embeddings.len() != expected {
+            return Err(EmbeddingGeneratorError::ModelError(format!(
+                "model `{}` returned {} embeddings for {} inputs",
+                model,
+                embeddings.len(),
+                expected
+            )));
+        }
+        let mut embeddings = embeddings.into_iter();
+        let embeddings = lengths
+            .into_iter()
+            .map(|length| {
+                let mut part_embeddings: Vec<Vec<f32>> = embeddings.by_ref().take(length).collect();
+                match part_embeddings.len() {
+                    1 => part_embeddings.swap_remove(0),
+                    _ => mean_pool(part_embeddings),
+                }
+            })
+            .collect();
+        Ok((embeddings, truncated))
+    }
+
+    /// Generates embeddings with a model, serving the ones in the cache from it.
+    async fn generate_cached(
+        &self,
+        embedding_model: &EmbeddingGeneratorTS,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return embedding_model.generate_embeddings(inputs, model).await,
+        };
+        let fingerprint = self
+            .fingerprints
+            .get(&model)
+            .map(String::as_str)
+            .unwrap_or_default();
+        // The cache only saves work, so embeddings are generated when it fails
+        let mut embeddings = cache
+            .get(&model, fingerprint, &inputs)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("unable to read the embedding cache: {}", e);
+                vec![None; inputs.len()]
+            });
+        let missing: Vec<usize> = (0..inputs.len())
+            .filter(|&i| embeddings[i].is_none())
+            .collect();
+        if !missing.is_empty() {
+            let missing_inputs: Vec<String> = missing.iter().map(|&i| inputs[i].clone()).collect();
+            let generated = embedding_model
+                .generate_embeddings(missing_inputs.clone(), model.clone())
+                .await?;
+            if let Err(e) = cache
+                .put(&model, fingerprint, &missing_inputs, &generated)
+                .await
+            {
+                warn!("unable to write the embedding cache: {}", e);
+            }
+            for (i, embedding) in missing.into_iter().zip(generated) {
+                embeddings[i] = Some(embedding);
+            }
+        }
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+
+    /// Returns the transforms of the embeddings of a model, if it has any.
+    pub fn post_processing(&self, model: &str) -> Option<&server_config::PostProcessingConfig> {
+        self.post_processing.get(model)
+    }
+
     /// Returns a list of available model names.
     ///
     /// # Returns
@@ -182,19 +876,66 @@ impl EmbeddingGenerator for EmbeddingRouter {
         inputs: Vec<String>,
         model: String,
     ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        Ok(self
+            .embed(inputs, model, None, None, false)
+            .await?
+            .embeddings)
+    }
+
+    async fn generate_role_embeddings(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+        role: EmbeddingRole,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        Ok(self
+            .embed(inputs, model, Some(role), None, false)
+            .await?
+            .embeddings)
+    }
+
+    fn dimensions(&self, model: String) -> Result<u64, EmbeddingGeneratorError> {
+        let embedding_model = self
+            .router
+            .get(&model)
+            .ok_or(EmbeddingGeneratorError::ModelLoadingError(model.clone()))?;
+        let dimensions = embedding_model.dimensions(model.clone())?;
+        match self.post_processing.get(&model) {
+            Some(config) => post_processed_dimensions(config, dimensions),
+            None => Ok(dimensions),
+        }
+    }
+
+    fn max_input_tokens(&self, model: String) -> Option<usize> {
+        self.router.get(&model)?.max_input_tokens(model)
+    }
+
+    fn status(&self, model: String) -> Result<ModelStatus, EmbeddingGeneratorError> {
         let embedding_model = self
             .router
             .get(&model)
             .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
-        embedding_model.generate_embeddings(inputs, model).await
+        embedding_model.status(model)
     }
 
-    fn dimensions(&self, model: String) -> Result<u64, EmbeddingGeneratorError> {
+    fn residency(&self, model: String) -> Option<ModelResidency> {
+        self.router.get(&model)?.residency(model)
+    }
+
+    async fn load_model(&self, model: String) -> Result<(), EmbeddingGeneratorError> {
         let embedding_model = self
             .router
             .get(&model)
-            .ok_or(EmbeddingGeneratorError::ModelLoadingError(model.clone()))?;
-        embedding_model.dimensions(model)
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+        embedding_model.load_model(model).await
+    }
+
+    fn unload_model(&self, model: String) -> Result<bool, EmbeddingGeneratorError> {
+        let embedding_model = self
+            .router
+            .get(&model)
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+        embedding_model.unload_model(model)
     }
 
     async fn tokenize_text(
@@ -206,6 +947,9 @@ impl EmbeddingGenerator for EmbeddingRouter {
             .router
             .get(&model)
             .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+        if let Some(tokenizer) = self.tokenizers.get(&model) {
+            return tokenizer.tokenize_text(inputs);
+        }
         embedding_model.tokenize_text(inputs, model).await
     }
 
@@ -218,6 +962,9 @@ impl EmbeddingGenerator for EmbeddingRouter {
             .router
             .get(&model)
             .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+        if let Some(tokenizer) = self.tokenizers.get(&model) {
+            return tokenizer.tokenize_encode(inputs);
+        }
         embedding_model.tokenize_encode(inputs, model).await
     }
 
@@ -230,6 +977,367 @@ impl EmbeddingGenerator for EmbeddingRouter {
             .router
             .get(&model)
             .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+        if let Some(tokenizer) = self.tokenizers.get(&model) {
+            return tokenizer.tokenize_decode(inputs);
+        }
         embedding_model.tokenize_decode(inputs, model).await
     }
+
+    async fn tokenize_encode_text(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<Tokens>, EmbeddingGeneratorError> {
+        let embedding_model = self
+            .router
+            .get(&model)
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+        if let Some(tokenizer) = self.tokenizers.get(&model) {
+            return tokenizer.tokenize_encode_text(inputs);
+        }
+        embedding_model.tokenize_encode_text(inputs, model).await
+    }
+
+    async fn count_tokens(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<usize>, EmbeddingGeneratorError> {
+        let embedding_model = self
+            .router
+            .get(&model)
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+        if let Some(tokenizer) = self.tokenizers.get(&model) {
+            return tokenizer.count_tokens(inputs);
+        }
+        embedding_model.count_tokens(inputs, model).await
+    }
+
+    async fn rerank(
+        &self,
+        query: String,
+        passages: Vec<String>,
+        model: String,
+    ) -> Result<Vec<f32>, EmbeddingGeneratorError> {
+        let embedding_model = self
+            .router
+            .get(&model)
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model.clone()))?;
+        embedding_model.rerank(query, passages, model).await
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use super::{EmbeddingGenerator, EmbeddingGeneratorError};
+
+    /// A model that embeds each input as its length, after `delay`, or fails while `failing`
+    /// as if it was down, or while `rejecting` as if the inputs were invalid. It counts its
+    /// calls and the inputs it embeds, and has no tokenizer, counting a token per byte. It
+    /// scores any passages with `rerank_scores`, if set.
+    #[derive(Default)]
+    pub struct MockGenerator {
+        pub failing: AtomicBool,
+        pub rejecting: AtomicBool,
+        pub delay: Option<Duration>,
+        pub calls: AtomicUsize,
+        pub embedded: AtomicUsize,
+        pub rerank_scores: Option<Vec<f32>>,
+    }
+
+    impl MockGenerator {
+        fn no_tokenizer() -> EmbeddingGeneratorError {
+            EmbeddingGeneratorError::InternalError("the mock model has no tokenizer".into())
+        }
+    }
+
+    #[async_trait]
+    impl EmbeddingGenerator for MockGenerator {
+        async fn generate_embeddings(
+            &self,
+            inputs: Vec<String>,
+            model: String,
+        ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if let Some(delay) = self.delay {
+                tokio::time::sleep(delay).await;
+            }
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(EmbeddingGeneratorError::Unavailable(model, "down".into()));
+            }
+            if self.rejecting.load(Ordering::SeqCst) {
+                return Err(EmbeddingGeneratorError::ModelError("invalid input".into()));
+            }
+            self.embedded.fetch_add(inputs.len(), Ordering::SeqCst);
+            Ok(inputs.iter().map(|i| vec![i.len() as f32]).collect())
+        }
+
+        fn dimensions(&self, _model: String) -> Result<u64, EmbeddingGeneratorError> {
+            Ok(1)
+        }
+
+        async fn tokenize_text(
+            &self,
+            _inputs: Vec<String>,
+            _model: String,
+        ) -> Result<Vec<Vec<String>>, EmbeddingGeneratorError> {
+            Err(Self::no_tokenizer())
+        }
+
+        async fn tokenize_encode(
+            &self,
+            _inputs: Vec<String>,
+            _model: String,
+        ) -> Result<Vec<Vec<i64>>, EmbeddingGeneratorError> {
+            Err(Self::no_tokenizer())
+        }
+
+        async fn tokenize_decode(
+            &self,
+            _inputs: Vec<Vec<i64>>,
+            _model: String,
+        ) -> Result<Vec<String>, EmbeddingGeneratorError> {
+            Err(Self::no_tokenizer())
+        }
+
+        async fn count_tokens(
+            &self,
+            inputs: Vec<String>,
+            _model: String,
+        ) -> Result<Vec<usize>, EmbeddingGeneratorError> {
+            Ok(inputs.iter().map(String::len).collect())
+        }
+
+        async fn rerank(
+            &self,
+            _query: String,
+            _passages: Vec<String>,
+            model: String,
+        ) -> Result<Vec<f32>, EmbeddingGeneratorError> {
+            self.rerank_scores
+                .clone()
+                .ok_or(EmbeddingGeneratorError::ModelNotFound(model))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use server_config::{DeviceKind, EmbeddingModel};
+    use std::sync::atomic::Ordering;
+    use testing::MockGenerator;
+
+    #[tokio::test]
+    async fn test_cached_embeddings() {
+        let generator = Arc::new(MockGenerator::default());
+        let router = EmbeddingRouter {
+            router: HashMap::from([("counting".to_string(), generator.clone() as _)]),
+            model_names: vec!["counting".into()],
+            templates: HashMap::new(),
+            long_input_policies: HashMap::new(),
+            post_processing: HashMap::new(),
+            tokenizers: HashMap::new(),
+            fingerprints: HashMap::new(),
+            cache: None,
+        }
+        .with_cache(EmbeddingCache::new("sqlite::memory:", 10).await.unwrap());
+
+        let embeddings = router
+            .generate_embeddings(vec!["a".into(), "bb".into()], "counting".into())
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![1.0], vec![2.0]]);
+        let embeddings = router
+            .generate_embeddings(
+                vec!["ccc".into(), "bb".into(), "a".into()],
+                "counting".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![3.0], vec![2.0], vec![1.0]]);
+        assert_eq!(generator.embedded.load(Ordering::SeqCst), 3);
+
+        let stats = router.cache_stats().await.unwrap().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 3, 3));
+    }
+
+    #[tokio::test]
+    async fn test_role_templates() {
+        let generator = Arc::new(MockGenerator::default());
+        let templates = server_config::TemplatesConfig {
+            query: Some("query: ".into()),
+            document: Some("passage: {text}.".into()),
+        };
+        let router = EmbeddingRouter {
+            router: HashMap::from([("counting".to_string(), generator as _)]),
+            model_names: vec!["counting".into()],
+            templates: HashMap::from([("counting".to_string(), templates)]),
+            long_input_policies: HashMap::new(),
+            post_processing: HashMap::new(),
+            tokenizers: HashMap::new(),
+            fingerprints: HashMap::new(),
+            cache: None,
+        };
+        let embed =
+            |role| router.generate_role_embeddings(vec!["ab".into()], "counting".into(), role);
+        assert_eq!(embed(EmbeddingRole::Query).await.unwrap(), vec![vec![9.0]]);
+        assert_eq!(
+            embed(EmbeddingRole::Document).await.unwrap(),
+            vec![vec![12.0]]
+        );
+        let embeddings = router
+            .generate_embeddings(vec!["ab".into()], "counting".into())
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![2.0]]);
+    }
+
+    #[tokio::test]
+    async fn test_standalone_tokenizer() {
+        let config = server_config::ServerConfig {
+            available_models: vec![EmbeddingModel {
+                model_kind: Hash,
+                max_sequence_length: Some(4),
+                tokenizer: Some(server_config::TokenizerConfig::Tiktoken(
+                    "cl100k_base".into(),
+                )),
+                ..Default::default()
+            }],
+            openai: None,
+            ..Default::default()
+        };
+        let router = EmbeddingRouter::new(Arc::new(config)).unwrap();
+        let inputs = vec!["hello world".to_string()];
+        // Tokenized by tiktoken, and not by characters as the hash model does
+        let tokens = router
+            .tokenize_encode(inputs.clone(), "hash".into())
+            .await
+            .unwrap();
+        assert_eq!(tokens[0].len(), 2);
+        let counts = router
+            .count_tokens(inputs.clone(), "hash".into())
+            .await
+            .unwrap();
+        assert_eq!(counts, vec![2]);
+        let decoded = router.tokenize_decode(tokens, "hash".into()).await.unwrap();
+        assert_eq!(decoded, inputs);
+        assert!(router
+            .tokenize_encode(inputs, "missing".into())
+            .await
+            .is_err());
+
+        // Long inputs are counted and cut with the same tokenizer, which has fewer tokens
+        // than the characters the model counts
+        let long = vec!["one two three four five six".to_string()];
+        let head = router
+            .embed(
+                long,
+                "hash".into(),
+                None,
+                Some(LongInputPolicy::TruncateHead),
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(head.truncated, vec![0]);
+        let tail = router
+            .generate_embeddings(vec![" five six".into()], "hash".into())
+            .await
+            .unwrap();
+        assert_eq!(head.embeddings, tail);
+    }
+
+    #[tokio::test]
+    async fn test_long_input_policies() {
+        let config = server_config::ServerConfig {
+            available_models: vec![EmbeddingModel {
+                model_kind: Hash,
+                max_sequence_length: Some(6),
+                ..Default::default()
+            }],
+            openai: None,
+            ..Default::default()
+        };
+        let router = EmbeddingRouter::new(Arc::new(config)).unwrap();
+        let hash = |texts: &[&str]| {
+            let texts = texts.iter().map(|t| t.to_string()).collect();
+            router.generate_embeddings(texts, "hash".into())
+        };
+        let embed = |policy| {
+            let inputs = vec!["abc".into(), "abcd efgh".into()];
+            router.embed(inputs, "hash".into(), None, Some(policy), true)
+        };
+
+        // Inputs of 6 tokens at most are embedded as they are, longer ones are truncated by
+        // the model, or cut into parts of 4 tokens
+        let expected = hash(&["abc", "abcd e"]).await.unwrap();
+        let tail = embed(LongInputPolicy::TruncateTail).await.unwrap();
+        assert_eq!(tail.embeddings, expected);
+        assert_eq!(tail.truncated, vec![1]);
+        assert_eq!(tail.tokens, Some(vec![3, 9]));
+        let head = embed(LongInputPolicy::TruncateHead).await.unwrap();
+        assert_eq!(head.embeddings, hash(&["abc", "efgh"]).await.unwrap());
+        assert_eq!(head.truncated, vec![1]);
+
+        let windows = embed(LongInputPolicy::SlidingWindow).await.unwrap();
+        assert_eq!(windows.embeddings[0], expected[0]);
+        assert_eq!(
+            windows.embeddings[1],
+            mean_pool(hash(&["abcd", " efg", "h"]).await.unwrap())
+        );
+        assert!(windows.truncated.is_empty());
+
+        let err = embed(LongInputPolicy::Error).await.unwrap_err();
+        assert!(matches!(
+            err,
+            EmbeddingGeneratorError::InputTooLong(_, 1, 9, 6)
+        ));
+    }
+
+    #[test]
+    fn test_check_local_model() {
+        let remote = EmbeddingModel {
+            model_kind: OpenAIAda02,
+            device_kind: DeviceKind::Remote,
+            ..Default::default()
+        };
+        assert!(check_local_model(&remote, true).is_ok());
+
+        let cross_encoder = EmbeddingModel {
+            model_kind: MsMarcoMiniLmL6V2,
+            device_kind: DeviceKind::Cpu,
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_local_model(&cross_encoder, false),
+            Err(EmbeddingGeneratorError::ConfigurationError(..))
+        ));
+
+        let downloaded = EmbeddingModel {
+            model_kind: AllMiniLmL6V2,
+            device_kind: DeviceKind::Cpu,
+            ..Default::default()
+        };
+        assert!(check_local_model(&downloaded, false).is_ok());
+        assert!(check_local_model(&downloaded, true).is_err());
+
+        let dir = TempDir::new("check-local-model");
+        let local = EmbeddingModel {
+            path: Some(dir.path().to_string_lossy().into()),
+            ..downloaded
+        };
+        let err = check_local_model(&local, true).unwrap_err();
+        assert!(err.to_string().contains("modules.json"));
+        for file in sentence_transformers::MODEL_FILES {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+        assert!(check_local_model(&local, true).is_ok());
+    }
 }
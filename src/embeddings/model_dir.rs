@@ -0,0 +1,426 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::server_config::{EmbeddingModel, ModelArchitecture, PoolingKind};
+use super::EmbeddingGeneratorError;
+
+/// Maximum number of tokens of an input, unless the model or its configuration sets it.
+const DEFAULT_MAX_SEQUENCE_LENGTH: usize = 512;
+
+/// Directory of the generated pooling configuration, distinct from the ones of the model.
+const POOLING_MODULE_PATH: &str = "indexify_pooling";
+
+const TRANSFORMER_MODULE: &str = "sentence_transformers.models.Transformer";
+const POOLING_MODULE: &str = "sentence_transformers.models.Pooling";
+
+/// A module of a sentence-transformer model, as listed in its `modules.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Module {
+    idx: usize,
+    name: String,
+    path: String,
+    #[serde(rename = "type")]
+    module_type: String,
+}
+
+/// Returns the files of the tokenizer of an architecture.
+fn tokenizer_files(architecture: &ModelArchitecture) -> &'static [&'static str] {
+    match architecture {
+        ModelArchitecture::Bert | ModelArchitecture::DistilBert => &["vocab.txt"],
+        ModelArchitecture::Roberta => &["vocab.json", "merges.txt"],
+        ModelArchitecture::Albert | ModelArchitecture::T5 => &["spiece.model"],
+    }
+}
+
+/// Returns the files that the directory of a custom model of an architecture needs.
+pub fn required_files(architecture: &ModelArchitecture) -> Vec<&'static str> {
+    let mut files = vec!["config.json", "rust_model.ot"];
+    files.extend(tokenizer_files(architecture));
+    files
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Value, EmbeddingGeneratorError> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        EmbeddingGeneratorError::ModelLoadingError(format!("{}: {}", path.display(), e))
+    })?;
+    serde_json::from_str(&content).map_err(|e| {
+        EmbeddingGeneratorError::ModelLoadingError(format!("{}: {}", path.display(), e))
+    })
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), EmbeddingGeneratorError> {
+    let content = serde_json::to_string_pretty(value)
+        .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?;
+    fs::write(path, content).map_err(|e| {
+        EmbeddingGeneratorError::ModelLoadingError(format!("{}: {}", path.display(), e))
+    })
+}
+
+fn read_modules(dir: &Path) -> Result<Option<Vec<Module>>, EmbeddingGeneratorError> {
+    let path = dir.join("modules.json");
+    if !path.exists() {
+        return Ok(None);
+    }
+    serde_json::from_value(read_json(&path)?)
+        .map(Some)
+        .map_err(|e| {
+            EmbeddingGeneratorError::ModelLoadingError(format!("{}: {}", path.display(), e))
+        })
+}
+
+/// Reads the hidden size of a transformer from its `config.json`.
+fn hidden_size(dir: &Path) -> Result<u64, EmbeddingGeneratorError> {
+    let config = read_json(&dir.join("config.json"))?;
+    ["hidden_size", "d_model", "dim"]
+        .iter()
+        .find_map(|key| config[key].as_u64())
+        .ok_or(EmbeddingGeneratorError::ModelLoadingError(format!(
+            "no hidden size in the config of `{}`",
+            dir.display()
+        )))
+}
+
+/// Reads the number of dimensions of the embeddings of a model from its directory, which
+/// is the output size of its last dense layer, or else the hidden size of its transformer.
+pub fn discover_dimensions(dir: &Path) -> Result<u64, EmbeddingGeneratorError> {
+    let modules = read_modules(dir)?.unwrap_or_default();
+    match modules
+        .iter()
+        .rev()
+        .find(|m| m.module_type.ends_with("Dense"))
+    {
+        Some(dense) => {
+            let config = read_json(&dir.join(&dense.path).join("config.json"))?;
+            config["out_features"]
+                .as_u64()
+                .ok_or(EmbeddingGeneratorError::ModelLoadingError(format!(
+                    "no output size in the config of the dense layer of `{}`",
+                    dir.display()
+                )))
+        }
+        None => hidden_size(dir),
+    }
+}
+
+/// Reads the maximum number of tokens of an input of a model from the `max_seq_length` of
+/// the `sentence_bert_config.json` of its directory.
+pub fn discover_max_sequence_length(dir: &Path) -> usize {
+    let path = dir.join("sentence_bert_config.json");
+    if !path.exists() {
+        return DEFAULT_MAX_SEQUENCE_LENGTH;
+    }
+    read_json(&path)
+        .ok()
+        .and_then(|config| config["max_seq_length"].as_u64())
+        .map_or(DEFAULT_MAX_SEQUENCE_LENGTH, |length| length as usize)
+}
+
+/// Checks the number of dimensions discovered from a model against its configuration.
+pub fn check_dimensions(
+    model: &EmbeddingModel,
+    dimensions: u64,
+) -> Result<u64, EmbeddingGeneratorError> {
+    match model.dimensions {
+        Some(expected) if expected != dimensions => {
+            Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+                "model `{}` has {} dimensions, not {}",
+                model.name(),
+                dimensions,
+                expected
+            )))
+        }
+        _ => Ok(dimensions),
+    }
+}
+
+/// A directory that models are prepared in, which is removed with the prepared models once
+/// it's dropped. The files the models link to are left alone.
+pub struct StagingDir(PathBuf);
+
+impl StagingDir {
+    pub fn new(path: PathBuf) -> Self {
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Returns the directory that the models of this server process are prepared in, so that
+/// servers running on the same host don't overwrite each other's models.
+pub fn staging_root() -> StagingDir {
+    StagingDir::new(
+        std::env::temp_dir()
+            .join("indexify")
+            .join(format!("models-{}", std::process::id())),
+    )
+}
+
+/// Links a file or directory of a model into its staging directory, or copies it on
+/// platforms without symbolic links.
+#[cfg(unix)]
+fn link(source: &Path, target: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(source, target)
+}
+
+#[cfg(not(unix))]
+fn link(source: &Path, target: &Path) -> std::io::Result<()> {
+    if !source.is_dir() {
+        return fs::copy(source, target).map(|_| ());
+    }
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        link(&entry.path(), &target.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/// Prepares a directory in the layout of sentence-transformers for a custom model, under
+/// `staging_root`, and returns its path. The files of the model are linked from its
+/// directory, while the modules, pooling and maximum sequence length are generated from
+/// its configuration, so plain Hugging Face models can be served too.
+pub fn prepare(
+    model: &EmbeddingModel,
+    staging_root: &Path,
+) -> Result<PathBuf, EmbeddingGeneratorError> {
+    let name = model.name();
+    // The staging directory is named after the model and replaced, so the name must not
+    // lead anywhere else
+    let mut components = Path::new(&name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+            "name `{}` of a custom model must be a plain directory name",
+            name
+        )));
+    }
+    let architecture =
+        model
+            .architecture
+            .as_ref()
+            .ok_or(EmbeddingGeneratorError::ConfigurationError(
+                "architecture".into(),
+                name.clone(),
+            ))?;
+    let source = fs::canonicalize(model.path.as_ref().ok_or(
+        EmbeddingGeneratorError::ConfigurationError("path".into(), name.clone()),
+    )?)
+    .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(format!("{}: {}", name, e)))?;
+
+    let config = read_json(&source.join("config.json"))?;
+    let model_type = config["model_type"].as_str().unwrap_or_default();
+    if model_type != architecture.to_string() {
+        return Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+            "model `{}` is a `{}` model, not `{}`",
+            name, model_type, architecture
+        )));
+    }
+    for file in required_files(architecture) {
+        if !source.join(file).exists() {
+            return Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+                "`{}` of model `{}` is missing from `{}`",
+                file,
+                name,
+                source.display()
+            )));
+        }
+    }
+
+    let staging = staging_root.join(&name);
+    let staging_error = |e: std::io::Error| {
+        EmbeddingGeneratorError::ModelLoadingError(format!("{}: {}", staging.display(), e))
+    };
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(staging_error)?;
+    }
+    fs::create_dir_all(&staging).map_err(staging_error)?;
+    for entry in fs::read_dir(&source).map_err(staging_error)? {
+        let entry = entry.map_err(staging_error)?;
+        let file_name = entry.file_name();
+        if file_name == "modules.json" || file_name == "sentence_bert_config.json" {
+            continue;
+        }
+        link(&entry.path(), &staging.join(&file_name)).map_err(staging_error)?;
+    }
+
+    let mut modules = read_modules(&source)?.unwrap_or_else(|| {
+        vec![Module {
+            idx: 0,
+            name: "0".into(),
+            path: "".into(),
+            module_type: TRANSFORMER_MODULE.into(),
+        }]
+    });
+    let has_pooling = modules.iter().any(|m| m.module_type == POOLING_MODULE);
+    if model.pooling.is_some() || !has_pooling {
+        modules.retain(|m| m.module_type != POOLING_MODULE);
+        modules.insert(
+            1.min(modules.len()),
+            Module {
+                idx: 1,
+                name: "1".into(),
+                path: POOLING_MODULE_PATH.into(),
+                module_type: POOLING_MODULE.into(),
+            },
+        );
+        let pooling = model.pooling.clone().unwrap_or_default();
+        fs::create_dir_all(staging.join(POOLING_MODULE_PATH)).map_err(staging_error)?;
+        write_json(
+            &staging.join(POOLING_MODULE_PATH).join("config.json"),
+            &serde_json::json!({
+                "word_embedding_dimension": hidden_size(&source)?,
+                "pooling_mode_cls_token": pooling == PoolingKind::Cls,
+                "pooling_mode_mean_tokens": pooling == PoolingKind::Mean,
+                "pooling_mode_max_tokens": false,
+                "pooling_mode_mean_sqrt_len_tokens": false,
+            }),
+        )?;
+    }
+    for (idx, module) in modules.iter_mut().enumerate() {
+        module.idx = idx;
+    }
+    write_json(&staging.join("modules.json"), &modules)?;
+
+    let sentence_bert_config_path = source.join("sentence_bert_config.json");
+    let mut sentence_bert_config = match sentence_bert_config_path.exists() {
+        true => read_json(&sentence_bert_config_path)?,
+        false => serde_json::json!({
+            "max_seq_length": DEFAULT_MAX_SEQUENCE_LENGTH,
+            "do_lower_case": false,
+        }),
+    };
+    if let Some(max_sequence_length) = model.max_sequence_length {
+        sentence_bert_config["max_seq_length"] = max_sequence_length.into();
+    }
+    write_json(
+        &staging.join("sentence_bert_config.json"),
+        &sentence_bert_config,
+    )?;
+
+    Ok(staging)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_config::EmbeddingModelKind;
+    use crate::test_util::TempDir;
+
+    fn model_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        for file in required_files(&ModelArchitecture::Bert) {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+        fs::write(
+            dir.path().join("config.json"),
+            r#"{"model_type": "bert", "hidden_size": 768}"#,
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_discover_dimensions() {
+        let temp_dir = model_dir("discover-dimensions");
+        let dir = temp_dir.path();
+        assert_eq!(discover_dimensions(dir).unwrap(), 768);
+
+        fs::create_dir_all(dir.join("2_Dense")).unwrap();
+        fs::write(
+            dir.join("2_Dense/config.json"),
+            r#"{"in_features": 768, "out_features": 256}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("modules.json"),
+            r#"[{"idx": 0, "name": "0", "path": "", "type": "sentence_transformers.models.Transformer"},
+                {"idx": 1, "name": "1", "path": "1_Pooling", "type": "sentence_transformers.models.Pooling"},
+                {"idx": 2, "name": "2", "path": "2_Dense", "type": "sentence_transformers.models.Dense"}]"#,
+        )
+        .unwrap();
+        assert_eq!(discover_dimensions(dir).unwrap(), 256);
+    }
+
+    #[test]
+    fn test_prepare_plain_model() {
+        let temp_dir = model_dir("prepare-plain-model");
+        let dir = temp_dir.path();
+        let staging_dir = TempDir::new("staging");
+        let staging_root = staging_dir.path();
+        let model = EmbeddingModel {
+            model_kind: EmbeddingModelKind::Custom,
+            name: Some("legal-bert".into()),
+            path: Some(dir.to_string_lossy().into()),
+            architecture: Some(ModelArchitecture::Bert),
+            max_sequence_length: Some(256),
+            pooling: Some(PoolingKind::Cls),
+            ..Default::default()
+        };
+        let staging = prepare(&model, staging_root).unwrap();
+        assert!(staging.join("rust_model.ot").exists());
+
+        let modules = read_modules(&staging).unwrap().unwrap();
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[1].path, POOLING_MODULE_PATH);
+        let pooling = read_json(&staging.join(POOLING_MODULE_PATH).join("config.json")).unwrap();
+        assert_eq!(pooling["pooling_mode_cls_token"], true);
+        assert_eq!(pooling["word_embedding_dimension"], 768);
+        let sentence_bert_config = read_json(&staging.join("sentence_bert_config.json")).unwrap();
+        assert_eq!(sentence_bert_config["max_seq_length"], 256);
+        assert_eq!(discover_max_sequence_length(&staging), 256);
+        assert_eq!(
+            discover_max_sequence_length(dir),
+            DEFAULT_MAX_SEQUENCE_LENGTH
+        );
+
+        let roberta = EmbeddingModel {
+            architecture: Some(ModelArchitecture::Roberta),
+            ..model
+        };
+        let err = prepare(&roberta, staging_root).unwrap_err();
+        assert!(err.to_string().contains("is a `bert` model, not `roberta`"));
+
+        for name in ["../legal-bert", "/tmp", "legal/bert", ".."] {
+            let escaping = EmbeddingModel {
+                name: Some(name.into()),
+                ..roberta.clone()
+            };
+            let err = prepare(&escaping, staging_root).unwrap_err();
+            assert!(err.to_string().contains("plain directory name"));
+        }
+    }
+
+    #[test]
+    fn test_staging_dir_removed_on_drop() {
+        let temp_dir = model_dir("staging-dir-removed");
+        let dir = temp_dir.path();
+        let staging_dir = TempDir::new("staging");
+        let staging_root = StagingDir::new(staging_dir.path().join("models"));
+        let model = EmbeddingModel {
+            model_kind: EmbeddingModelKind::Custom,
+            name: Some("legal-bert".into()),
+            path: Some(dir.to_string_lossy().into()),
+            architecture: Some(ModelArchitecture::Bert),
+            ..Default::default()
+        };
+        let staging = prepare(&model, staging_root.path()).unwrap();
+        assert!(staging.join("rust_model.ot").exists());
+
+        drop(staging_root);
+        assert!(!staging_dir.path().join("models").exists());
+        // Only the links to the files of the model are removed
+        assert!(dir.join("rust_model.ot").exists());
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use ort::execution_providers::CUDAExecutionProvider;
+use ort::session::Session;
+use ort::value::{Tensor, ValueType};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+use super::model_dir;
+use super::server_config::{self, DeviceKind, EmbeddingModel, OnnxModelConfig, PoolingKind};
+use super::{EmbeddingGenerator, EmbeddingGeneratorError, Tokens};
+
+/// Maximum number of tokens of an input, unless the tokenizer truncates inputs already.
+const DEFAULT_MAX_SEQUENCE_LENGTH: usize = 512;
+
+/// A sentence-transformer model exported to ONNX, along with its tokenizer.
+struct OnnxModel {
+    session: Mutex<Session>,
+    tokenizer: Tokenizer,
+    /// The tokenizer without truncation or padding, which tokenizes whole texts.
+    full_tokenizer: Tokenizer,
+    max_sequence_length: usize,
+    pooling: PoolingKind,
+    normalize: bool,
+    dimensions: u64,
+}
+
+impl OnnxModel {
+    fn new(
+        model: &EmbeddingModel,
+        config: &OnnxModelConfig,
+    ) -> Result<Self, EmbeddingGeneratorError> {
+        let loading_error = |e: ort::Error| {
+            EmbeddingGeneratorError::ModelLoadingError(format!("{}: {}", config.model_path, e))
+        };
+        let mut builder = Session::builder().map_err(loading_error)?;
+        if let DeviceKind::Gpu = model.device_kind {
+            builder = builder
+                .with_execution_providers([CUDAExecutionProvider::default().build()])
+                .map_err(loading_error)?;
+        }
+        let session = builder
+            .commit_from_file(&config.model_path)
+            .map_err(loading_error)?;
+
+        // The hidden size is the last dimension of the first output, whether it's the
+        // embeddings of the tokens or of the whole text
+        let dimensions = match session.outputs.first().map(|o| &o.output_type) {
+            Some(ValueType::Tensor { shape, .. }) => shape.last().copied().unwrap_or(-1),
+            _ => -1,
+        };
+        if dimensions <= 0 {
+            return Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+                "unable to find the embedding dimensions of `{}`",
+                config.model_path
+            )));
+        }
+        let dimensions = model_dir::check_dimensions(model, dimensions as u64)?;
+
+        let mut tokenizer = Tokenizer::from_file(&config.tokenizer_path).map_err(|e| {
+            EmbeddingGeneratorError::ModelLoadingError(format!("{}: {}", config.tokenizer_path, e))
+        })?;
+        let mut full_tokenizer = tokenizer.clone();
+        full_tokenizer
+            .with_truncation(None)
+            .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))?;
+        full_tokenizer.with_padding(None);
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        let max_length = match (model.max_sequence_length, tokenizer.get_truncation()) {
+            (Some(max_sequence_length), _) => Some(max_sequence_length),
+            (None, None) => Some(DEFAULT_MAX_SEQUENCE_LENGTH),
+            (None, Some(_)) => None,
+        };
+        if let Some(max_length) = max_length {
+            tokenizer
+                .with_truncation(Some(TruncationParams {
+                    max_length,
+                    ..Default::default()
+                }))
+                .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))?;
+        }
+        let max_sequence_length = tokenizer
+            .get_truncation()
+            .map_or(DEFAULT_MAX_SEQUENCE_LENGTH, |t| t.max_length);
+
+        Ok(Self {
+            session: Mutex::new(session),
+            tokenizer,
+            full_tokenizer,
+            max_sequence_length,
+            pooling: model.pooling.clone().unwrap_or_default(),
+            normalize: config.normalize,
+            dimensions,
+        })
+    }
+
+    fn embed(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        if inputs.is_empty() {
+            return Ok(vec![]);
+        }
+        let encodings = self
+            .tokenizer
+            .encode_batch(inputs, true)
+            .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
+        let batch_size = encodings.len();
+        let sequence_length = encodings[0].get_ids().len();
+        let to_tensor = |values: Vec<i64>| {
+            Tensor::from_array(([batch_size, sequence_length], values))
+                .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))
+        };
+        let input_ids = encodings
+            .iter()
+            .flat_map(|e| e.get_ids().iter().map(|&id| id as i64))
+            .collect();
+        let attention_mask: Vec<i64> = encodings
+            .iter()
+            .flat_map(|e| e.get_attention_mask().iter().map(|&m| m as i64))
+            .collect();
+        let token_type_ids = encodings
+            .iter()
+            .flat_map(|e| e.get_type_ids().iter().map(|&t| t as i64))
+            .collect();
+
+        let mut session = self
+            .session
+            .lock()
+            .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?;
+        let mut model_inputs = ort::inputs![
+            "input_ids" => to_tensor(input_ids)?,
+            "attention_mask" => to_tensor(attention_mask.clone())?,
+        ];
+        // Not every architecture takes token types, e.g. models based on RoBERTa don't
+        if session.inputs.iter().any(|i| i.name == "token_type_ids") {
+            model_inputs.push(("token_type_ids".into(), to_tensor(token_type_ids)?.into()));
+        }
+        let outputs = session
+            .run(model_inputs)
+            .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
+        let (shape, values) = outputs[0]
+            .try_extract_tensor::<f32>()
+            .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
+
+        let hidden_size = self.dimensions as usize;
+        let mut embeddings: Vec<Vec<f32>> = match shape.len() {
+            // Models exported with their pooling layer output text embeddings
+            2 => values.chunks(hidden_size).map(|e| e.to_vec()).collect(),
+            3 => values
+                .chunks(sequence_length * hidden_size)
+                .zip(attention_mask.chunks(sequence_length))
+                .map(|(tokens, mask)| pool(tokens, mask, hidden_size, &self.pooling))
+                .collect(),
+            _ => {
+                return Err(EmbeddingGeneratorError::ModelError(format!(
+                    "unexpected output shape {:?}",
+                    shape
+                )))
+            }
+        };
+        if self.normalize {
+            embeddings.iter_mut().for_each(|e| normalize(e));
+        }
+        Ok(embeddings)
+    }
+}
+
+/// Pools the embeddings of the tokens of a text, flattened, into a single embedding.
+fn pool(tokens: &[f32], mask: &[i64], hidden_size: usize, pooling: &PoolingKind) -> Vec<f32> {
+    match pooling {
+        PoolingKind::Cls => tokens[..hidden_size].to_vec(),
+        PoolingKind::Mean => {
+            let mut embedding = vec![0.0; hidden_size];
+            let mut count = 0.0;
+            for (token, &m) in tokens.chunks(hidden_size).zip(mask) {
+                if m == 0 {
+                    continue;
+                }
+                embedding.iter_mut().zip(token).for_each(|(e, t)| *e += t);
+                count += 1.0;
+            }
+            if count > 0.0 {
+                embedding.iter_mut().for_each(|e| *e /= count);
+            }
+            embedding
+        }
+    }
+}
+
+/// Scales an embedding to unit length.
+fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|e| e * e).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|e| *e /= norm);
+    }
+}
+
+/// A struct that represents a collection of sentence-transformer models exported to ONNX,
+/// and run with ONNX Runtime.
+pub struct OnnxModels {
+    models: HashMap<String, Arc<OnnxModel>>,
+}
+
+impl OnnxModels {
+    /// Creates a new instance of `OnnxModels` and loads the specified models.
+    ///
+    /// # Arguments
+    ///
+    /// * `models_to_load` - A vector of `EmbeddingModel` configurations, which all have an
+    ///   `onnx` configuration.
+    ///
+    /// # Returns
+    ///
+    /// * A result containing the `OnnxModels` instance if successful, or an
+    ///   `EmbeddingGeneratorError` if a model or tokenizer fails to load.
+    pub fn new(
+        models_to_load: Vec<server_config::EmbeddingModel>,
+    ) -> Result<Self, EmbeddingGeneratorError> {
+        let mut models = HashMap::new();
+        for model in models_to_load {
+            let name = model.name();
+            let config = model
+                .onnx
+                .as_ref()
+                .ok_or(EmbeddingGeneratorError::ConfigurationError(
+                    "onnx".into(),
+                    name.clone(),
+                ))?;
+            let onnx_model = OnnxModel::new(&model, config)?;
+            models.insert(name, Arc::new(onnx_model));
+        }
+        Ok(Self { models })
+    }
+
+    fn get(&self, model: &str) -> Result<Arc<OnnxModel>, EmbeddingGeneratorError> {
+        self.models
+            .get(model)
+            .cloned()
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model.into()))
+    }
+}
+
+#[async_trait]
+impl EmbeddingGenerator for OnnxModels {
+    async fn generate_embeddings(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        let onnx_model = self.get(&model)?;
+        tokio::task::spawn_blocking(move || onnx_model.embed(inputs))
+            .await
+            .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?
+    }
+
+    fn dimensions(&self, model: String) -> Result<u64, EmbeddingGeneratorError> {
+        Ok(self.get(&model)?.dimensions)
+    }
+
+    fn max_input_tokens(&self, model: String) -> Option<usize> {
+        self.get(&model).ok().map(|m| m.max_sequence_length)
+    }
+
+    async fn tokenize_text(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<Vec<String>>, EmbeddingGeneratorError> {
+        let onnx_model = self.get(&model)?;
+        let mut tokenized_inputs = Vec::new();
+        for input in inputs {
+            let encoding = onnx_model
+                .full_tokenizer
+                .encode(input, false)
+                .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
+            tokenized_inputs.push(encoding.get_tokens().to_vec());
+        }
+        Ok(tokenized_inputs)
+    }
+
+    async fn tokenize_encode(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<Vec<i64>>, EmbeddingGeneratorError> {
+        let onnx_model = self.get(&model)?;
+        let mut tokens = Vec::new();
+        for input in inputs {
+            let encoding = onnx_model
+                .full_tokenizer
+                .encode(input, false)
+                .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
+            tokens.push(encoding.get_ids().iter().map(|&id| id as i64).collect());
+        }
+        Ok(tokens)
+    }
+
+    async fn tokenize_encode_text(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<Tokens>, EmbeddingGeneratorError> {
+        let onnx_model = self.get(&model)?;
+        let mut tokenized = Vec::new();
+        for input in inputs {
+            let encoding = onnx_model
+                .full_tokenizer
+                .encode(input, false)
+                .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
+            tokenized.push(Tokens {
+                ids: encoding.get_ids().iter().map(|&id| id as i64).collect(),
+                texts: encoding.get_tokens().to_vec(),
+            });
+        }
+        Ok(tokenized)
+    }
+
+    async fn tokenize_decode(
+        &self,
+        inputs: Vec<Vec<i64>>,
+        model: String,
+    ) -> Result<Vec<String>, EmbeddingGeneratorError> {
+        let onnx_model = self.get(&model)?;
+        let mut results = Vec::new();
+        for tokens in inputs {
+            let ids: Vec<u32> = tokens.into_iter().map(|id| id as u32).collect();
+            let text = onnx_model
+                .tokenizer
+                .decode(&ids, true)
+                .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
+            results.push(text);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mean_pooling_ignores_padding() {
+        let tokens = vec![1.0, 2.0, 3.0, 4.0, 100.0, 100.0];
+        let mask = vec![1, 1, 0];
+        assert_eq!(pool(&tokens, &mask, 2, &PoolingKind::Mean), vec![2.0, 3.0]);
+        assert_eq!(pool(&tokens, &mask, 2, &PoolingKind::Cls), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut embedding = vec![3.0, 4.0];
+        normalize(&mut embedding);
+        assert_eq!(embedding, vec![0.6, 0.8]);
+    }
+}
@@ -1,68 +1,158 @@
-use crate::{EmbeddingGenerator, EmbeddingGeneratorError};
+use std::ops::Range;
+use std::time::Duration;
 
+use crate::{EmbeddingGenerator, EmbeddingGeneratorError, Tokens};
+
+use super::openai_compatible::{EmbeddingsClient, RetryPolicy};
+use super::rate_limit::TokenRateLimiter;
 use super::server_config::{self};
+use super::tiktoken::TiktokenTokenizer;
 use anyhow::Result;
-use async_openai::types::{CreateEmbeddingRequest, EmbeddingInput};
-use async_openai::{Client, Embeddings};
 use async_trait::async_trait;
+use strum::EnumProperty;
+use tracing::warn;
+
+/// URL of the embeddings endpoint of the OpenAI API.
+const OPENAI_EMBEDDINGS_URL: &str = "https://api.openai.com/v1/embeddings";
+
+/// Maximum number of tokens of an input of OpenAI embedding models.
+const MAX_INPUT_TOKENS: usize = 8191;
 
 /// A struct that represents an interface to the OpenAI API for generating text embeddings.
 ///
 /// This struct provides methods for generating text embeddings using the OpenAI API.
-/// It supports generating embeddings for multiple input texts using a specified model.
+/// It supports generating embeddings for multiple input texts using a specified model,
+/// either from OpenAI or from a deployment of the model in Azure OpenAI. Inputs are sent in
+/// as many requests as the limits of the API require.
 pub struct OpenAI {
-    client: Client,
-    model: String,
+    client: EmbeddingsClient,
+    /// Kind of the model, which is served under a name of its own.
+    model_kind: server_config::EmbeddingModelKind,
+    max_inputs_per_request: usize,
+    max_tokens_per_request: usize,
+    rate_limiter: Option<TokenRateLimiter>,
 
-    tokenizer: Result<tiktoken_rs::CoreBPE>,
+    tokenizer: TiktokenTokenizer,
 }
 
 impl OpenAI {
     /// Creates a new instance of `OpenAI` with the specified configuration.
     ///
     /// This method initializes the OpenAI client with the provided API key and sets the model
-    /// to be used for generating embeddings.
+    /// to be used for generating embeddings. With an Azure configuration, the client targets
+    /// the deployment of the model in the Azure OpenAI resource, which authenticates with an
+    /// `api-key` header.
     pub fn new(
         openai_config: server_config::OpenAIConfig,
         model_config: server_config::EmbeddingModel,
     ) -> Result<Self, EmbeddingGeneratorError> {
-        let client = Client::new().with_api_key(openai_config.api_key);
+        let model = model_config.model_kind.to_string();
+        let (url, headers) = match &openai_config.azure {
+            Some(azure) => {
+                let deployment = azure.deployments.get(&model_config.name()).ok_or(
+                    EmbeddingGeneratorError::ConfigurationError(
+                        "openai.azure.deployments".into(),
+                        model_config.name(),
+                    ),
+                )?;
+                let url = format!(
+                    "{}/openai/deployments/{}/embeddings?api-version={}",
+                    azure.endpoint.trim_end_matches('/'),
+                    deployment,
+                    azure.api_version
+                );
+                let headers =
+                    EmbeddingsClient::auth_headers(Some(&openai_config.api_key), Some("api-key"))?;
+                (url, headers)
+            }
+            None => (
+                OPENAI_EMBEDDINGS_URL.to_string(),
+                EmbeddingsClient::auth_headers(Some(&openai_config.api_key), None)?,
+            ),
+        };
+        let timeout = Duration::from_millis(openai_config.timeout_ms);
+        let client =
+            EmbeddingsClient::new(url, model, headers, timeout)?.with_retries(RetryPolicy {
+                max_retries: openai_config.max_retries,
+                ..Default::default()
+            });
         Ok(Self {
             client,
-            model: model_config.model_kind.to_string(),
-            tokenizer: tiktoken_rs::cl100k_base(),
+            model_kind: model_config.model_kind,
+            max_inputs_per_request: openai_config.max_inputs_per_request.max(1),
+            max_tokens_per_request: openai_config.max_tokens_per_request,
+            rate_limiter: openai_config.tokens_per_minute.map(TokenRateLimiter::new),
+            tokenizer: TiktokenTokenizer::new()?,
         })
     }
 }
 
+/// Splits inputs into consecutive batches of at most `max_inputs` inputs, and of at most
+/// `max_tokens` tokens unless a single input has more, given the number of tokens of each.
+fn sub_batches(token_counts: &[usize], max_inputs: usize, max_tokens: usize) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut tokens = 0;
+    for (i, &count) in token_counts.iter().enumerate() {
+        if i > start && (i - start == max_inputs || tokens + count > max_tokens) {
+            batches.push(start..i);
+            start = i;
+            tokens = 0;
+        }
+        tokens += count;
+    }
+    if start < token_counts.len() {
+        batches.push(start..token_counts.len());
+    }
+    batches
+}
+
 #[async_trait]
 impl EmbeddingGenerator for OpenAI {
     async fn generate_embeddings(
         &self,
-        inputs: Vec<String>,
+        mut inputs: Vec<String>,
         _model: String,
     ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
-        let embeddings = Embeddings::new(&self.client);
-        let response = embeddings
-            .create(CreateEmbeddingRequest {
-                input: EmbeddingInput::StringArray(inputs),
-                model: self.model.clone(),
-                user: None,
-            })
-            .await
-            .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
-        let mut embeddings: Vec<Vec<f32>> = Vec::new();
-        for embedding in response.data {
-            embeddings.push(embedding.embedding);
+        // Inputs over the limit of the model are truncated, like local models do
+        for input in inputs.iter_mut() {
+            if let Some(truncated) = self.tokenizer.truncate(input, MAX_INPUT_TOKENS) {
+                warn!(
+                    "truncating an input to the first {} tokens",
+                    MAX_INPUT_TOKENS
+                );
+                *input = truncated;
+            }
+        }
+        let token_counts: Vec<usize> = inputs
+            .iter()
+            .map(|input| self.tokenizer.count_tokens(input))
+            .collect();
+        let mut embeddings = Vec::with_capacity(inputs.len());
+        for batch in sub_batches(
+            &token_counts,
+            self.max_inputs_per_request,
+            self.max_tokens_per_request,
+        ) {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter
+                    .acquire(token_counts[batch.clone()].iter().sum())
+                    .await;
+            }
+            embeddings.extend(self.client.embed(inputs[batch].to_vec()).await?);
         }
         Ok(embeddings)
     }
 
     fn dimensions(&self, model: String) -> Result<u64, EmbeddingGeneratorError> {
-        match model.as_str() {
-            "text-embedding-ada-002" => Ok(1536),
-            _ => Err(EmbeddingGeneratorError::ModelNotFound(model)),
-        }
+        self.model_kind
+            .get_str("dimensions")
+            .and_then(|d| d.parse().ok())
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model))
+    }
+
+    fn max_input_tokens(&self, _model: String) -> Option<usize> {
+        Some(MAX_INPUT_TOKENS)
     }
 
     async fn tokenize_text(
@@ -70,17 +160,7 @@ impl EmbeddingGenerator for OpenAI {
         inputs: Vec<String>,
         _model: String,
     ) -> Result<Vec<Vec<String>>, EmbeddingGeneratorError> {
-        let mut results = Vec::new();
-        for input in &inputs {
-            let tokens = self
-                .tokenizer
-                .as_ref()
-                .unwrap()
-                .split_by_token(input, true)
-                .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
-            results.push(tokens);
-        }
-        Ok(results)
+        self.tokenizer.tokenize_text(inputs)
     }
 
     async fn tokenize_encode(
@@ -88,19 +168,7 @@ impl EmbeddingGenerator for OpenAI {
         inputs: Vec<String>,
         _model: String,
     ) -> Result<Vec<Vec<i64>>, EmbeddingGeneratorError> {
-        let mut result: Vec<Vec<i64>> = Vec::new();
-        for input in inputs {
-            let tokens: Vec<i64> = self
-                .tokenizer
-                .as_ref()
-                .unwrap()
-                .encode_ordinary(input.as_str())
-                .into_iter()
-                .map(|x| x as i64)
-                .collect();
-            result.push(tokens);
-        }
-        Ok(result)
+        Ok(self.tokenizer.tokenize_encode(inputs))
     }
 
     async fn tokenize_decode(
@@ -108,25 +176,24 @@ impl EmbeddingGenerator for OpenAI {
         inputs: Vec<Vec<i64>>,
         _model: String,
     ) -> Result<Vec<String>, EmbeddingGeneratorError> {
-        let mut result: Vec<String> = Vec::new();
-        for input in inputs {
-            let input_usize = input.into_iter().map(|x| x as usize).collect();
-            let text: String = self
-                .tokenizer
-                .as_ref()
-                .unwrap()
-                .decode(input_usize)
-                .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?;
-            result.push(text);
-        }
-        Ok(result)
+        self.tokenizer.tokenize_decode(inputs)
+    }
+
+    async fn tokenize_encode_text(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Tokens>, EmbeddingGeneratorError> {
+        Ok(self.tokenizer.tokenize_encode_text(inputs))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::server_config::AzureOpenAIConfig;
     use crate::OpenAIConfig;
 
+    use super::super::openai_compatible::testing;
     use super::*;
 
     #[tokio::test]
@@ -138,10 +205,12 @@ mod tests {
         let openai = OpenAI::new(
             OpenAIConfig {
                 api_key: api_key.unwrap(),
+                ..Default::default()
             },
             crate::EmbeddingModel {
                 model_kind: crate::EmbeddingModelKind::OpenAIAda02,
                 device_kind: crate::DeviceKind::Remote,
+                ..Default::default()
             },
         )
         .unwrap();
@@ -158,10 +227,12 @@ mod tests {
         let openai = OpenAI::new(
             OpenAIConfig {
                 api_key: "not needed".into(),
+                ..Default::default()
             },
             crate::EmbeddingModel {
                 model_kind: crate::EmbeddingModelKind::OpenAIAda02,
                 device_kind: crate::DeviceKind::Remote,
+                ..Default::default()
             },
         )
         .unwrap();
@@ -176,10 +247,12 @@ mod tests {
         let openai = OpenAI::new(
             OpenAIConfig {
                 api_key: "not needed".into(),
+                ..Default::default()
             },
             crate::EmbeddingModel {
                 model_kind: crate::EmbeddingModelKind::OpenAIAda02,
                 device_kind: crate::DeviceKind::Remote,
+                ..Default::default()
             },
         )
         .unwrap();
@@ -192,4 +265,62 @@ mod tests {
         let tokenized_text = openai.tokenize_decode(tokens, "".into()).await.unwrap();
         assert_eq!(inputs, tokenized_text);
     }
+
+    #[test]
+    fn test_sub_batches() {
+        assert_eq!(
+            sub_batches(&[1, 1, 1, 1, 1], 2, 100),
+            vec![0..2, 2..4, 4..5]
+        );
+        assert_eq!(
+            sub_batches(&[40, 50, 20, 200, 10], 10, 100),
+            vec![0..2, 2..3, 3..4, 4..5]
+        );
+        assert!(sub_batches(&[], 2, 100).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_azure_embeddings() {
+        let addr = testing::mock_server(
+            "/openai/deployments/ada-prod/embeddings",
+            |headers, query| {
+                headers.get("api-key").map(|v| v.as_bytes()) == Some(b"secret")
+                    && query == Some("api-version=2023-05-15")
+            },
+        )
+        .await;
+        let model = crate::EmbeddingModel {
+            model_kind: crate::EmbeddingModelKind::OpenAIAda02,
+            name: Some("azure-ada".into()),
+            device_kind: crate::DeviceKind::Remote,
+            ..Default::default()
+        };
+        let config = OpenAIConfig {
+            api_key: "secret".into(),
+            azure: Some(AzureOpenAIConfig {
+                endpoint: format!("{}/", addr),
+                api_version: "2023-05-15".into(),
+                deployments: [("azure-ada".to_string(), "ada-prod".to_string())]
+                    .into_iter()
+                    .collect(),
+            }),
+            ..Default::default()
+        };
+        let openai = OpenAI::new(config.clone(), model.clone()).unwrap();
+        let embeddings = openai
+            .generate_embeddings(vec!["hello".into(), "hi".into()], "".into())
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![5.0, 1.0], vec![2.0, 1.0]]);
+        assert_eq!(openai.dimensions("azure-ada".into()).unwrap(), 1536);
+
+        let undeployed = OpenAIConfig {
+            azure: config.azure.map(|azure| AzureOpenAIConfig {
+                deployments: Default::default(),
+                ..azure
+            }),
+            ..config
+        };
+        assert!(OpenAI::new(undeployed, model).is_err());
+    }
 }
@@ -0,0 +1,577 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::server_config::EmbeddingModel;
+use super::tiktoken::TiktokenTokenizer;
+use super::{EmbeddingGenerator, EmbeddingGeneratorError, Tokens};
+
+#[derive(Debug, Serialize)]
+struct EmbeddingsRequest<'a> {
+    input: &'a [String],
+    model: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f32>,
+    #[serde(default)]
+    index: usize,
+}
+
+/// How requests that are rate limited, or fail on the server, are retried. The server can
+/// tell how long to wait with a `Retry-After` header, otherwise the wait doubles with each
+/// retry. Either way, the wait is at most `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Returns the time to wait before retrying a request, from the `retry-after-ms` header of
+/// OpenAI, or the standard `Retry-After` header in seconds or as a date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(ms) = headers
+        .get("retry-after-ms")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<f64>().ok())
+    {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Some(Duration::from_secs_f64(seconds.max(0.0)));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// A client of the `/embeddings` endpoint of the OpenAI API, or of any server implementing it.
+pub struct EmbeddingsClient {
+    http: reqwest::Client,
+    url: String,
+    model: String,
+    retries: RetryPolicy,
+}
+
+impl EmbeddingsClient {
+    /// Creates a client that posts requests for `model` to `url`, with the given headers.
+    /// Requests that don't complete within `timeout` are retried.
+    pub fn new(
+        url: String,
+        model: String,
+        headers: HeaderMap,
+        timeout: Duration,
+    ) -> Result<Self, EmbeddingGeneratorError> {
+        let http = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(timeout)
+            .build()
+            .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?;
+        Ok(Self {
+            http,
+            url,
+            model,
+            retries: RetryPolicy::default(),
+        })
+    }
+
+    /// Sets how failed requests are retried.
+    pub fn with_retries(self, retries: RetryPolicy) -> Self {
+        Self { retries, ..self }
+    }
+
+    /// Returns the headers that send an API key, either as a bearer token in the
+    /// `Authorization` header, or as is in the given header.
+    pub fn auth_headers(
+        api_key: Option<&str>,
+        auth_header: Option<&str>,
+    ) -> Result<HeaderMap, EmbeddingGeneratorError> {
+        let mut headers = HeaderMap::new();
+        let api_key = match api_key {
+            Some(api_key) => api_key,
+            None => return Ok(headers),
+        };
+        let (name, value) = match auth_header {
+            Some(auth_header) => (
+                HeaderName::from_bytes(auth_header.as_bytes())
+                    .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?,
+                api_key.to_string(),
+            ),
+            None => (AUTHORIZATION, format!("Bearer {}", api_key)),
+        };
+        let mut value = HeaderValue::from_str(&value)
+            .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?;
+        value.set_sensitive(true);
+        headers.insert(name, value);
+        Ok(headers)
+    }
+
+    /// Generates the embeddings of the inputs, in the order of the inputs. Requests that are
+    /// rate limited, fail on the server or can't reach it are retried. Once retries run out,
+    /// rate limits fail as `Overloaded`, and server errors as `Unavailable`. Inputs the server
+    /// can't process fail as `ModelError`, and other failures, such as a rejected API key, as
+    /// `ProviderError`.
+    pub async fn embed(
+        &self,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        let mut backoff = self.retries.initial_backoff;
+        let mut retries = 0;
+        let response = loop {
+            let result = self
+                .http
+                .post(&self.url)
+                .json(&EmbeddingsRequest {
+                    input: &inputs,
+                    model: &self.model,
+                })
+                .send()
+                .await;
+            let can_retry = retries < self.retries.max_retries;
+            let (wait, err) = match result {
+                Ok(response) if response.status().is_success() => break response,
+                Ok(response) => {
+                    let status = response.status();
+                    let wait = retry_after(response.headers());
+                    let body = response.text().await.unwrap_or_default();
+                    let message = format!("`{}` responded with {}: {}", self.url, status, body);
+                    let err = match status {
+                        StatusCode::TOO_MANY_REQUESTS => {
+                            EmbeddingGeneratorError::Overloaded(self.model.clone())
+                        }
+                        _ if status.is_server_error() => EmbeddingGeneratorError::Unavailable(
+                            self.model.clone(),
+                            message.clone(),
+                        ),
+                        StatusCode::BAD_REQUEST
+                        | StatusCode::PAYLOAD_TOO_LARGE
+                        | StatusCode::UNPROCESSABLE_ENTITY => {
+                            return Err(EmbeddingGeneratorError::ModelError(message))
+                        }
+                        _ => {
+                            return Err(EmbeddingGeneratorError::ProviderError(
+                                self.model.clone(),
+                                message,
+                            ))
+                        }
+                    };
+                    if !can_retry {
+                        return Err(err);
+                    }
+                    (wait, message)
+                }
+                Err(e) if e.is_connect() || e.is_timeout() => {
+                    if !can_retry {
+                        return Err(EmbeddingGeneratorError::Unavailable(
+                            self.model.clone(),
+                            e.to_string(),
+                        ));
+                    }
+                    (None, e.to_string())
+                }
+                Err(e) => {
+                    return Err(EmbeddingGeneratorError::ProviderError(
+                        self.model.clone(),
+                        e.to_string(),
+                    ))
+                }
+            };
+            let wait = wait.unwrap_or(backoff).min(self.retries.max_backoff);
+            warn!(
+                "retrying a request to `{}` in {:?}, after: {}",
+                self.url, wait, err
+            );
+            tokio::time::sleep(wait).await;
+            backoff = (backoff * 2).min(self.retries.max_backoff);
+            retries += 1;
+        };
+        let mut response: EmbeddingsResponse = response
+            .json()
+            .await
+            .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
+        if response.data.len() != inputs.len() {
+            return Err(EmbeddingGeneratorError::ModelError(format!(
+                "`{}` returned {} embeddings for {} inputs",
+                self.url,
+                response.data.len(),
+                inputs.len()
+            )));
+        }
+        response.data.sort_by_key(|d| d.index);
+        Ok(response.data.into_iter().map(|d| d.embedding).collect())
+    }
+}
+
+/// A model served by a server implementing the OpenAI embeddings API. Texts are tokenized
+/// with the tokenizer of OpenAI models, which approximates the tokenizer of the model.
+pub struct OpenAICompatible {
+    client: EmbeddingsClient,
+    dimensions: u64,
+    max_sequence_length: Option<usize>,
+    tokenizer: TiktokenTokenizer,
+}
+
+impl OpenAICompatible {
+    /// Creates a new instance of `OpenAICompatible` for a model, which must have its server
+    /// and its dimensions configured.
+    pub fn new(model: &EmbeddingModel) -> Result<Self, EmbeddingGeneratorError> {
+        let name = model.name();
+        let config =
+            model
+                .openai_compatible
+                .as_ref()
+                .ok_or(EmbeddingGeneratorError::ConfigurationError(
+                    "openai_compatible".into(),
+                    name.clone(),
+                ))?;
+        let dimensions = model
+            .dimensions
+            .ok_or(EmbeddingGeneratorError::ConfigurationError(
+                "dimensions".into(),
+                name.clone(),
+            ))?;
+        let headers = EmbeddingsClient::auth_headers(
+            config.api_key.as_deref(),
+            config.auth_header.as_deref(),
+        )?;
+        let client = EmbeddingsClient::new(
+            format!("{}/embeddings", config.base_url.trim_end_matches('/')),
+            config.model.clone().unwrap_or(name),
+            headers,
+            Duration::from_millis(config.timeout_ms),
+        )?;
+        Ok(Self {
+            client,
+            dimensions,
+            max_sequence_length: model.max_sequence_length,
+            tokenizer: TiktokenTokenizer::new()?,
+        })
+    }
+}
+
+#[async_trait]
+impl EmbeddingGenerator for OpenAICompatible {
+    async fn generate_embeddings(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
+        // Longer inputs are truncated to their first tokens, like the ones of other models,
+        // as counted by the tokenizer of OpenAI models
+        let inputs = match self.max_sequence_length {
+            Some(max_tokens) => inputs
+                .into_iter()
+                .map(|input| self.tokenizer.truncate(&input, max_tokens).unwrap_or(input))
+                .collect(),
+            None => inputs,
+        };
+        let embeddings = self.client.embed(inputs).await?;
+        if let Some(embedding) = embeddings
+            .iter()
+            .find(|e| e.len() as u64 != self.dimensions)
+        {
+            return Err(EmbeddingGeneratorError::ModelError(format!(
+                "expected embeddings with {} dimensions, got {}",
+                self.dimensions,
+                embedding.len()
+            )));
+        }
+        Ok(embeddings)
+    }
+
+    fn dimensions(&self, _model: String) -> Result<u64, EmbeddingGeneratorError> {
+        Ok(self.dimensions)
+    }
+
+    fn max_input_tokens(&self, _model: String) -> Option<usize> {
+        self.max_sequence_length
+    }
+
+    async fn tokenize_text(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Vec<String>>, EmbeddingGeneratorError> {
+        self.tokenizer.tokenize_text(inputs)
+    }
+
+    async fn tokenize_encode(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Vec<i64>>, EmbeddingGeneratorError> {
+        Ok(self.tokenizer.tokenize_encode(inputs))
+    }
+
+    async fn tokenize_decode(
+        &self,
+        inputs: Vec<Vec<i64>>,
+        _model: String,
+    ) -> Result<Vec<String>, EmbeddingGeneratorError> {
+        self.tokenizer.tokenize_decode(inputs)
+    }
+
+    async fn tokenize_encode_text(
+        &self,
+        inputs: Vec<String>,
+        _model: String,
+    ) -> Result<Vec<Tokens>, EmbeddingGeneratorError> {
+        Ok(self.tokenizer.tokenize_encode_text(inputs))
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use axum::{
+        extract::RawQuery,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Json, Router,
+    };
+    use serde_json::json;
+
+    /// Starts a server that embeds each input at `route` as its length, when `authorized`
+    /// accepts the headers and query of the request, and returns its address.
+    pub async fn mock_server(
+        route: &str,
+        authorized: fn(&HeaderMap, Option<&str>) -> bool,
+    ) -> String {
+        let app = Router::new().route(
+            route,
+            post(
+                move |headers: HeaderMap,
+                      RawQuery(query): RawQuery,
+                      Json(request): Json<serde_json::Value>| async move {
+                    if !authorized(&headers, query.as_deref()) {
+                        return (StatusCode::UNAUTHORIZED, Json(json!({})));
+                    }
+                    // Returned out of order, to check that embeddings are sorted by index
+                    let data: Vec<serde_json::Value> = request["input"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .enumerate()
+                        .rev()
+                        .map(|(index, input)| {
+                            let length = input.as_str().unwrap().len() as f32;
+                            json!({"object": "embedding", "index": index, "embedding": [length, 1.0]})
+                        })
+                        .collect();
+                    (
+                        StatusCode::OK,
+                        Json(json!({"object": "list", "data": data, "model": request["model"]})),
+                    )
+                },
+            ),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        format!("http://{}", addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_config::{DeviceKind, EmbeddingModelKind, OpenAICompatibleConfig};
+
+    fn model(base_url: String, api_key: &str) -> EmbeddingModel {
+        EmbeddingModel {
+            model_kind: EmbeddingModelKind::OpenAICompatible,
+            device_kind: DeviceKind::Remote,
+            name: Some("in-house".into()),
+            dimensions: Some(2),
+            openai_compatible: Some(OpenAICompatibleConfig {
+                base_url,
+                model: None,
+                api_key: Some(api_key.into()),
+                auth_header: None,
+                timeout_ms: 1000,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_embeddings() {
+        let addr = testing::mock_server("/v1/embeddings", |headers, _| {
+            headers.get("authorization").map(|v| v.as_bytes()) == Some(b"Bearer secret")
+        })
+        .await;
+        let base_url = format!("{}/v1", addr);
+        let generator = OpenAICompatible::new(&model(base_url.clone(), "secret")).unwrap();
+        let embeddings = generator
+            .generate_embeddings(vec!["a".into(), "abc".into()], "in-house".into())
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![1.0, 1.0], vec![3.0, 1.0]]);
+
+        let unauthorized = OpenAICompatible::new(&model(base_url, "wrong")).unwrap();
+        let err = unauthorized
+            .generate_embeddings(vec!["a".into()], "in-house".into())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EmbeddingGeneratorError::ProviderError(..)));
+        assert!(err.to_string().contains("401"));
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        use axum::{response::IntoResponse, routing::post, Router};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Rate limited, then failing, then embedding each input as 1.0
+        static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+        let app = Router::new().route(
+            "/embeddings",
+            post(|| async {
+                match REQUESTS.fetch_add(1, Ordering::SeqCst) {
+                    0 => (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        [("retry-after-ms", "10")],
+                        "",
+                    )
+                        .into_response(),
+                    1 => (StatusCode::SERVICE_UNAVAILABLE, "").into_response(),
+                    _ => axum::Json(serde_json::json!({
+                        "data": [{"embedding": [1.0], "index": 0}]
+                    }))
+                    .into_response(),
+                }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/embeddings", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let client = |max_retries| {
+            EmbeddingsClient::new(
+                url.clone(),
+                "model".into(),
+                HeaderMap::new(),
+                Duration::from_secs(1),
+            )
+            .unwrap()
+            .with_retries(RetryPolicy {
+                max_retries,
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            })
+        };
+        let embeddings = client(2).embed(vec!["a".into()]).await.unwrap();
+        assert_eq!(embeddings, vec![vec![1.0]]);
+        assert_eq!(REQUESTS.load(Ordering::SeqCst), 3);
+
+        REQUESTS.store(0, Ordering::SeqCst);
+        let err = client(0).embed(vec!["a".into()]).await.unwrap_err();
+        assert!(matches!(err, EmbeddingGeneratorError::Overloaded(_)));
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_capped() {
+        use axum::{response::IntoResponse, routing::post, Router};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Asks to wait for an hour, then embeds each input as 1.0
+        static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+        let app = Router::new().route(
+            "/embeddings",
+            post(|| async {
+                match REQUESTS.fetch_add(1, Ordering::SeqCst) {
+                    0 => (StatusCode::TOO_MANY_REQUESTS, [("retry-after", "3600")], "")
+                        .into_response(),
+                    _ => axum::Json(serde_json::json!({
+                        "data": [{"embedding": [1.0], "index": 0}]
+                    }))
+                    .into_response(),
+                }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/embeddings", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let client = EmbeddingsClient::new(
+            url,
+            "model".into(),
+            HeaderMap::new(),
+            Duration::from_secs(1),
+        )
+        .unwrap()
+        .with_retries(RetryPolicy {
+            max_retries: 1,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+        });
+        let embeddings =
+            tokio::time::timeout(Duration::from_secs(5), client.embed(vec!["a".into()]))
+                .await
+                .expect("waited longer than the maximum backoff")
+                .unwrap();
+        assert_eq!(embeddings, vec![vec![1.0]]);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        // Accepts connections without ever answering
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/embeddings", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let client = EmbeddingsClient::new(
+            url,
+            "model".into(),
+            HeaderMap::new(),
+            Duration::from_millis(50),
+        )
+        .unwrap()
+        .with_retries(RetryPolicy {
+            max_retries: 1,
+            initial_backoff: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let err = client.embed(vec!["a".into()]).await.unwrap_err();
+        assert!(matches!(err, EmbeddingGeneratorError::Unavailable(..)));
+    }
+}
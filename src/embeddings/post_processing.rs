@@ -0,0 +1,98 @@
+use super::server_config::{PostProcessingConfig, Quantization};
+use super::EmbeddingGeneratorError;
+
+/// Applies the transforms of the configuration to embeddings: truncation to fewer
+/// dimensions, then normalization, then quantization.
+pub fn post_process(config: &PostProcessingConfig, embeddings: &mut [Vec<f32>]) {
+    for embedding in embeddings.iter_mut() {
+        if let Some(dimensions) = config.dimensions {
+            embedding.truncate(dimensions as usize);
+        }
+        if config.normalize {
+            let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+            if norm > 0.0 {
+                embedding.iter_mut().for_each(|v| *v /= norm);
+            }
+        }
+        match config.quantization {
+            Some(Quantization::Int8) => embedding
+                .iter_mut()
+                .for_each(|v| *v = (v.clamp(-1.0, 1.0) * 127.0).round()),
+            Some(Quantization::Binary) => embedding
+                .iter_mut()
+                .for_each(|v| *v = if *v > 0.0 { 1.0 } else { -1.0 }),
+            None => {}
+        }
+    }
+}
+
+/// Checks that the transforms of the configuration can be applied in order. Values are
+/// clamped to `[-1, 1]` by int8 quantization, so embeddings must be normalized first.
+pub fn check_post_processing(config: &PostProcessingConfig) -> Result<(), EmbeddingGeneratorError> {
+    if config.quantization == Some(Quantization::Int8) && !config.normalize {
+        return Err(EmbeddingGeneratorError::InternalError(
+            "int8 quantization needs normalized embeddings, set `normalize`".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the number of dimensions of embeddings of `dimensions` dimensions once they're
+/// post-processed, which can't be more than they have.
+pub fn post_processed_dimensions(
+    config: &PostProcessingConfig,
+    dimensions: u64,
+) -> Result<u64, EmbeddingGeneratorError> {
+    check_post_processing(config)?;
+    match config.dimensions {
+        Some(truncated) if truncated == 0 || truncated > dimensions => {
+            Err(EmbeddingGeneratorError::InternalError(format!(
+                "embeddings of {} dimensions can't be truncated to {}",
+                dimensions, truncated
+            )))
+        }
+        Some(truncated) => Ok(truncated),
+        None => Ok(dimensions),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post_process() {
+        let config = PostProcessingConfig {
+            dimensions: Some(2),
+            normalize: true,
+            quantization: None,
+        };
+        let mut embeddings = vec![vec![3.0, -4.0, 12.0], vec![0.0, 0.0, 1.0]];
+        post_process(&config, &mut embeddings);
+        assert_eq!(embeddings, vec![vec![0.6, -0.8], vec![0.0, 0.0]]);
+        assert_eq!(post_processed_dimensions(&config, 3).unwrap(), 2);
+        assert!(post_processed_dimensions(&config, 1).is_err());
+
+        let int8 = PostProcessingConfig {
+            quantization: Some(Quantization::Int8),
+            ..config.clone()
+        };
+        let mut embeddings = vec![vec![3.0, -4.0]];
+        post_process(&int8, &mut embeddings);
+        assert_eq!(embeddings, vec![vec![76.0, -102.0]]);
+        let unnormalized = PostProcessingConfig {
+            normalize: false,
+            ..int8
+        };
+        assert!(check_post_processing(&unnormalized).is_err());
+        assert!(post_processed_dimensions(&unnormalized, 3).is_err());
+
+        let binary = PostProcessingConfig {
+            quantization: Some(Quantization::Binary),
+            ..Default::default()
+        };
+        let mut embeddings = vec![vec![0.3, -0.1, 0.0]];
+        post_process(&binary, &mut embeddings);
+        assert_eq!(embeddings, vec![vec![1.0, -1.0, -1.0]]);
+    }
+}
@@ -0,0 +1,63 @@
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+/// A client-side limit of the number of tokens sent per minute to an API. It's a bucket
+/// that holds a minute worth of tokens, and refills continuously.
+pub struct TokenRateLimiter {
+    tokens_per_minute: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    available: f64,
+    updated: Instant,
+}
+
+impl TokenRateLimiter {
+    pub fn new(tokens_per_minute: u64) -> Self {
+        Self {
+            tokens_per_minute: tokens_per_minute as f64,
+            bucket: Mutex::new(Bucket {
+                available: tokens_per_minute as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `tokens` tokens can be sent, and takes them from the bucket. Callers are
+    /// served in order. A request of more tokens than the limit waits for a full bucket.
+    pub async fn acquire(&self, tokens: usize) {
+        let tokens = (tokens as f64).min(self.tokens_per_minute);
+        let tokens_per_second = self.tokens_per_minute / 60.0;
+        // The lock is held while waiting, so that later callers wait behind this one
+        let mut bucket = self.bucket.lock().await;
+        loop {
+            let now = Instant::now();
+            let refilled = now.duration_since(bucket.updated).as_secs_f64() * tokens_per_second;
+            bucket.available = (bucket.available + refilled).min(self.tokens_per_minute);
+            bucket.updated = now;
+            if bucket.available >= tokens {
+                bucket.available -= tokens;
+                return;
+            }
+            let missing = tokens - bucket.available;
+            sleep(Duration::from_secs_f64(missing / tokens_per_second)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_tokens() {
+        // 100 tokens per second
+        let limiter = TokenRateLimiter::new(6000);
+        let start = Instant::now();
+        limiter.acquire(6000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        limiter.acquire(50).await;
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
@@ -1,22 +1,38 @@
-use std::sync::mpsc;
+use std::collections::VecDeque;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use tokio::sync::Notify;
 
 use rust_bert::pipelines::sentence_embeddings::{
     SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
 };
+use rust_bert::RustBertError;
 use rust_tokenizers::tokenizer::TruncationStrategy;
 
-use super::server_config::{self, EmbeddingModelKind};
-use super::{EmbeddingGenerator, EmbeddingGeneratorError};
+use super::cross_encoder::CrossEncoder;
+use super::model_dir;
+use super::server_config::{self, BatchingConfig, EmbeddingModelKind, ModelLoadingConfig};
+use super::{EmbeddingGenerator, EmbeddingGeneratorError, ModelResidency, ModelStatus, Tokens};
 use std::collections::HashMap;
+use strum::EnumProperty;
+use tracing::{error, info, warn};
 
 enum ModelOperation {
     EncodeEmbeddings,
     Tokenize,
     TokenizeEncode,
+    /// Encodes the inputs into the ids of their tokens, with the text of each token.
+    TokenizeEncodeText,
     TokenizeDecode,
+    /// Scores the inputs against the query with a cross-encoder.
+    Rerank(String),
 }
 
 enum ModelResult {
@@ -24,8 +40,25 @@ enum ModelResult {
     Tokenized(Vec<Vec<String>>),
     TokenizedDecoded(Vec<String>),
     TokenizedEncoded(Vec<Vec<i64>>),
+    TokenizedEncodedText(Vec<Tokens>),
+    Scores(Vec<f32>),
 }
 
+/// Files that a local directory of a sentence-transformer model needs, on top of the
+/// tokenizer and pooling files.
+pub const MODEL_FILES: [&str; 3] = ["modules.json", "config.json", "rust_model.ot"];
+
+/// Time a replica waits before it restarts after its first failure. The time doubles with
+/// each consecutive failure, up to `MAX_RESTART_BACKOFF`.
+const INITIAL_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Maximum time a request waits for its model to load, before it's queued.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time between two checks for models that have been idle long enough to be unloaded.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 type Message = (
     String,
     Vec<String>,
@@ -34,127 +67,874 @@ type Message = (
     oneshot::Sender<Result<ModelResult, EmbeddingGeneratorError>>,
 );
 
+/// The queue of requests of a model, shared by its replicas.
+struct ModelQueue {
+    receiver: mpsc::Receiver<Message>,
+    /// Requests received while a batch was collected, which don't belong to it
+    pending: VecDeque<Message>,
+    batching: BatchingConfig,
+}
+
+impl ModelQueue {
+    /// Waits for the next request, and returns it along with the requests batched with it.
+    /// Requests whose caller stopped waiting, e.g. after its timeout, are dropped instead of
+    /// run. Returns `None` once every sender is dropped.
+    fn next(&mut self) -> Option<Vec<Message>> {
+        loop {
+            let message = match self.pending.pop_front() {
+                Some(message) => message,
+                None => self.receiver.recv().ok()?,
+            };
+            if message.4.is_closed() {
+                continue;
+            }
+            let mut batch = match message.3 {
+                ModelOperation::EncodeEmbeddings => {
+                    collect_batch(message, &self.receiver, &mut self.pending, &self.batching)
+                }
+                _ => vec![message],
+            };
+            // Callers may stop waiting while the batch is collected
+            batch.retain(|message| !message.4.is_closed());
+            if !batch.is_empty() {
+                return Some(batch);
+            }
+        }
+    }
+}
+
+/// A model loaded by a replica.
+enum LoadedModel {
+    SentenceEmbeddings(SentenceEmbeddingsModel),
+    CrossEncoder(CrossEncoder),
+}
+
+/// The status of each replica of a model, and a notification of their changes. Replicas
+/// share it until their threads exit, and stop once the model is unloaded.
+struct Replicas {
+    statuses: Mutex<Vec<ModelStatus>>,
+    changed: Notify,
+    unloaded: AtomicBool,
+    /// Wakes the replicas waiting to restart when the model is unloaded.
+    stopped: Condvar,
+}
+
+impl Replicas {
+    fn new(statuses: Vec<ModelStatus>) -> Self {
+        Self {
+            statuses: Mutex::new(statuses),
+            changed: Notify::new(),
+            unloaded: AtomicBool::new(false),
+            stopped: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<ModelStatus>> {
+        self.statuses.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sets the status of a replica, and returns whether the replica should keep running,
+    /// which it shouldn't once the model is unloaded.
+    fn set_status(&self, replica: usize, status: ModelStatus) -> bool {
+        self.lock()[replica] = status;
+        self.changed.notify_waiters();
+        !self.unloaded.load(Ordering::SeqCst)
+    }
+
+    /// Waits for `timeout`, or until the model is unloaded, and returns whether it was.
+    fn wait_unloaded(&self, timeout: Duration) -> bool {
+        let statuses = self.lock();
+        let _statuses = self
+            .stopped
+            .wait_timeout_while(statuses, timeout, |_| !self.unloaded.load(Ordering::SeqCst))
+            .unwrap_or_else(PoisonError::into_inner);
+        self.unloaded.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of replicas that have the model loaded.
+    fn ready(&self) -> usize {
+        self.lock()
+            .iter()
+            .filter(|status| **status == ModelStatus::Ready)
+            .count()
+    }
+}
+
+/// The queue of requests of a model, as seen by callers, and the status of its replicas.
+struct ModelHandle {
+    sender: mpsc::SyncSender<Message>,
+    request_timeout: Duration,
+    replicas: Arc<Replicas>,
+}
+
+impl ModelHandle {
+    /// Returns the status of the model, which is ready as long as one of its replicas is.
+    fn status(&self) -> ModelStatus {
+        let replicas = self.replicas.lock();
+        if replicas.contains(&ModelStatus::Ready) {
+            return ModelStatus::Ready;
+        }
+        replicas
+            .iter()
+            .find(|status| matches!(status, ModelStatus::Failed(_)))
+            .cloned()
+            .unwrap_or(ModelStatus::Loading)
+    }
+
+    /// Waits until the model is loaded, up to `LOAD_TIMEOUT`, and fails if it couldn't be.
+    async fn wait_loaded(&self, model: &str) -> Result<(), EmbeddingGeneratorError> {
+        let loaded = async {
+            loop {
+                let changed = self.replicas.changed.notified();
+                match self.status() {
+                    ModelStatus::Loading => changed.await,
+                    status => return status,
+                }
+            }
+        };
+        match tokio::time::timeout(LOAD_TIMEOUT, loaded).await {
+            Ok(ModelStatus::Failed(err)) => {
+                Err(EmbeddingGeneratorError::Unavailable(model.into(), err))
+            }
+            Ok(_) => Ok(()),
+            Err(_) => Err(EmbeddingGeneratorError::Unavailable(
+                model.into(),
+                format!("not loaded after {:?}", LOAD_TIMEOUT),
+            )),
+        }
+    }
+}
+
+/// The model is unloaded once its queue is dropped by the models and every request.
+impl Drop for ModelHandle {
+    fn drop(&mut self) {
+        let _statuses = self.replicas.lock();
+        self.replicas.unloaded.store(true, Ordering::SeqCst);
+        self.replicas.stopped.notify_all();
+    }
+}
+
+/// A model of the collection, whose replicas run while it's loaded.
+struct ModelSlot {
+    config: server_config::EmbeddingModel,
+    /// Memory in megabytes the replicas of the model take once loaded.
+    memory_mb: u64,
+    /// The queue of the model, while it's loaded.
+    handle: Option<Arc<ModelHandle>>,
+    /// The replicas of the times the model was unloaded, until their threads exit.
+    draining: Vec<Weak<Replicas>>,
+    last_used: Instant,
+}
+
+impl ModelSlot {
+    /// Unloads the model, and returns whether it was loaded. Its replicas keep their memory
+    /// until they've served the requests already queued.
+    fn unload(&mut self) -> bool {
+        match self.handle.take() {
+            Some(handle) => {
+                self.draining.push(Arc::downgrade(&handle.replicas));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the memory in megabytes the model takes while it's loaded, and the memory of
+    /// the replicas that still have it loaded after it was unloaded.
+    fn resident_mb(&mut self) -> u64 {
+        self.draining.retain(|replicas| replicas.strong_count() > 0);
+        let replicas = self
+            .config
+            .workers
+            .clone()
+            .unwrap_or_default()
+            .replicas
+            .max(1);
+        let draining: usize = self
+            .draining
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|replicas| replicas.ready())
+            .sum();
+        let loaded_mb = match self.handle {
+            Some(_) => self.memory_mb,
+            None => 0,
+        };
+        loaded_mb + self.memory_mb * draining as u64 / replicas as u64
+    }
+
+    /// Returns whether replicas of the model are still running after it was unloaded.
+    fn is_draining(&mut self) -> bool {
+        self.draining.retain(|replicas| replicas.strong_count() > 0);
+        !self.draining.is_empty()
+    }
+}
+
+/// The models of the collection, which are loaded on demand and unloaded to keep the memory
+/// of the loaded models under the budget. A model is unloaded by dropping its queue, and its
+/// replicas stop once the requests already queued are served.
+struct ModelSlots {
+    slots: Mutex<HashMap<String, ModelSlot>>,
+    memory_budget_mb: Option<u64>,
+}
+
+impl ModelSlots {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, ModelSlot>> {
+        self.slots.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the queue of a model, and loads the model first if it isn't loaded.
+    fn acquire(&self, model: &str) -> Result<Arc<ModelHandle>, EmbeddingGeneratorError> {
+        let mut slots = self.lock();
+        let slot = slots
+            .get_mut(model)
+            .ok_or_else(|| EmbeddingGeneratorError::ModelNotFound(model.into()))?;
+        slot.last_used = Instant::now();
+        if let Some(handle) = &slot.handle {
+            return Ok(handle.clone());
+        }
+        let memory_mb = slot.memory_mb;
+        self.make_room(&mut slots, model, memory_mb)?;
+        let slot = slots.get_mut(model).unwrap();
+        info!("loading model `{}`", model);
+        let handle = Arc::new(SentenceTransformerModels::spawn_workers(
+            slot.config.clone(),
+            None,
+        )?);
+        slot.handle = Some(handle.clone());
+        Ok(handle)
+    }
+
+    /// Unloads the least recently used models other than `model`, until `memory_mb` more
+    /// megabytes fit in the budget. Unloaded models count until their replicas exit, and the
+    /// model is unavailable until they do if unloading every other model isn't enough.
+    fn make_room(
+        &self,
+        slots: &mut HashMap<String, ModelSlot>,
+        model: &str,
+        memory_mb: u64,
+    ) -> Result<(), EmbeddingGeneratorError> {
+        let budget = match self.memory_budget_mb {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+        loop {
+            let resident: u64 = slots.values_mut().map(ModelSlot::resident_mb).sum();
+            if resident + memory_mb <= budget {
+                return Ok(());
+            }
+            let least_recently_used = slots
+                .iter()
+                .filter(|(name, slot)| slot.handle.is_some() && name.as_str() != model)
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(name, _)| name.clone());
+            match least_recently_used {
+                Some(name) => {
+                    info!("unloading model `{}` to make room for `{}`", name, model);
+                    slots.get_mut(&name).unwrap().unload();
+                }
+                None if slots.values_mut().any(ModelSlot::is_draining) => {
+                    return Err(EmbeddingGeneratorError::Unavailable(
+                        model.into(),
+                        "unloaded models are still serving their queued requests".into(),
+                    ))
+                }
+                None => {
+                    return Err(EmbeddingGeneratorError::InternalError(format!(
+                        "model `{}` needs {} MB, more than the memory budget of {} MB",
+                        model, memory_mb, budget
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Unloads a model, and returns whether it was loaded.
+    fn unload(&self, model: &str) -> Result<bool, EmbeddingGeneratorError> {
+        let mut slots = self.lock();
+        let slot = slots
+            .get_mut(model)
+            .ok_or_else(|| EmbeddingGeneratorError::ModelNotFound(model.into()))?;
+        Ok(slot.unload())
+    }
+
+    /// Unloads the models that haven't been loaded or requested for `idle`.
+    fn unload_idle(&self, idle: Duration) {
+        for (name, slot) in self.lock().iter_mut() {
+            if slot.handle.is_some() && slot.last_used.elapsed() >= idle {
+                info!("unloading model `{}`, idle for {:?}", name, idle);
+                slot.unload();
+            }
+        }
+    }
+}
+
+/// Returns the memory in megabytes the replicas of a model take once loaded: the configured
+/// memory of a replica, or else the size of its weights.
+fn memory_mb(model: &server_config::EmbeddingModel) -> u64 {
+    let replica_mb = model
+        .memory_mb
+        .or_else(|| match &model.path {
+            Some(path) => fs::metadata(Path::new(path).join("rust_model.ot"))
+                .ok()
+                .map(|metadata| metadata.len().div_ceil(1 << 20)),
+            None => model
+                .model_kind
+                .get_str("memory_mb")
+                .and_then(|m| m.parse().ok()),
+        })
+        .unwrap_or_default();
+    let replicas = model.workers.clone().unwrap_or_default().replicas.max(1);
+    replica_mb * replicas as u64
+}
+
+/// Sets the number of threads libtorch runs an operation with. The setting is shared by
+/// the whole process, so it's applied once, before any model is loaded.
+pub fn set_intra_op_threads(threads: usize) {
+    tch::set_num_threads(threads as i32);
+}
+
+/// Returns the message of a panic, which is usually a string.
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "panic".into(),
+    }
+}
+
+/// Result of the first attempt of a replica to load a model, sent to the caller of `new`.
+type LoadResult = (String, Result<(), String>);
+
 /// A struct that represents a collection of sentence transformer models.
 ///
 /// This struct provides methods for generating sentence embeddings using
-/// pre-trained transformer models. It supports multiple models and manages
-/// them in a separate thread.
+/// pre-trained transformer models. It supports multiple models, each with its own
+/// queue of requests served by one or more replicas running in their own threads.
+/// Models are loaded at startup or on their first request, and can be unloaded.
 pub struct SentenceTransformerModels {
-    sender: mpsc::SyncSender<Message>,
+    models: Arc<ModelSlots>,
+    dimensions: HashMap<String, u64>,
+    max_sequence_lengths: HashMap<String, usize>,
+    /// The directory the custom models are prepared in, if any, which is removed with them.
+    _staging: Option<model_dir::StagingDir>,
 }
 
 impl SentenceTransformerModels {
     /// Creates a new instance of `SentenceTransformerModels` and loads the specified models.
     ///
-    /// This method spawns a new thread that runs the `runner` method, which is responsible
-    /// for loading the specified models and processing incoming requests for generating
-    /// embeddings.
+    /// This method spawns the worker threads of each model, which load a replica of the
+    /// model and process incoming requests for generating embeddings. The directories of
+    /// custom models are prepared, and their dimensions discovered, before the threads are
+    /// spawned so that invalid models fail right away. It returns once every replica tried
+    /// to load its model, and fails if one couldn't, unless `allow_unavailable_models` is set,
+    /// in which case the replica keeps trying in the background. With lazy loading, models
+    /// are only loaded on their first request.
     ///
     /// # Arguments
     ///
     /// * `models_to_load` - A vector of `EmbeddingModel` configurations specifying the models
     ///   to be loaded.
+    /// * `allow_unavailable_models` - Whether models that fail to load are only logged.
+    /// * `loading` - When models are loaded and unloaded, and the memory they can take.
     ///
     /// # Returns
     ///
     /// * A result containing an `Arc` reference to the `SentenceTransformerModels` instance
     ///   if successful, or an `EmbeddingGeneratorError` if an error occurs.
     pub fn new(
-        models_to_load: Vec<server_config::EmbeddingModel>,
+        mut models_to_load: Vec<server_config::EmbeddingModel>,
+        allow_unavailable_models: bool,
+        loading: &ModelLoadingConfig,
     ) -> Result<Self, EmbeddingGeneratorError> {
-        let (sender, receiver) = mpsc::sync_channel(100);
-        thread::spawn(move || {
-            if let Err(err) = Self::runner(receiver, models_to_load) {
-                tracing::error!("embedding generator runner exited with error: {}", err);
+        let mut staging = None;
+        let mut dimensions = HashMap::new();
+        let mut max_sequence_lengths = HashMap::new();
+        for model in models_to_load.iter_mut() {
+            // Cross-encoders don't generate embeddings
+            if let EmbeddingModelKind::MsMarcoMiniLmL6V2 = model.model_kind {
+                continue;
+            }
+            let model_dimensions = match model.model_kind {
+                EmbeddingModelKind::Custom => {
+                    let staging_root = staging.get_or_insert_with(model_dir::staging_root);
+                    let dir = model_dir::prepare(model, staging_root.path())?;
+                    let model_dimensions = model_dir::discover_dimensions(&dir)?;
+                    model.path = Some(dir.to_string_lossy().into());
+                    Some(model_dimensions)
+                }
+                _ => match &model.path {
+                    Some(path) => Some(model_dir::discover_dimensions(Path::new(path))?),
+                    None => model
+                        .model_kind
+                        .get_str("dimensions")
+                        .and_then(|d| d.parse().ok()),
+                },
+            };
+            if let Some(model_dimensions) = model_dimensions {
+                let model_dimensions = model_dir::check_dimensions(model, model_dimensions)?;
+                dimensions.insert(model.name(), model_dimensions);
             }
+            let max_sequence_length = match &model.path {
+                Some(path) => Some(model_dir::discover_max_sequence_length(Path::new(path))),
+                None => model
+                    .model_kind
+                    .get_str("max_sequence_length")
+                    .and_then(|l| l.parse().ok()),
+            };
+            if let Some(max_sequence_length) = model.max_sequence_length.or(max_sequence_length) {
+                max_sequence_lengths.insert(model.name(), max_sequence_length);
+            }
+        }
+
+        let mut slots = HashMap::new();
+        for model in models_to_load {
+            let memory_mb = memory_mb(&model);
+            // A model of unknown size would never count against the budget
+            if loading.memory_budget_mb.is_some() && memory_mb == 0 {
+                return Err(EmbeddingGeneratorError::ConfigurationError(
+                    "memory_mb".into(),
+                    model.name(),
+                ));
+            }
+            if let Some(budget) = loading
+                .memory_budget_mb
+                .filter(|&budget| memory_mb > budget)
+            {
+                return Err(EmbeddingGeneratorError::InternalError(format!(
+                    "model `{}` needs {} MB, more than the memory budget of {} MB",
+                    model.name(),
+                    memory_mb,
+                    budget
+                )));
+            }
+            slots.insert(
+                model.name(),
+                ModelSlot {
+                    config: model,
+                    memory_mb,
+                    handle: None,
+                    draining: Vec::new(),
+                    last_used: Instant::now(),
+                },
+            );
+        }
+        let total_mb: u64 = slots.values().map(|slot| slot.memory_mb).sum();
+        if let Some(budget) = loading.memory_budget_mb {
+            if !loading.lazy && total_mb > budget {
+                return Err(EmbeddingGeneratorError::InternalError(format!(
+                    "models need {} MB, more than the memory budget of {} MB, unless they're \
+                     loaded lazily",
+                    total_mb, budget
+                )));
+            }
+        }
+
+        let (loaded_sender, loaded_receiver) = mpsc::channel();
+        let mut replicas = 0;
+        if !loading.lazy {
+            for slot in slots.values_mut() {
+                let handle = Self::spawn_workers(slot.config.clone(), Some(loaded_sender.clone()))?;
+                replicas += handle.replicas.lock().len();
+                slot.handle = Some(Arc::new(handle));
+            }
+        }
+        drop(loaded_sender);
+        for (model, result) in loaded_receiver.iter().take(replicas) {
+            if let Err(err) = result {
+                if !allow_unavailable_models {
+                    return Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+                        "{}: {}",
+                        model, err
+                    )));
+                }
+                warn!("model `{}` is unavailable until it loads: {}", model, err);
+            }
+        }
+        let models = Arc::new(ModelSlots {
+            slots: Mutex::new(slots),
+            memory_budget_mb: loading.memory_budget_mb,
         });
-        Ok(SentenceTransformerModels { sender })
+        if let Some(idle_unload_secs) = loading.idle_unload_secs {
+            Self::spawn_unloader(
+                Arc::downgrade(&models),
+                Duration::from_secs(idle_unload_secs),
+            )?;
+        }
+        Ok(SentenceTransformerModels {
+            models,
+            dimensions,
+            max_sequence_lengths,
+            _staging: staging,
+        })
     }
 
-    /// This method is run in a separate thread and listens for incoming requests to generate
-    /// embeddings using the loaded models. It sends the generated embeddings back to the caller
-    /// through a one-shot channel.
-    fn runner(
-        receiver: mpsc::Receiver<Message>,
-        models_to_load: Vec<server_config::EmbeddingModel>,
+    /// Spawns the thread that unloads the models that have been idle for `idle`, until the
+    /// models are dropped.
+    fn spawn_unloader(
+        models: Weak<ModelSlots>,
+        idle: Duration,
     ) -> Result<(), EmbeddingGeneratorError> {
-        let mut models: HashMap<String, SentenceEmbeddingsModel> = HashMap::new();
-        for model in &models_to_load {
-            match &model.model_kind {
-                EmbeddingModelKind::AllMiniLmL12V2 => {
-                    let model = SentenceEmbeddingsBuilder::remote(
-                        SentenceEmbeddingsModelType::AllMiniLmL12V2,
-                    )
-                    .create_model()
-                    .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))?;
-                    models.insert(EmbeddingModelKind::AllMiniLmL12V2.to_string(), model);
-                }
-                EmbeddingModelKind::AllMiniLmL6V2 => {
-                    let model = SentenceEmbeddingsBuilder::remote(
-                        SentenceEmbeddingsModelType::AllMiniLmL6V2,
-                    )
-                    .create_model()
-                    .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))?;
-                    models.insert(EmbeddingModelKind::AllMiniLmL6V2.to_string(), model);
+        thread::Builder::new()
+            .name("model-unloader".into())
+            .spawn(move || loop {
+                thread::sleep(IDLE_CHECK_INTERVAL);
+                match models.upgrade() {
+                    Some(models) => models.unload_idle(idle),
+                    None => return,
                 }
-                EmbeddingModelKind::T5Base => {
-                    let model = SentenceEmbeddingsBuilder::remote(
-                        SentenceEmbeddingsModelType::SentenceT5Base,
-                    )
-                    .create_model()
-                    .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))?;
-                    models.insert(EmbeddingModelKind::T5Base.to_string(), model);
-                }
-                _ => {
-                    return Err(EmbeddingGeneratorError::InternalError(
-                        "unknown model kind".into(),
-                    ));
+            })
+            .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Spawns the replicas of a model, which share the queue of the model, and returns the
+    /// handle of the queue. The result of the first attempt of each replica to load the model
+    /// is sent to `loaded`, if any.
+    fn spawn_workers(
+        model: server_config::EmbeddingModel,
+        loaded: Option<mpsc::Sender<LoadResult>>,
+    ) -> Result<ModelHandle, EmbeddingGeneratorError> {
+        let workers = model.workers.clone().unwrap_or_default();
+        let (sender, receiver) = mpsc::sync_channel(workers.queue_size);
+        let queue = Arc::new(Mutex::new(ModelQueue {
+            receiver,
+            pending: VecDeque::new(),
+            batching: model.batching.clone().unwrap_or_default(),
+        }));
+        let replicas = Arc::new(Replicas::new(vec![
+            ModelStatus::Loading;
+            workers.replicas.max(1)
+        ]));
+        for replica in 0..workers.replicas.max(1) {
+            let model = model.clone();
+            let queue = queue.clone();
+            let replicas = replicas.clone();
+            let loaded = loaded.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", model.name(), replica))
+                .spawn(move || Self::supervise(&model, &queue, &replicas, replica, loaded))
+                .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?;
+        }
+        Ok(ModelHandle {
+            sender,
+            request_timeout: Duration::from_millis(workers.request_timeout_ms),
+            replicas,
+        })
+    }
+
+    /// Runs a replica of a model, and restarts it with exponential backoff whenever it fails
+    /// to load the model or panics, until the queue of the model is closed or the model is
+    /// unloaded. The result of the first attempt to load the model is sent to `loaded`.
+    fn supervise(
+        model: &server_config::EmbeddingModel,
+        queue: &Mutex<ModelQueue>,
+        replicas: &Replicas,
+        replica: usize,
+        mut loaded: Option<mpsc::Sender<LoadResult>>,
+    ) {
+        let mut backoff = INITIAL_RESTART_BACKOFF;
+        loop {
+            let mut ready = false;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                Self::worker(model, queue, &mut || {
+                    ready = true;
+                    replicas.set_status(replica, ModelStatus::Ready);
+                    if let Some(loaded) = loaded.take() {
+                        let _ = loaded.send((model.name(), Ok(())));
+                    }
+                })
+            }));
+            let err = match result {
+                Ok(Ok(())) => {
+                    replicas.set_status(replica, ModelStatus::Unloaded);
+                    return;
                 }
+                Ok(Err(err)) => err.to_string(),
+                Err(panic) => panic_message(panic.as_ref()),
+            };
+            // A replica that served requests before failing restarts right away
+            if ready {
+                backoff = INITIAL_RESTART_BACKOFF;
+            }
+            error!(
+                "replica {} of model `{}` failed, restarting in {:?}: {}",
+                replica,
+                model.name(),
+                backoff,
+                err
+            );
+            if !replicas.set_status(replica, ModelStatus::Failed(err.clone())) {
+                return;
+            }
+            if let Some(loaded) = loaded.take() {
+                let _ = loaded.send((model.name(), Err(err)));
+            }
+            if replicas.wait_unloaded(backoff) {
+                return;
+            }
+            backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+            if !replicas.set_status(replica, ModelStatus::Loading) {
+                return;
             }
         }
-        for (model_name, inputs, batched_tokens, model_operation, sender) in receiver.iter() {
-            let model = models.get(&model_name);
-            if model.is_none() {
-                let _ = sender.send(Err(EmbeddingGeneratorError::ModelNotFound(model_name)));
-                continue;
+    }
+
+    /// Loads a model, for a replica.
+    fn load(model: &server_config::EmbeddingModel) -> Result<LoadedModel, EmbeddingGeneratorError> {
+        let model_type = match &model.model_kind {
+            EmbeddingModelKind::AllMiniLmL12V2 => Some(SentenceEmbeddingsModelType::AllMiniLmL12V2),
+            EmbeddingModelKind::AllMiniLmL6V2 => Some(SentenceEmbeddingsModelType::AllMiniLmL6V2),
+            EmbeddingModelKind::T5Base => Some(SentenceEmbeddingsModelType::SentenceT5Base),
+            // Custom models are always loaded from their prepared directory
+            EmbeddingModelKind::Custom => None,
+            EmbeddingModelKind::MsMarcoMiniLmL6V2 => {
+                return Ok(LoadedModel::CrossEncoder(CrossEncoder::new(model)?));
             }
-            match model_operation {
-                ModelOperation::EncodeEmbeddings => {
-                    let result = model
-                        .unwrap()
-                        .encode(&inputs)
-                        .map(ModelResult::Embeddings)
-                        .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()));
-                    let _ = sender.send(result);
+            _ => {
+                return Err(EmbeddingGeneratorError::InternalError(
+                    "unknown model kind".into(),
+                ));
+            }
+        };
+        let sentence_embeddings_model: Result<SentenceEmbeddingsModel, RustBertError> =
+            match (&model.path, model_type) {
+                (Some(path), _) => SentenceEmbeddingsBuilder::local(path).create_model(),
+                (None, Some(model_type)) => {
+                    SentenceEmbeddingsBuilder::remote(model_type).create_model()
                 }
-                ModelOperation::Tokenize => {
-                    let tokenizer = model.unwrap().get_tokenizer();
-                    let mut tokenized_inputs = Vec::new();
-                    for input in &inputs {
-                        tokenized_inputs.push(tokenizer.tokenize(input));
-                    }
-                    let _ = sender.send(Ok(ModelResult::Tokenized(tokenized_inputs)));
+                (None, None) => {
+                    return Err(EmbeddingGeneratorError::ConfigurationError(
+                        "path".into(),
+                        model.name(),
+                    ))
+                }
+            };
+        sentence_embeddings_model
+            .map(LoadedModel::SentenceEmbeddings)
+            .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))
+    }
+
+    /// This method is run by each replica of a model in its own thread. It loads the model,
+    /// calls `on_loaded`, then takes requests from the queue of the model, with requests to
+    /// embed texts coalesced into batches. It sends the results back to each caller through
+    /// a one-shot channel.
+    fn worker(
+        model: &server_config::EmbeddingModel,
+        queue: &Mutex<ModelQueue>,
+        on_loaded: &mut dyn FnMut(),
+    ) -> Result<(), EmbeddingGeneratorError> {
+        let loaded_model = Self::load(model)?;
+        on_loaded();
+        loop {
+            // The queue is locked while a batch is collected, but not while it runs. A replica
+            // that panicked can't have left the queue in an inconsistent state.
+            let batch = match queue.lock().unwrap_or_else(PoisonError::into_inner).next() {
+                Some(batch) => batch,
+                None => return Ok(()),
+            };
+            match &loaded_model {
+                LoadedModel::SentenceEmbeddings(model)
+                    if matches!(batch[0].3, ModelOperation::EncodeEmbeddings) =>
+                {
+                    Self::encode_batch(model, batch)
                 }
-                ModelOperation::TokenizeEncode => {
-                    let tokenizer = model.unwrap().get_tokenizer();
-                    let result =
-                        tokenizer.encode_list(&inputs, 512, &TruncationStrategy::DoNotTruncate, 0);
-                    let tokens: Vec<Vec<i64>> = result.into_iter().map(|t| t.token_ids).collect();
-                    let _ = sender.send(Ok(ModelResult::TokenizedEncoded(tokens)));
+                LoadedModel::SentenceEmbeddings(model) => {
+                    for (_, inputs, batched_tokens, model_operation, sender) in batch {
+                        let _ =
+                            sender.send(Self::run(model, model_operation, inputs, batched_tokens));
+                    }
                 }
-                ModelOperation::TokenizeDecode => {
-                    let tokenizer = model.unwrap().get_tokenizer();
-                    let mut results: Vec<String> = Vec::new();
-                    for tokens in batched_tokens {
-                        let result = tokenizer.decode(&tokens, true, true);
-                        results.push(result);
+                LoadedModel::CrossEncoder(cross_encoder) => {
+                    for (model_name, inputs, _, model_operation, sender) in batch {
+                        let result = match model_operation {
+                            ModelOperation::Rerank(query) => cross_encoder
+                                .score(&query, &inputs)
+                                .map(ModelResult::Scores),
+                            _ => Err(EmbeddingGeneratorError::ModelNotFound(model_name)),
+                        };
+                        let _ = sender.send(result);
                     }
-                    let _ = sender.send(Ok(ModelResult::TokenizedDecoded(results)));
                 }
             }
         }
-        Ok(())
     }
+
+    /// Sends a request to the queue of a model, and waits for its result until the request
+    /// timeout of the model. The model is loaded first if it isn't, and the request waits for
+    /// it to load. A full queue rejects the request rather than blocking the async runtime
+    /// until a replica frees a slot.
+    async fn request(
+        &self,
+        model: String,
+        inputs: Vec<String>,
+        batched_tokens: Vec<Vec<i64>>,
+        model_operation: ModelOperation,
+    ) -> Result<ModelResult, EmbeddingGeneratorError> {
+        let handle = self.models.acquire(&model)?;
+        handle.wait_loaded(&model).await?;
+        let (tx, rx) = oneshot::channel();
+        handle
+            .sender
+            .try_send((model.clone(), inputs, batched_tokens, model_operation, tx))
+            .map_err(|e| match e {
+                mpsc::TrySendError::Full(_) => EmbeddingGeneratorError::Overloaded(model.clone()),
+                mpsc::TrySendError::Disconnected(_) => EmbeddingGeneratorError::Unavailable(
+                    model.clone(),
+                    "no replica of the model is running".into(),
+                ),
+            })?;
+        match tokio::time::timeout(handle.request_timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(EmbeddingGeneratorError::Unavailable(
+                model,
+                "the replica of the model stopped".into(),
+            )),
+            Err(_) => Err(EmbeddingGeneratorError::Unavailable(
+                model,
+                format!("no result after {:?}", handle.request_timeout),
+            )),
+        }
+    }
+
+    /// Embeds the texts of a batch of requests in a single forward pass, and sends the
+    /// embeddings of the texts of each request back to it.
+    fn encode_batch(model: &SentenceEmbeddingsModel, mut batch: Vec<Message>) {
+        let lengths: Vec<usize> = batch.iter().map(|message| message.1.len()).collect();
+        let inputs: Vec<String> = batch
+            .iter_mut()
+            .flat_map(|message| std::mem::take(&mut message.1))
+            .collect();
+        match model.encode(&inputs) {
+            Ok(embeddings) => {
+                let mut embeddings = embeddings.into_iter();
+                for (message, length) in batch.into_iter().zip(lengths) {
+                    let embeddings = embeddings.by_ref().take(length).collect();
+                    let _ = message.4.send(Ok(ModelResult::Embeddings(embeddings)));
+                }
+            }
+            Err(err) => {
+                for message in batch {
+                    let _ = message
+                        .4
+                        .send(Err(EmbeddingGeneratorError::ModelError(err.to_string())));
+                }
+            }
+        }
+    }
+
+    /// Runs an operation of a sentence embeddings model.
+    fn run(
+        model: &SentenceEmbeddingsModel,
+        model_operation: ModelOperation,
+        inputs: Vec<String>,
+        batched_tokens: Vec<Vec<i64>>,
+    ) -> Result<ModelResult, EmbeddingGeneratorError> {
+        match model_operation {
+            ModelOperation::EncodeEmbeddings => model
+                .encode(&inputs)
+                .map(ModelResult::Embeddings)
+                .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string())),
+            ModelOperation::Tokenize => {
+                let tokenizer = model.get_tokenizer();
+                let mut tokenized_inputs = Vec::new();
+                for input in &inputs {
+                    tokenized_inputs.push(tokenizer.tokenize(input));
+                }
+                Ok(ModelResult::Tokenized(tokenized_inputs))
+            }
+            ModelOperation::TokenizeEncode => {
+                let tokenizer = model.get_tokenizer();
+                let result =
+                    tokenizer.encode_list(&inputs, 512, &TruncationStrategy::DoNotTruncate, 0);
+                let tokens: Vec<Vec<i64>> = result.into_iter().map(|t| t.token_ids).collect();
+                Ok(ModelResult::TokenizedEncoded(tokens))
+            }
+            ModelOperation::TokenizeEncodeText => {
+                let tokenizer = model.get_tokenizer();
+                let result =
+                    tokenizer.encode_list(&inputs, 512, &TruncationStrategy::DoNotTruncate, 0);
+                let tokenized = result
+                    .into_iter()
+                    .map(|t| Tokens {
+                        texts: t
+                            .token_ids
+                            .iter()
+                            .map(|&id| tokenizer.decode(&[id], false, false))
+                            .collect(),
+                        ids: t.token_ids,
+                    })
+                    .collect();
+                Ok(ModelResult::TokenizedEncodedText(tokenized))
+            }
+            ModelOperation::TokenizeDecode => {
+                let tokenizer = model.get_tokenizer();
+                let mut results: Vec<String> = Vec::new();
+                for tokens in batched_tokens {
+                    let result = tokenizer.decode(&tokens, true, true);
+                    results.push(result);
+                }
+                Ok(ModelResult::TokenizedDecoded(results))
+            }
+            ModelOperation::Rerank(_) => Err(EmbeddingGeneratorError::InternalError(
+                "sentence embeddings models can not re-rank".into(),
+            )),
+        }
+    }
+}
+
+/// Collects the requests to embed texts with the model of `first` into a batch, from the
+/// pending requests and then the ones received before `max_wait_ms` elapses, until the batch
+/// holds `max_batch_size` texts. Requests that don't fit in the batch are left pending, and
+/// requests whose caller stopped waiting are dropped.
+fn collect_batch(
+    first: Message,
+    receiver: &mpsc::Receiver<Message>,
+    pending: &mut VecDeque<Message>,
+    batching: &BatchingConfig,
+) -> Vec<Message> {
+    let model = first.0.clone();
+    let batchable = |message: &Message| {
+        message.0 == model && matches!(message.3, ModelOperation::EncodeEmbeddings)
+    };
+    let mut size = first.1.len();
+    let mut batch = vec![first];
+    let mut skipped = VecDeque::new();
+    while let Some(message) = pending.pop_front() {
+        if message.4.is_closed() {
+            continue;
+        }
+        if batchable(&message) && size + message.1.len() <= batching.max_batch_size {
+            size += message.1.len();
+            batch.push(message);
+        } else {
+            skipped.push_back(message);
+        }
+    }
+    *pending = skipped;
+
+    let deadline = Instant::now() + Duration::from_millis(batching.max_wait_ms);
+    while size < batching.max_batch_size {
+        let message =
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(message) => message,
+                Err(_) => break,
+            };
+        if message.4.is_closed() {
+            continue;
+        }
+        if !batchable(&message) {
+            pending.push_back(message);
+        } else if size + message.1.len() <= batching.max_batch_size {
+            size += message.1.len();
+            batch.push(message);
+        } else {
+            // The batch is as full as it gets, the request starts the next one
+            pending.push_back(message);
+            break;
+        }
+    }
+    batch
 }
 
 #[async_trait]
@@ -164,33 +944,55 @@ impl EmbeddingGenerator for SentenceTransformerModels {
         texts: Vec<String>,
         model: String,
     ) -> Result<Vec<Vec<f32>>, EmbeddingGeneratorError> {
-        let (tx, rx) = oneshot::channel();
-        let _ = self
-            .sender
-            .send((model, texts, vec![], ModelOperation::EncodeEmbeddings, tx));
-        match rx.await {
-            Ok(result) => match result {
-                Ok(ModelResult::Embeddings(embeddings)) => Ok(embeddings),
-                Err(err) => Err(err),
-                Ok(_) => Err(EmbeddingGeneratorError::InternalError(
-                    "unexpected tokenized result".into(),
-                )),
-            },
-            Err(_) => Err(EmbeddingGeneratorError::InternalError(
-                "channel closed unexpectedly".into(),
+        match self
+            .request(model, texts, vec![], ModelOperation::EncodeEmbeddings)
+            .await?
+        {
+            ModelResult::Embeddings(embeddings) => Ok(embeddings),
+            _ => Err(EmbeddingGeneratorError::InternalError(
+                "unexpected tokenized result".into(),
             )),
         }
     }
 
     fn dimensions(&self, model: String) -> Result<u64, EmbeddingGeneratorError> {
-        match model.as_str() {
-            "all-minilm-l12-v2" => Ok(384),
-            "all-minilm-l6-v2" => Ok(384),
-            "all-mpnet-base-v2" => Ok(768),
-            "all-distilroberta-v1" => Ok(768),
-            "t5-base" => Ok(768),
-            _ => Err(EmbeddingGeneratorError::ModelNotFound(model)),
-        }
+        self.dimensions
+            .get(&model)
+            .copied()
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model))
+    }
+
+    fn max_input_tokens(&self, model: String) -> Option<usize> {
+        self.max_sequence_lengths.get(&model).copied()
+    }
+
+    fn status(&self, model: String) -> Result<ModelStatus, EmbeddingGeneratorError> {
+        let slots = self.models.lock();
+        let slot = slots
+            .get(&model)
+            .ok_or(EmbeddingGeneratorError::ModelNotFound(model))?;
+        Ok(match &slot.handle {
+            Some(handle) => handle.status(),
+            None => ModelStatus::Unloaded,
+        })
+    }
+
+    fn residency(&self, model: String) -> Option<ModelResidency> {
+        let slots = self.models.lock();
+        let slot = slots.get(&model)?;
+        Some(ModelResidency {
+            resident: slot.handle.is_some(),
+            memory_mb: slot.memory_mb,
+            idle_secs: slot.last_used.elapsed().as_secs(),
+        })
+    }
+
+    async fn load_model(&self, model: String) -> Result<(), EmbeddingGeneratorError> {
+        self.models.acquire(&model)?.wait_loaded(&model).await
+    }
+
+    fn unload_model(&self, model: String) -> Result<bool, EmbeddingGeneratorError> {
+        self.models.unload(&model)
     }
 
     async fn tokenize_text(
@@ -198,22 +1000,13 @@ impl EmbeddingGenerator for SentenceTransformerModels {
         inputs: Vec<String>,
         model: String,
     ) -> Result<Vec<Vec<String>>, EmbeddingGeneratorError> {
-        let (tx, rx) = oneshot::channel();
-
-        let _ = self
-            .sender
-            .send((model, inputs, vec![], ModelOperation::Tokenize, tx));
-
-        match rx.await {
-            Ok(result) => match result {
-                Ok(ModelResult::Tokenized(tokenized_texts)) => Ok(tokenized_texts),
-                Err(err) => Err(err),
-                Ok(_) => Err(EmbeddingGeneratorError::InternalError(
-                    "unexpected embeddings result".into(),
-                )),
-            },
-            Err(_) => Err(EmbeddingGeneratorError::InternalError(
-                "channel closed unexpectedly".into(),
+        match self
+            .request(model, inputs, vec![], ModelOperation::Tokenize)
+            .await?
+        {
+            ModelResult::Tokenized(tokenized_texts) => Ok(tokenized_texts),
+            _ => Err(EmbeddingGeneratorError::InternalError(
+                "unexpected embeddings result".into(),
             )),
         }
     }
@@ -223,22 +1016,29 @@ impl EmbeddingGenerator for SentenceTransformerModels {
         inputs: Vec<String>,
         model: String,
     ) -> Result<Vec<Vec<i64>>, EmbeddingGeneratorError> {
-        let (tx, rx) = oneshot::channel();
+        match self
+            .request(model, inputs, vec![], ModelOperation::TokenizeEncode)
+            .await?
+        {
+            ModelResult::TokenizedEncoded(tokens) => Ok(tokens),
+            _ => Err(EmbeddingGeneratorError::InternalError(
+                "unexpected embeddings result".into(),
+            )),
+        }
+    }
 
-        let _ = self
-            .sender
-            .send((model, inputs, vec![], ModelOperation::TokenizeEncode, tx));
-
-        match rx.await {
-            Ok(result) => match result {
-                Ok(ModelResult::TokenizedEncoded(tokens)) => Ok(tokens),
-                Err(err) => Err(err),
-                Ok(_) => Err(EmbeddingGeneratorError::InternalError(
-                    "unexpected embeddings result".into(),
-                )),
-            },
-            Err(_) => Err(EmbeddingGeneratorError::InternalError(
-                "channel closed unexpectedly".into(),
+    async fn tokenize_encode_text(
+        &self,
+        inputs: Vec<String>,
+        model: String,
+    ) -> Result<Vec<Tokens>, EmbeddingGeneratorError> {
+        match self
+            .request(model, inputs, vec![], ModelOperation::TokenizeEncodeText)
+            .await?
+        {
+            ModelResult::TokenizedEncodedText(tokenized) => Ok(tokenized),
+            _ => Err(EmbeddingGeneratorError::InternalError(
+                "unexpected embeddings result".into(),
             )),
         }
     }
@@ -248,22 +1048,30 @@ impl EmbeddingGenerator for SentenceTransformerModels {
         inputs: Vec<Vec<i64>>,
         model: String,
     ) -> Result<Vec<String>, EmbeddingGeneratorError> {
-        let (tx, rx) = oneshot::channel();
+        match self
+            .request(model, vec![], inputs, ModelOperation::TokenizeDecode)
+            .await?
+        {
+            ModelResult::TokenizedDecoded(texts) => Ok(texts),
+            _ => Err(EmbeddingGeneratorError::InternalError(
+                "unexpected embeddings result".into(),
+            )),
+        }
+    }
 
-        let _ = self
-            .sender
-            .send((model, vec![], inputs, ModelOperation::TokenizeDecode, tx));
-
-        match rx.await {
-            Ok(result) => match result {
-                Ok(ModelResult::TokenizedDecoded(texts)) => Ok(texts),
-                Err(err) => Err(err),
-                Ok(_) => Err(EmbeddingGeneratorError::InternalError(
-                    "unexpected embeddings result".into(),
-                )),
-            },
-            Err(_) => Err(EmbeddingGeneratorError::InternalError(
-                "channel closed unexpectedly".into(),
+    async fn rerank(
+        &self,
+        query: String,
+        passages: Vec<String>,
+        model: String,
+    ) -> Result<Vec<f32>, EmbeddingGeneratorError> {
+        match self
+            .request(model, passages, vec![], ModelOperation::Rerank(query))
+            .await?
+        {
+            ModelResult::Scores(scores) => Ok(scores),
+            _ => Err(EmbeddingGeneratorError::InternalError(
+                "unexpected embeddings result".into(),
             )),
         }
     }
@@ -274,9 +1082,247 @@ mod tests {
     use std::vec;
 
     use super::*;
+    use crate::test_util::TempDir;
     use server_config::DeviceKind;
     use server_config::EmbeddingModelKind::AllMiniLmL12V2;
 
+    /// Returns a request, and the receiver of its result, which must be kept for the request
+    /// to be run.
+    fn message(
+        model: &str,
+        texts: usize,
+        operation: ModelOperation,
+    ) -> (
+        Message,
+        oneshot::Receiver<Result<ModelResult, EmbeddingGeneratorError>>,
+    ) {
+        let (tx, rx) = oneshot::channel();
+        (
+            (
+                model.into(),
+                vec!["text".into(); texts],
+                vec![],
+                operation,
+                tx,
+            ),
+            rx,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_request_backpressure() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let handle = ModelHandle {
+            sender,
+            request_timeout: Duration::from_millis(10),
+            replicas: Arc::new(Replicas::new(vec![ModelStatus::Ready])),
+        };
+        let models = SentenceTransformerModels {
+            models: Arc::new(ModelSlots {
+                slots: Mutex::new(HashMap::from([(
+                    "busy".to_string(),
+                    ModelSlot {
+                        config: Default::default(),
+                        memory_mb: 0,
+                        handle: Some(Arc::new(handle)),
+                        draining: Vec::new(),
+                        last_used: Instant::now(),
+                    },
+                )])),
+                memory_budget_mb: None,
+            }),
+            dimensions: HashMap::new(),
+            max_sequence_lengths: HashMap::new(),
+            _staging: None,
+        };
+
+        // Nothing serves the queue, so the first request times out and fills it, until a
+        // replica takes it and drops it
+        let err = models
+            .generate_embeddings(vec!["text".into()], "busy".into())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EmbeddingGeneratorError::Unavailable(..)));
+        let err = models
+            .generate_embeddings(vec!["text".into()], "busy".into())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EmbeddingGeneratorError::Overloaded(_)));
+
+        drop(receiver);
+        let err = models
+            .generate_embeddings(vec!["text".into()], "busy".into())
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("no replica of the model is running"));
+    }
+
+    #[test]
+    fn test_unavailable_models() {
+        // The directory has enough to discover the dimensions, but no weights
+        let dir = TempDir::new("unavailable-models");
+        std::fs::write(dir.path().join("config.json"), r#"{"hidden_size": 384}"#).unwrap();
+        let model = server_config::EmbeddingModel {
+            model_kind: AllMiniLmL12V2,
+            device_kind: DeviceKind::Cpu,
+            path: Some(dir.path().to_string_lossy().into()),
+            ..Default::default()
+        };
+
+        let loading = ModelLoadingConfig::default();
+        assert!(SentenceTransformerModels::new(vec![model.clone()], false, &loading).is_err());
+        let models = SentenceTransformerModels::new(vec![model], true, &loading).unwrap();
+        let status = models.status("all-minilm-l12-v2".into()).unwrap();
+        assert!(matches!(status, ModelStatus::Failed(_)));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let dir = TempDir::new("memory-budget");
+        std::fs::write(dir.path().join("config.json"), r#"{"hidden_size": 384}"#).unwrap();
+        let model = |name: &str| server_config::EmbeddingModel {
+            model_kind: AllMiniLmL12V2,
+            name: Some(name.into()),
+            path: Some(dir.path().to_string_lossy().into()),
+            memory_mb: Some(100),
+            ..Default::default()
+        };
+        let models = vec![model("a"), model("b"), model("c")];
+        let mut loading = ModelLoadingConfig {
+            memory_budget_mb: Some(200),
+            ..Default::default()
+        };
+        // The models don't fit in the budget together, unless they're loaded lazily, and the
+        // size of each model must be known
+        assert!(SentenceTransformerModels::new(models.clone(), true, &loading).is_err());
+        loading.lazy = true;
+        let unknown = server_config::EmbeddingModel {
+            memory_mb: None,
+            ..model("d")
+        };
+        assert!(matches!(
+            SentenceTransformerModels::new(vec![unknown], true, &loading),
+            Err(EmbeddingGeneratorError::ConfigurationError(..))
+        ));
+        let models = SentenceTransformerModels::new(models, true, &loading).unwrap();
+        let resident = |models: &SentenceTransformerModels| {
+            let mut names: Vec<String> = ["a", "b", "c"]
+                .into_iter()
+                .filter(|&name| models.residency(name.into()).unwrap().resident)
+                .map(String::from)
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(models.status("a".into()).unwrap(), ModelStatus::Unloaded);
+        assert!(resident(&models).is_empty());
+
+        // Loading a model unloads the least recently used one once the budget is full
+        let mut started = Vec::new();
+        let mut acquire = |name: &str| {
+            let handle = models.models.acquire(name).unwrap();
+            started.push(Arc::downgrade(&handle.replicas));
+        };
+        for name in ["a", "b", "c"] {
+            acquire(name);
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(resident(&models), vec!["b", "c"]);
+        acquire("b");
+        acquire("a");
+        assert_eq!(resident(&models), vec!["a", "b"]);
+
+        assert!(models.unload_model("a".into()).unwrap());
+        assert!(!models.unload_model("a".into()).unwrap());
+        assert_eq!(resident(&models), vec!["b"]);
+        models.models.unload_idle(Duration::ZERO);
+        assert!(resident(&models).is_empty());
+
+        // Replicas that still have an unloaded model count until they exit
+        let replicas = Arc::new(Replicas::new(vec![ModelStatus::Ready]));
+        for name in ["b", "c"] {
+            let mut slots = models.models.lock();
+            let slot = slots.get_mut(name).unwrap();
+            slot.draining.push(Arc::downgrade(&replicas));
+        }
+        assert!(matches!(
+            models.models.acquire("a"),
+            Err(EmbeddingGeneratorError::Unavailable(..))
+        ));
+        drop(replicas);
+        assert!(models.models.acquire("a").is_ok());
+
+        // Unloaded replicas stop right away, rather than retrying to load their model
+        drop(models);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while started.iter().any(|replicas| replicas.strong_count() > 0) {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_collect_batch() {
+        let batching = BatchingConfig {
+            max_batch_size: 4,
+            max_wait_ms: 0,
+        };
+        let (sender, receiver) = mpsc::sync_channel(10);
+        let (tokenize, _tokenize_rx) = message("a", 1, ModelOperation::Tokenize);
+        let mut pending = VecDeque::from([tokenize]);
+        let mut results = Vec::new();
+        for (message, rx) in [
+            message("a", 2, ModelOperation::EncodeEmbeddings),
+            message("b", 1, ModelOperation::EncodeEmbeddings),
+            message("a", 2, ModelOperation::EncodeEmbeddings),
+            message("a", 1, ModelOperation::EncodeEmbeddings),
+        ] {
+            sender.send(message).unwrap();
+            results.push(rx);
+        }
+
+        let (first, _first_rx) = message("a", 1, ModelOperation::EncodeEmbeddings);
+        let batch = collect_batch(first, &receiver, &mut pending, &batching);
+        let sizes: Vec<usize> = batch.iter().map(|m| m.1.len()).collect();
+        assert_eq!(sizes, vec![1, 2]);
+        let pending: Vec<(String, usize)> = pending.into_iter().map(|m| (m.0, m.1.len())).collect();
+        assert_eq!(
+            pending,
+            vec![("a".into(), 1), ("b".into(), 1), ("a".into(), 2)]
+        );
+        // The last request is still queued, for the next batch
+        assert_eq!(receiver.try_recv().unwrap().1.len(), 1);
+    }
+
+    #[test]
+    fn test_abandoned_requests() {
+        let (sender, receiver) = mpsc::sync_channel(10);
+        let mut queue = ModelQueue {
+            receiver,
+            pending: VecDeque::new(),
+            batching: BatchingConfig {
+                max_batch_size: 4,
+                max_wait_ms: 0,
+            },
+        };
+        // The callers of the first request and of the one batched with it stopped waiting
+        let (abandoned, _) = message("a", 1, ModelOperation::Tokenize);
+        let (batched_abandoned, _) = message("a", 2, ModelOperation::EncodeEmbeddings);
+        let (embed, _embed_rx) = message("a", 1, ModelOperation::EncodeEmbeddings);
+        let (tokenize, _tokenize_rx) = message("a", 3, ModelOperation::Tokenize);
+        for message in [abandoned, embed, batched_abandoned, tokenize] {
+            sender.send(message).unwrap();
+        }
+
+        let sizes = |batch: Vec<Message>| batch.iter().map(|m| m.1.len()).collect::<Vec<_>>();
+        assert_eq!(sizes(queue.next().unwrap()), vec![1]);
+        assert_eq!(sizes(queue.next().unwrap()), vec![3]);
+        drop(sender);
+        assert!(queue.next().is_none());
+    }
+
     #[tokio::test]
     async fn test_generate_embeddings_all_mini_lm_l12v2() {
         let inputs = vec![
@@ -284,12 +1330,16 @@ mod tests {
             "Hello, NBA!".into(),
             "Hello, NFL!".into(),
         ];
-        let embedding_generator =
-            SentenceTransformerModels::new(vec![server_config::EmbeddingModel {
+        let embedding_generator = SentenceTransformerModels::new(
+            vec![server_config::EmbeddingModel {
                 model_kind: AllMiniLmL12V2,
                 device_kind: DeviceKind::Cpu,
-            }])
-            .unwrap();
+                ..Default::default()
+            }],
+            false,
+            &ModelLoadingConfig::default(),
+        )
+        .unwrap();
         let embeddings = embedding_generator
             .generate_embeddings(inputs, "all-minilm-l12-v2".into())
             .await
@@ -301,12 +1351,16 @@ mod tests {
     #[tokio::test]
     async fn test_tokenization() {
         let inputs = vec!["hello world so long that it doesn't fit my mind ".repeat(10)];
-        let embedding_generator =
-            SentenceTransformerModels::new(vec![server_config::EmbeddingModel {
+        let embedding_generator = SentenceTransformerModels::new(
+            vec![server_config::EmbeddingModel {
                 model_kind: AllMiniLmL12V2,
                 device_kind: DeviceKind::Cpu,
-            }])
-            .unwrap();
+                ..Default::default()
+            }],
+            false,
+            &ModelLoadingConfig::default(),
+        )
+        .unwrap();
         let tokenized_text = embedding_generator
             .tokenize_text(inputs, "all-minilm-l12-v2".into())
             .await
@@ -319,21 +1373,62 @@ mod tests {
     async fn test_tokenization_encode_decode() {
         let model: String = "all-minilm-l12-v2".into();
         let inputs = vec!["embiid is the mvp".into()];
-        let embedding_generator =
-            SentenceTransformerModels::new(vec![server_config::EmbeddingModel {
+        let embedding_generator = SentenceTransformerModels::new(
+            vec![server_config::EmbeddingModel {
                 model_kind: AllMiniLmL12V2,
                 device_kind: DeviceKind::Cpu,
-            }])
-            .unwrap();
+                ..Default::default()
+            }],
+            false,
+            &ModelLoadingConfig::default(),
+        )
+        .unwrap();
         let tokens = embedding_generator
             .tokenize_encode(inputs.clone(), model.clone())
             .await
             .unwrap();
 
+        // The ids come with the text of each token, [CLS] and [SEP] included
+        let tokenized = embedding_generator
+            .tokenize_encode_text(inputs.clone(), model.clone())
+            .await
+            .unwrap();
+        assert_eq!(tokenized[0].ids, tokens[0]);
+        assert_eq!(tokenized[0].texts.len(), tokens[0].len());
+        assert_eq!(tokenized[0].texts.first().unwrap(), "[CLS]");
+        assert_eq!(tokenized[0].texts.last().unwrap(), "[SEP]");
+
         let tokenized_text = embedding_generator
             .tokenize_decode(tokens, model.clone())
             .await
             .unwrap();
         assert_eq!(inputs, tokenized_text);
     }
+
+    #[tokio::test]
+    #[ignore = "needs the model converted to rust-bert weights in models/ms-marco-MiniLM-L-6-v2"]
+    async fn test_rerank_ms_marco_mini_lm_l6v2() {
+        let model: String = "ms-marco-minilm-l6-v2".into();
+        let passages = vec![
+            "The capital of France is Paris.".into(),
+            "Embiid was the MVP of the 2023 NBA season.".into(),
+        ];
+        let embedding_generator = SentenceTransformerModels::new(
+            vec![server_config::EmbeddingModel {
+                model_kind: server_config::EmbeddingModelKind::MsMarcoMiniLmL6V2,
+                device_kind: DeviceKind::Cpu,
+                path: Some("models/ms-marco-MiniLM-L-6-v2".into()),
+                ..Default::default()
+            }],
+            false,
+            &ModelLoadingConfig::default(),
+        )
+        .unwrap();
+        let scores = embedding_generator
+            .rerank("who was the nba mvp?".into(), passages, model)
+            .await
+            .unwrap();
+        assert_eq!(scores.len(), 2);
+        assert!(scores[1] > scores[0]);
+    }
 }
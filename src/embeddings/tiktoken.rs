@@ -0,0 +1,116 @@
+use super::{EmbeddingGeneratorError, Tokens};
+
+/// A byte pair encoding tokenizer of OpenAI models, `cl100k_base` for embedding models.
+pub struct TiktokenTokenizer {
+    bpe: tiktoken_rs::CoreBPE,
+}
+
+impl TiktokenTokenizer {
+    pub fn new() -> Result<Self, EmbeddingGeneratorError> {
+        Self::from_encoding("cl100k_base")
+    }
+
+    /// Creates the tokenizer of a tiktoken encoding, by its name.
+    pub fn from_encoding(encoding: &str) -> Result<Self, EmbeddingGeneratorError> {
+        let bpe = match encoding {
+            "o200k_base" => tiktoken_rs::o200k_base(),
+            "cl100k_base" => tiktoken_rs::cl100k_base(),
+            "p50k_base" => tiktoken_rs::p50k_base(),
+            "p50k_edit" => tiktoken_rs::p50k_edit(),
+            "r50k_base" | "gpt2" => tiktoken_rs::r50k_base(),
+            _ => {
+                return Err(EmbeddingGeneratorError::ModelLoadingError(format!(
+                    "unknown tiktoken encoding `{}`",
+                    encoding
+                )))
+            }
+        }
+        .map_err(|e| EmbeddingGeneratorError::ModelLoadingError(e.to_string()))?;
+        Ok(Self { bpe })
+    }
+
+    pub fn tokenize_text(
+        &self,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<String>>, EmbeddingGeneratorError> {
+        let mut results = Vec::new();
+        for input in &inputs {
+            let tokens = self
+                .bpe
+                .split_by_token(input, true)
+                .map_err(|e| EmbeddingGeneratorError::ModelError(e.to_string()))?;
+            results.push(tokens);
+        }
+        Ok(results)
+    }
+
+    pub fn tokenize_encode(&self, inputs: Vec<String>) -> Vec<Vec<i64>> {
+        inputs
+            .iter()
+            .map(|input| {
+                self.bpe
+                    .encode_ordinary(input)
+                    .into_iter()
+                    .map(|x| x as i64)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Returns the ids of the tokens of each input with their texts. Tokens that end in the
+    /// middle of a character have the replacement character in their text.
+    pub fn tokenize_encode_text(&self, inputs: Vec<String>) -> Vec<Tokens> {
+        inputs
+            .iter()
+            .map(|input| {
+                let ids = self.bpe.encode_ordinary(input);
+                Tokens {
+                    texts: self
+                        .bpe
+                        ._decode_native_and_split(ids.clone())
+                        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                        .collect(),
+                    ids: ids.into_iter().map(|x| x as i64).collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the number of tokens of a text.
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.bpe.encode_ordinary(text).len()
+    }
+
+    /// Returns the text cut to its first `max_tokens` tokens, or `None` if it has no more
+    /// tokens than that. Tokens that end in the middle of a character are dropped as well.
+    pub fn truncate(&self, text: &str, max_tokens: usize) -> Option<String> {
+        let mut tokens = self.bpe.encode_ordinary(text);
+        if tokens.len() <= max_tokens {
+            return None;
+        }
+        tokens.truncate(max_tokens);
+        while !tokens.is_empty() {
+            if let Ok(truncated) = self.bpe.decode(tokens.clone()) {
+                return Some(truncated);
+            }
+            tokens.pop();
+        }
+        Some(String::new())
+    }
+
+    pub fn tokenize_decode(
+        &self,
+        inputs: Vec<Vec<i64>>,
+    ) -> Result<Vec<String>, EmbeddingGeneratorError> {
+        let mut result: Vec<String> = Vec::new();
+        for input in inputs {
+            let input_usize = input.into_iter().map(|x| x as usize).collect();
+            let text: String = self
+                .bpe
+                .decode(input_usize)
+                .map_err(|e| EmbeddingGeneratorError::InternalError(e.to_string()))?;
+            result.push(text);
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,239 @@
+use tokenizers::Tokenizer;
+
+use super::server_config::TokenizerConfig;
+use super::tiktoken::TiktokenTokenizer;
+use super::{EmbeddingGeneratorError, Tokens};
+
+/// A tokenizer loaded without its model, from the Hugging Face `tokenizer.json` of the model
+/// or a tiktoken encoding. Texts of a model with a standalone tokenizer are tokenized by it,
+/// so splitting and counting them never waits for the model, or needs it in memory. Texts are
+/// encoded whole and with the special tokens the model adds, such as `[CLS]` and `[SEP]`, so
+/// that they have as many tokens as the model counts.
+pub enum StandaloneTokenizer {
+    HuggingFace(Box<Tokenizer>),
+    Tiktoken(TiktokenTokenizer),
+}
+
+fn tokenizer_error(e: impl ToString) -> EmbeddingGeneratorError {
+    EmbeddingGeneratorError::ModelError(e.to_string())
+}
+
+impl StandaloneTokenizer {
+    pub fn new(config: &TokenizerConfig) -> Result<Self, EmbeddingGeneratorError> {
+        match config {
+            TokenizerConfig::Path(path) => {
+                let loading_error =
+                    |e| EmbeddingGeneratorError::ModelLoadingError(format!("{}: {}", path, e));
+                let mut tokenizer = Tokenizer::from_file(path).map_err(loading_error)?;
+                // The truncation and padding of the model would hide how long texts are
+                tokenizer.with_truncation(None).map_err(loading_error)?;
+                tokenizer.with_padding(None);
+                Ok(Self::HuggingFace(Box::new(tokenizer)))
+            }
+            TokenizerConfig::Tiktoken(encoding) => {
+                Ok(Self::Tiktoken(TiktokenTokenizer::from_encoding(encoding)?))
+            }
+        }
+    }
+
+    pub fn tokenize_text(
+        &self,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<String>>, EmbeddingGeneratorError> {
+        match self {
+            Self::HuggingFace(tokenizer) => inputs
+                .into_iter()
+                .map(|input| {
+                    let encoding = tokenizer.encode(input, false).map_err(tokenizer_error)?;
+                    Ok(encoding.get_tokens().to_vec())
+                })
+                .collect(),
+            Self::Tiktoken(tokenizer) => tokenizer.tokenize_text(inputs),
+        }
+    }
+
+    pub fn tokenize_encode(
+        &self,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<i64>>, EmbeddingGeneratorError> {
+        match self {
+            Self::HuggingFace(tokenizer) => inputs
+                .into_iter()
+                .map(|input| {
+                    let encoding = tokenizer.encode(input, true).map_err(tokenizer_error)?;
+                    Ok(encoding.get_ids().iter().map(|&id| id as i64).collect())
+                })
+                .collect(),
+            Self::Tiktoken(tokenizer) => Ok(tokenizer.tokenize_encode(inputs)),
+        }
+    }
+
+    pub fn tokenize_encode_text(
+        &self,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Tokens>, EmbeddingGeneratorError> {
+        match self {
+            Self::HuggingFace(tokenizer) => inputs
+                .into_iter()
+                .map(|input| {
+                    let encoding = tokenizer.encode(input, true).map_err(tokenizer_error)?;
+                    Ok(Tokens {
+                        ids: encoding.get_ids().iter().map(|&id| id as i64).collect(),
+                        texts: encoding.get_tokens().to_vec(),
+                    })
+                })
+                .collect(),
+            Self::Tiktoken(tokenizer) => Ok(tokenizer.tokenize_encode_text(inputs)),
+        }
+    }
+
+    pub fn tokenize_decode(
+        &self,
+        inputs: Vec<Vec<i64>>,
+    ) -> Result<Vec<String>, EmbeddingGeneratorError> {
+        match self {
+            Self::HuggingFace(tokenizer) => inputs
+                .into_iter()
+                .map(|tokens| {
+                    let ids: Vec<u32> = tokens.into_iter().map(|id| id as u32).collect();
+                    tokenizer.decode(&ids, true).map_err(tokenizer_error)
+                })
+                .collect(),
+            Self::Tiktoken(tokenizer) => tokenizer.tokenize_decode(inputs),
+        }
+    }
+
+    pub fn count_tokens(&self, inputs: Vec<String>) -> Result<Vec<usize>, EmbeddingGeneratorError> {
+        match self {
+            Self::HuggingFace(_) => {
+                Ok(self.tokenize_encode(inputs)?.iter().map(Vec::len).collect())
+            }
+            Self::Tiktoken(tokenizer) => Ok(inputs
+                .iter()
+                .map(|input| tokenizer.count_tokens(input))
+                .collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tokenizers::processors::template::TemplateProcessing;
+    use tokenizers::{AddedToken, PaddingParams, PaddingStrategy, TruncationParams};
+
+    #[test]
+    fn test_hugging_face_tokenizer() {
+        let vocab = HashMap::from([
+            ("[UNK]".to_string(), 0),
+            ("embiid".to_string(), 1),
+            ("is".to_string(), 2),
+            ("the".to_string(), 3),
+            ("mvp".to_string(), 4),
+        ]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".into())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Whitespace {});
+        let dir = TempDir::new("tokenizer");
+        let path = dir.path().join("tokenizer.json");
+        tokenizer.save(&path, false).unwrap();
+
+        let tokenizer =
+            StandaloneTokenizer::new(&TokenizerConfig::Path(path.display().to_string())).unwrap();
+        let inputs = vec!["embiid is the mvp".to_string(), "the goat".to_string()];
+        let tokens = tokenizer.tokenize_encode(inputs.clone()).unwrap();
+        assert_eq!(tokens, vec![vec![1, 2, 3, 4], vec![3, 0]]);
+        assert_eq!(tokenizer.count_tokens(inputs.clone()).unwrap(), vec![4, 2]);
+        assert_eq!(
+            tokenizer.tokenize_text(inputs.clone()).unwrap()[1],
+            vec!["the", "[UNK]"]
+        );
+        let tokenized = tokenizer.tokenize_encode_text(inputs).unwrap();
+        assert_eq!(tokenized[1].ids, tokens[1]);
+        assert_eq!(tokenized[1].texts, vec!["the", "[UNK]"]);
+        assert_eq!(
+            tokenizer.tokenize_decode(vec![vec![1, 4]]).unwrap(),
+            vec!["embiid mvp"]
+        );
+    }
+
+    #[test]
+    fn test_truncating_tokenizer() {
+        let vocab = HashMap::from([
+            ("[UNK]".to_string(), 0),
+            ("embiid".to_string(), 1),
+            ("is".to_string(), 2),
+            ("the".to_string(), 3),
+            ("mvp".to_string(), 4),
+            ("[CLS]".to_string(), 5),
+            ("[SEP]".to_string(), 6),
+        ]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".into())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Whitespace {});
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("[CLS]", true),
+            AddedToken::from("[SEP]", true),
+        ]);
+        tokenizer.with_post_processor(
+            TemplateProcessing::builder()
+                .try_single("[CLS] $A [SEP]")
+                .unwrap()
+                .special_tokens(vec![("[CLS]", 5), ("[SEP]", 6)])
+                .build()
+                .unwrap(),
+        );
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: 4,
+                ..Default::default()
+            }))
+            .unwrap();
+        tokenizer.with_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::Fixed(12),
+            ..Default::default()
+        }));
+        let dir = TempDir::new("tokenizer");
+        let path = dir.path().join("tokenizer.json");
+        tokenizer.save(&path, false).unwrap();
+
+        // Texts are neither truncated nor padded, and have the special tokens of the model
+        let tokenizer =
+            StandaloneTokenizer::new(&TokenizerConfig::Path(path.display().to_string())).unwrap();
+        let inputs = vec!["embiid is the mvp the mvp".to_string()];
+        let tokens = tokenizer.tokenize_encode(inputs.clone()).unwrap();
+        assert_eq!(tokens, vec![vec![5, 1, 2, 3, 4, 3, 4, 6]]);
+        assert_eq!(tokenizer.count_tokens(inputs.clone()).unwrap(), vec![8]);
+        let tokenized = tokenizer.tokenize_encode_text(inputs.clone()).unwrap();
+        assert_eq!(tokenized[0].ids, tokens[0]);
+        assert_eq!(tokenized[0].texts[0], "[CLS]");
+        assert_eq!(tokenizer.tokenize_text(inputs.clone()).unwrap()[0].len(), 6);
+        assert_eq!(tokenizer.tokenize_decode(tokens).unwrap(), inputs);
+    }
+
+    #[test]
+    fn test_tiktoken_tokenizer() {
+        let tokenizer =
+            StandaloneTokenizer::new(&TokenizerConfig::Tiktoken("cl100k_base".into())).unwrap();
+        let inputs = vec!["hello world".to_string()];
+        let tokens = tokenizer.tokenize_encode(inputs.clone()).unwrap();
+        assert_eq!(tokenizer.count_tokens(inputs.clone()).unwrap(), vec![2]);
+        let tokenized = tokenizer.tokenize_encode_text(inputs.clone()).unwrap();
+        assert_eq!(tokenized[0].ids, tokens[0]);
+        assert_eq!(tokenized[0].texts, vec!["hello", " world"]);
+        assert_eq!(tokenizer.tokenize_decode(tokens).unwrap(), inputs);
+        assert!(StandaloneTokenizer::new(&TokenizerConfig::Tiktoken("nope".into())).is_err());
+    }
+}
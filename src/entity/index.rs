@@ -13,6 +13,7 @@ pub struct Model {
     pub vector_db: String,
     pub vector_db_params: Option<String>,
     pub unique_params: Option<String>,
+    pub chunking_params: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -2,4 +2,6 @@
 
 pub mod prelude;
 
+pub mod embedding_cache;
 pub mod index;
+pub mod parent_chunk;
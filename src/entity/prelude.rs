@@ -1,3 +1,5 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.3
 
+pub use super::embedding_cache::Entity as EmbeddingCache;
 pub use super::index::Entity as Index;
+pub use super::parent_chunk::Entity as ParentChunk;
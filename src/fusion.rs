@@ -0,0 +1,104 @@
+use std::{collections::HashMap, hash::Hash};
+
+/// Constant of reciprocal rank fusion that dampens the weight of the top ranks.
+pub const RRF_K: f32 = 60.0;
+
+/// An item of one of the ranked lists to be fused.
+#[derive(Debug, Clone)]
+pub struct RankedItem<K, T> {
+    /// Items with the same key in different lists are considered the same item.
+    pub key: K,
+    pub item: T,
+    /// Similarity to the query, comparable across lists.
+    pub similarity: f32,
+}
+
+/// An item of the fused list.
+#[derive(Debug, Clone)]
+pub struct FusedItem<T> {
+    pub item: T,
+    /// Reciprocal rank fusion score, summed across the lists the item appears in.
+    pub score: f32,
+    /// Highest similarity of the item across the lists it appears in.
+    pub similarity: f32,
+}
+
+/// Merges lists ranked by relevance with reciprocal rank fusion, and returns the top `k`.
+///
+/// Each item scores `1 / (RRF_K + rank)` in every list it appears in. When an item appears
+/// in several lists, the copy with the highest similarity is kept. Items with the same fused
+/// score, such as the top hits of lists that do not overlap, are ordered by similarity.
+pub fn reciprocal_rank_fusion<K: Eq + Hash, T>(
+    lists: Vec<Vec<RankedItem<K, T>>>,
+    k: usize,
+) -> Vec<FusedItem<T>> {
+    let mut fused: Vec<FusedItem<T>> = Vec::new();
+    let mut positions: HashMap<K, usize> = HashMap::new();
+    for list in lists {
+        for (rank, ranked) in list.into_iter().enumerate() {
+            let score = 1.0 / (RRF_K + (rank + 1) as f32);
+            match positions.get(&ranked.key) {
+                Some(&pos) => {
+                    let existing = &mut fused[pos];
+                    existing.score += score;
+                    if ranked.similarity > existing.similarity {
+                        existing.item = ranked.item;
+                        existing.similarity = ranked.similarity;
+                    }
+                }
+                None => {
+                    positions.insert(ranked.key, fused.len());
+                    fused.push(FusedItem {
+                        item: ranked.item,
+                        score,
+                        similarity: ranked.similarity,
+                    });
+                }
+            }
+        }
+    }
+    fused.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(b.similarity.total_cmp(&a.similarity))
+    });
+    fused.truncate(k);
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked(key: &str, similarity: f32) -> RankedItem<String, String> {
+        RankedItem {
+            key: key.into(),
+            item: key.into(),
+            similarity,
+        }
+    }
+
+    #[test]
+    fn test_disjoint_lists_interleave_by_similarity() {
+        let lists = vec![
+            vec![ranked("docs-1", 0.7), ranked("docs-2", 0.6)],
+            vec![ranked("tickets-1", 0.9), ranked("tickets-2", 0.5)],
+        ];
+        let fused = reciprocal_rank_fusion(lists, 3);
+        let keys: Vec<&str> = fused.iter().map(|f| f.item.as_str()).collect();
+        assert_eq!(keys, vec!["tickets-1", "docs-1", "docs-2"]);
+    }
+
+    #[test]
+    fn test_items_in_several_lists_are_merged() {
+        let lists = vec![
+            vec![ranked("a", 0.5), ranked("b", 0.4)],
+            vec![ranked("c", 0.9), ranked("b", 0.8)],
+        ];
+        let fused = reciprocal_rank_fusion(lists, 10);
+        assert_eq!(fused.len(), 3);
+        assert_eq!(fused[0].item, "b");
+        assert_eq!(fused[0].similarity, 0.8);
+        assert_eq!(fused[0].score, 2.0 / (RRF_K + 2.0));
+    }
+}
@@ -1,15 +1,22 @@
-use std::{collections::HashMap, str::FromStr, vec};
+use std::{collections::HashMap, str::FromStr, sync::Arc, vec};
 
 use anyhow::Result;
+use chrono::Utc;
+use futures::future::try_join_all;
+use md5::{Digest, Md5};
 use sea_orm::DatabaseConnection;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::info;
 
 use crate::{
+    boosting::{self, ScoreBoost, ScoreComponents},
+    embeddings::post_process,
+    fusion::{self, RankedItem},
     persistence::{Respository, RespositoryError},
     text_splitters::{self, TextSplitterKind, TextSplitterTS},
-    vectordbs, CreateIndexParams, EmbeddingGeneratorError, EmbeddingGeneratorTS, SearchResult,
-    VectorDBTS, VectorDbError, VectorIndexConfig,
+    vectordbs, CreateIndexParams, EmbeddingGeneratorError, EmbeddingGeneratorTS, EmbeddingRole,
+    MetricKind, PostProcessingConfig, SearchResult, VectorDBTS, VectorDbError, VectorIndexConfig,
 };
 
 #[async_trait::async_trait]
@@ -25,6 +32,114 @@ pub struct Text {
     pub metadata: HashMap<String, String>,
 }
 
+/// Maximum number of tokens in the chunks that are embedded, unless the index says otherwise.
+const DEFAULT_CHUNK_SIZE: u64 = 1000;
+
+/// The parents that the embedded chunks of a document are grouped into.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParentChunking {
+    /// Parents are chunks of at most this many tokens.
+    Tokens(u64),
+    /// The whole document is the parent of all of its chunks.
+    Document,
+}
+
+/// How the documents added to an index are chunked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkingParams {
+    /// Maximum number of tokens in the chunks that are embedded and searched.
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u64,
+    /// Larger chunks that are returned in place of the embedded chunks they contain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<ParentChunking>,
+}
+
+fn default_chunk_size() -> u64 {
+    DEFAULT_CHUNK_SIZE
+}
+
+impl ChunkingParams {
+    /// Checks that the chunks are not empty and fit in their parents.
+    pub fn validate(&self) -> Result<(), IndexError> {
+        if self.chunk_size == 0 {
+            return Err(IndexError::InvalidChunking(
+                "chunk_size must be greater than 0".into(),
+            ));
+        }
+        if let Some(ParentChunking::Tokens(parent_size)) = self.parent {
+            if parent_size < self.chunk_size {
+                return Err(IndexError::InvalidChunking(format!(
+                    "parent chunks of {} tokens are smaller than the chunk_size of {}",
+                    parent_size, self.chunk_size
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for ChunkingParams {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            parent: None,
+        }
+    }
+}
+
+/// Re-ranking of search results with a cross-encoder model.
+#[derive(Debug, Clone)]
+pub struct RerankOptions {
+    /// Name of the cross-encoder model.
+    pub model: String,
+    /// Number of candidates fetched from the vector database and scored by the model.
+    pub candidates: u64,
+}
+
+/// Options that control how an index is searched.
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// Number of chunks of the same document to return before and after each hit.
+    pub context_window: u64,
+    /// Re-ranks the results with a cross-encoder, if set.
+    pub rerank: Option<RerankOptions>,
+    /// Adjustments of the similarity score, based on the metadata of the results.
+    pub boosts: Vec<ScoreBoost>,
+}
+
+/// Number of candidates fetched for each requested result when boosting scores.
+const BOOST_CANDIDATES_PER_RESULT: u64 = 4;
+
+/// A search result along with the chunks surrounding it in its document.
+#[derive(Debug, Clone, Default)]
+pub struct SearchHit {
+    pub result: SearchResult,
+    /// Chunks preceding the hit, in document order.
+    pub context_before: Vec<SearchResult>,
+    /// Chunks following the hit, in document order.
+    pub context_after: Vec<SearchResult>,
+    /// Text of the parent chunk of the hit, if the index stores parents.
+    pub parent: Option<String>,
+    /// Relevance score of the hit from the cross-encoder, if the results were re-ranked.
+    pub rerank_score: Option<f32>,
+    /// Final score of the hit, the similarity between 0 and 1 multiplied by the boosts.
+    pub score: f32,
+    /// Components of the final score, if the search boosted scores.
+    pub score_components: Option<ScoreComponents>,
+}
+
+/// A search hit from one of the indexes of a federated search.
+#[derive(Debug, Clone)]
+pub struct FederatedSearchHit {
+    /// Name of the index the hit comes from.
+    pub index: String,
+    pub hit: SearchHit,
+    /// Reciprocal rank fusion score of the hit across the indexes.
+    pub score: f32,
+}
+
 #[derive(Error, Debug)]
 pub enum IndexError {
     #[error(transparent)]
@@ -44,12 +159,15 @@ pub enum IndexError {
 
     #[error("logic error: `{0}`")]
     LogicError(String),
+
+    #[error("invalid chunking: `{0}`")]
+    InvalidChunking(String),
 }
 
 pub struct IndexManager {
     vectordb: VectorDBTS,
     embedding_router: EmbeddingGeneratorTS,
-    repository: Respository,
+    repository: Arc<Respository>,
 }
 
 impl IndexManager {
@@ -80,7 +198,7 @@ impl IndexManager {
         Ok(Some(IndexManager {
             vectordb,
             embedding_router,
-            repository,
+            repository: Arc::new(repository),
         }))
     }
 
@@ -99,7 +217,9 @@ impl IndexManager {
         vectordb_params: CreateIndexParams,
         embedding_model: String,
         text_splitter: TextSplitterKind,
+        chunking_params: ChunkingParams,
     ) -> Result<(), IndexError> {
+        chunking_params.validate()?;
         // This is to ensure the user is not requesting to create an index
         // with a text splitter that is not supported
         let _ = text_splitters::get_splitter(
@@ -114,6 +234,7 @@ impl IndexManager {
                 vectordb_params,
                 self.vectordb.clone(),
                 text_splitter.to_string(),
+                chunking_params,
             )
             .await?;
         Ok(())
@@ -125,6 +246,17 @@ impl IndexManager {
         if let Some(params) = index_entity.unique_params {
             unique_params = serde_json::from_str(&params)?;
         }
+        let mut chunking_params = ChunkingParams::default();
+        if let Some(params) = index_entity.chunking_params {
+            chunking_params = serde_json::from_str(&params)?;
+        }
+        let mut metric = None;
+        let mut post_processing = None;
+        if let Some(params) = index_entity.vector_db_params {
+            let vectordb_params: CreateIndexParams = serde_json::from_str(&params)?;
+            metric.replace(vectordb_params.metric);
+            post_processing = vectordb_params.post_processing;
+        }
         let splitter_kind = TextSplitterKind::from_str(&index_entity.text_splitter)
             .map_err(|e| IndexError::LogicError(e.to_string()))?;
         let splitter = text_splitters::get_splitter(
@@ -139,10 +271,65 @@ impl IndexManager {
             index_entity.embedding_model,
             splitter,
             unique_params,
+            self.repository.clone(),
+            chunking_params,
+            metric,
+            post_processing,
         )
         .await?;
         Ok(index)
     }
+
+    /// Searches several indexes concurrently and merges their results with
+    /// reciprocal rank fusion. Indexes can use different metrics and embedding models.
+    pub async fn federated_search(
+        &self,
+        index_names: Vec<String>,
+        query: String,
+        k: u64,
+        options: SearchOptions,
+    ) -> Result<Vec<FederatedSearchHit>, IndexError> {
+        let indexes = try_join_all(index_names.into_iter().map(|name| async move {
+            self.load(name.clone())
+                .await?
+                .ok_or(IndexError::Persistence(RespositoryError::IndexNotFound(
+                    name,
+                )))
+        }))
+        .await?;
+        let results = try_join_all(
+            indexes
+                .iter()
+                .map(|index| index.search(query.clone(), k, options.clone())),
+        )
+        .await?;
+        let ranked_lists = indexes
+            .iter()
+            .zip(results)
+            .map(|(index, hits)| {
+                hits.into_iter()
+                    .map(|hit| RankedItem {
+                        key: (
+                            index.name().to_string(),
+                            hit.result.document_id.clone(),
+                            hit.result.chunk,
+                        ),
+                        similarity: hit.score,
+                        item: (index.name().to_string(), hit),
+                    })
+                    .collect()
+            })
+            .collect();
+        let fused = fusion::reciprocal_rank_fusion(ranked_lists, k as usize);
+        Ok(fused
+            .into_iter()
+            .map(|f| FederatedSearchHit {
+                index: f.item.0,
+                hit: f.item.1,
+                score: f.score,
+            })
+            .collect())
+    }
 }
 pub struct Index {
     name: String,
@@ -151,9 +338,14 @@ pub struct Index {
     embedding_model: String,
     text_splitter: TextSplitterTS,
     hash_on: Vec<String>,
+    repository: Arc<Respository>,
+    chunking_params: ChunkingParams,
+    metric: Option<MetricKind>,
+    post_processing: Option<PostProcessingConfig>,
 }
 
 impl Index {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         name: String,
         vectordb: VectorDBTS,
@@ -161,6 +353,10 @@ impl Index {
         embedding_model: String,
         text_splitter: TextSplitterTS,
         hash_on: Vec<String>,
+        repository: Arc<Respository>,
+        chunking_params: ChunkingParams,
+        metric: Option<MetricKind>,
+        post_processing: Option<PostProcessingConfig>,
     ) -> Result<Option<Index>, IndexError> {
         Ok(Some(Self {
             name,
@@ -169,23 +365,87 @@ impl Index {
             embedding_model,
             text_splitter,
             hash_on,
+            repository,
+            chunking_params,
+            metric,
+            post_processing,
         }))
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Maps a score returned by a search of this index to a similarity between 0 and 1.
+    /// Indexes created before their metric was recorded keep the raw score.
+    pub fn normalize_score(&self, score: f32) -> f32 {
+        match &self.metric {
+            Some(metric) => metric.normalize_score(score),
+            None => score,
+        }
+    }
+
     pub async fn add_texts(&self, texts: Vec<Text>) -> Result<(), IndexError> {
+        let chunk_size = self.chunking_params.chunk_size;
         for mut text in texts {
             let mut splitted_texts = Vec::new();
+            // The index in `parent_texts` of the parent of each chunk, if any
+            let mut chunk_parents = Vec::new();
+            let mut parent_texts = Vec::new();
 
             for doc in text.texts {
-                let s_text = self.text_splitter.split(&doc, 1000, 0).await?;
-                splitted_texts.extend(s_text);
+                let doc_parents = match &self.chunking_params.parent {
+                    None => {
+                        let s_text = self.text_splitter.split(&doc, chunk_size, 0).await?;
+                        splitted_texts.extend(s_text);
+                        continue;
+                    }
+                    Some(ParentChunking::Tokens(parent_size)) => {
+                        self.text_splitter.split(&doc, *parent_size, 0).await?
+                    }
+                    Some(ParentChunking::Document) => vec![doc],
+                };
+                // The children of each parent are split again at the chunk size,
+                // and only the children are embedded.
+                for parent_text in doc_parents {
+                    let s_text = self
+                        .text_splitter
+                        .split(&parent_text, chunk_size, 0)
+                        .await?;
+                    chunk_parents.extend(vec![parent_texts.len(); s_text.len()]);
+                    splitted_texts.extend(s_text);
+                    parent_texts.push(parent_text);
+                }
             }
             text.texts = splitted_texts;
-
-            let embeddings = self
+            // Parents belong to the document they're split from, so that the ones of its
+            // previous version can be deleted when it's added again
+            let document_id = vectordbs::document_id(&text.texts, &text.metadata, &self.hash_on);
+            let parents: Vec<(String, String)> = parent_texts
+                .into_iter()
+                .map(|parent_text| {
+                    (
+                        self.parent_chunk_id(&document_id, &parent_text),
+                        parent_text,
+                    )
+                })
+                .collect();
+            let parent_ids = chunk_parents
+                .into_iter()
+                .map(|i| Some(parents[i].0.clone()))
+                .collect();
+
+            let mut embeddings = self
                 .embedding_generator
-                .generate_embeddings(text.texts.clone(), self.embedding_model.clone())
+                .generate_role_embeddings(
+                    text.texts.clone(),
+                    self.embedding_model.clone(),
+                    EmbeddingRole::Document,
+                )
                 .await?;
+            if let Some(config) = &self.post_processing {
+                post_process(config, &mut embeddings);
+            }
 
             self.vectordb
                 .add_embedding(
@@ -194,32 +454,184 @@ impl Index {
                     text.texts,
                     text.metadata,
                     self.hash_on.clone(),
+                    parent_ids,
                 )
                 .await?;
+            // Parents are only stored once their children can be searched, and the ones of the
+            // previous version of the document are only deleted once its points are
+            self.repository
+                .add_parent_chunks(&self.name, &document_id, parents)
+                .await?;
         }
         Ok(())
     }
 
-    pub async fn search(&self, query: String, k: u64) -> Result<Vec<SearchResult>, IndexError> {
-        let query_embedding = self
+    fn parent_chunk_id(&self, document_id: &str, text: &str) -> String {
+        let mut hasher = Md5::new();
+        hasher.update(&self.name);
+        hasher.update(document_id);
+        hasher.update(text);
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub async fn search(
+        &self,
+        query: String,
+        k: u64,
+        options: SearchOptions,
+    ) -> Result<Vec<SearchHit>, IndexError> {
+        let mut query_embeddings = self
             .embedding_generator
-            .generate_embeddings(vec![query], self.embedding_model.clone())
-            .await?
-            .get(0)
-            .unwrap()
-            .to_owned();
+            .generate_role_embeddings(
+                vec![query.clone()],
+                self.embedding_model.clone(),
+                EmbeddingRole::Query,
+            )
+            .await?;
+        if let Some(config) = &self.post_processing {
+            post_process(config, &mut query_embeddings);
+        }
+        let query_embedding = query_embeddings.get(0).unwrap().to_owned();
 
+        // Re-ranking and boosting over-fetch candidates, and keep the top k after scoring them
+        let mut num_candidates = k;
+        if let Some(rerank) = &options.rerank {
+            num_candidates = num_candidates.max(rerank.candidates);
+        }
+        if !options.boosts.is_empty() {
+            num_candidates = num_candidates.max(k.saturating_mul(BOOST_CANDIDATES_PER_RESULT));
+        }
         let results = self
             .vectordb
-            .search(self.name.clone(), query_embedding, k)
+            .search(self.name.clone(), query_embedding, num_candidates)
             .await?;
-        Ok(results)
+        let rerank_scores = match &options.rerank {
+            Some(rerank) => self
+                .rerank(query, &results, &rerank.model)
+                .await?
+                .into_iter()
+                .map(Some)
+                .collect(),
+            None => vec![None; results.len()],
+        };
+
+        let now = Utc::now();
+        let mut scored: Vec<(SearchResult, Option<f32>, f32, ScoreComponents)> = results
+            .into_iter()
+            .zip(rerank_scores)
+            .map(|(result, rerank_score)| {
+                // Cross-encoder logits are squashed to 0-1, like the similarity they replace
+                let similarity = match rerank_score {
+                    Some(score) => 1.0 / (1.0 + (-score).exp()),
+                    None => self.normalize_score(result.score),
+                };
+                let (score, components) =
+                    boosting::apply_boosts(similarity, &result.metadata, &options.boosts, now);
+                (result, rerank_score, score, components)
+            })
+            .collect();
+        if options.rerank.is_some() || !options.boosts.is_empty() {
+            scored.sort_by(|a, b| b.2.total_cmp(&a.2));
+        }
+        scored.truncate(k as usize);
+
+        let mut hits = Vec::new();
+        for (result, rerank_score, score, components) in scored {
+            let mut hit = self.with_context(result, options.context_window).await?;
+            hit.rerank_score = rerank_score;
+            hit.score = score;
+            if !options.boosts.is_empty() {
+                hit.score_components = Some(components);
+            }
+            hits.push(hit);
+        }
+        self.with_parents(&mut hits).await?;
+        Ok(hits)
+    }
+
+    /// Looks up the parent chunks of the hits in the document store.
+    async fn with_parents(&self, hits: &mut [SearchHit]) -> Result<(), IndexError> {
+        let parent_ids: Vec<String> = hits
+            .iter()
+            .filter_map(|hit| hit.result.parent_id.clone())
+            .collect();
+        if parent_ids.is_empty() {
+            return Ok(());
+        }
+        let parents = self
+            .repository
+            .get_parent_chunks(&self.name, parent_ids)
+            .await?;
+        for hit in hits {
+            if let Some(parent_id) = &hit.result.parent_id {
+                hit.parent = parents.get(parent_id).cloned();
+            }
+        }
+        Ok(())
+    }
+
+    /// Scores the relevance of the results to the query with a cross-encoder.
+    async fn rerank(
+        &self,
+        query: String,
+        results: &[SearchResult],
+        model: &str,
+    ) -> Result<Vec<f32>, IndexError> {
+        let passages = results.iter().map(|r| r.texts.clone()).collect();
+        let scores = self
+            .embedding_generator
+            .rerank(query, passages, model.into())
+            .await?;
+        // Results without a score would otherwise be dropped from the hits
+        if scores.len() != results.len() {
+            return Err(IndexError::EmbeddingGenerator(
+                EmbeddingGeneratorError::ModelError(format!(
+                    "`{}` returned {} scores for {} results",
+                    model,
+                    scores.len(),
+                    results.len()
+                )),
+            ));
+        }
+        Ok(scores)
+    }
+
+    /// Fetches the `window` chunks on either side of a search result from its document.
+    async fn with_context(
+        &self,
+        result: SearchResult,
+        window: u64,
+    ) -> Result<SearchHit, IndexError> {
+        // Points written before documents were tracked have no id to look them up by
+        if window == 0 || result.document_id.is_empty() {
+            return Ok(SearchHit {
+                result,
+                ..Default::default()
+            });
+        }
+        let start = result.chunk.saturating_sub(window);
+        let end = result.chunk.saturating_add(window);
+        let chunks = self
+            .vectordb
+            .get_chunks(&self.name, &result.document_id, start..=end)
+            .await?;
+        let (context_before, context_after): (Vec<SearchResult>, Vec<SearchResult>) = chunks
+            .into_iter()
+            .filter(|c| c.chunk != result.chunk)
+            .partition(|c| c.chunk < result.chunk);
+        Ok(SearchHit {
+            result,
+            context_before,
+            context_after,
+            ..Default::default()
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::entity::index::Entity as IndexEntity;
+    use super::super::entity::parent_chunk::Entity as ParentChunkEntity;
     use sea_orm::entity::prelude::*;
     use sea_orm::{
         sea_query::TableCreateStatement, Database, DatabaseConnection, DbBackend, DbConn, DbErr,
@@ -227,13 +639,40 @@ mod tests {
     };
 
     use super::*;
+    use std::ops::RangeInclusive;
     use std::sync::Arc;
 
     use crate::{
-        qdrant::QdrantDb, CreateIndexParams, EmbeddingRouter, MetricKind, QdrantConfig,
-        ServerConfig, VectorIndexConfig,
+        embeddings::testing::MockGenerator, qdrant::QdrantDb, CreateIndexParams, EmbeddingRouter,
+        MetricKind, QdrantConfig, ServerConfig, VectorDb, VectorIndexConfig,
     };
 
+    #[test]
+    fn test_chunking_validation() {
+        assert!(ChunkingParams::default().validate().is_ok());
+        let params = ChunkingParams {
+            chunk_size: 100,
+            parent: Some(ParentChunking::Tokens(100)),
+        };
+        assert!(params.validate().is_ok());
+        let params = ChunkingParams {
+            chunk_size: 0,
+            parent: None,
+        };
+        assert!(matches!(
+            params.validate(),
+            Err(IndexError::InvalidChunking(_))
+        ));
+        let params = ChunkingParams {
+            chunk_size: 100,
+            parent: Some(ParentChunking::Tokens(50)),
+        };
+        assert!(matches!(
+            params.validate(),
+            Err(IndexError::InvalidChunking(_))
+        ));
+    }
+
     #[tokio::test]
     #[tracing_test::traced_test]
     async fn test_qdrant_search_basic() {
@@ -249,6 +688,8 @@ mod tests {
             vector_dim: 384,
             metric: MetricKind::Cosine,
             unique_params: None,
+            post_processing: None,
+            quantization: None,
         };
         let index_config = Some(VectorIndexConfig {
             index_store: crate::IndexStoreKind::Qdrant,
@@ -266,6 +707,7 @@ mod tests {
                 index_params,
                 "all-minilm-l12-v2".into(),
                 TextSplitterKind::Noop,
+                ChunkingParams::default(),
             )
             .await
             .unwrap();
@@ -287,10 +729,214 @@ mod tests {
             ])
             .await
             .unwrap();
-        let result = index.search("pipe".into(), 1).await.unwrap();
+        let result = index
+            .search("pipe".into(), 1, SearchOptions::default())
+            .await
+            .unwrap();
         assert_eq!(1, result.len())
     }
 
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_qdrant_search_parent_document() {
+        let qdrant: VectorDBTS = Arc::new(QdrantDb::new(crate::QdrantConfig {
+            addr: "http://localhost:6334".into(),
+        }));
+        qdrant.drop_index("parents".into()).await.unwrap();
+        let embedding_router =
+            Arc::new(EmbeddingRouter::new(Arc::new(ServerConfig::default())).unwrap());
+
+        let index_params = CreateIndexParams {
+            name: "parents".into(),
+            vector_dim: 384,
+            metric: MetricKind::Cosine,
+            unique_params: None,
+            post_processing: None,
+            quantization: None,
+        };
+        let index_config = Some(VectorIndexConfig {
+            index_store: crate::IndexStoreKind::Qdrant,
+            qdrant_config: Some(QdrantConfig {
+                addr: "http://localhost:6334".into(),
+            }),
+            db_url: "sqlite::memory:".into(),
+        });
+        let db = create_db().await.unwrap();
+        let index_manager = IndexManager::new_with_db(index_config, embedding_router, db)
+            .unwrap()
+            .unwrap();
+        index_manager
+            .create_index(
+                index_params,
+                "all-minilm-l12-v2".into(),
+                TextSplitterKind::NewLine,
+                ChunkingParams {
+                    chunk_size: 4,
+                    parent: Some(ParentChunking::Document),
+                },
+            )
+            .await
+            .unwrap();
+        let index = index_manager.load("parents".into()).await.unwrap().unwrap();
+        let doc = "the sixers lost in the second round\nembiid is the mvp this season";
+        index
+            .add_texts(vec![Text {
+                texts: vec![doc.into()],
+                metadata: HashMap::new(),
+            }])
+            .await
+            .unwrap();
+        let result = index
+            .search("who won the mvp".into(), 1, SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, result.len());
+        assert_eq!(Some(doc.to_string()), result[0].parent);
+    }
+
+    /// A vector database that returns the first `results` of any search, whatever the query,
+    /// and doesn't store anything.
+    #[derive(Default)]
+    pub struct MockVectorDb {
+        pub results: Vec<SearchResult>,
+    }
+
+    #[async_trait::async_trait]
+    impl VectorDb for MockVectorDb {
+        async fn create_index(&self, _index: CreateIndexParams) -> Result<(), VectorDbError> {
+            Ok(())
+        }
+
+        async fn add_embedding(
+            &self,
+            _index: &str,
+            _embeddings: Vec<Vec<f32>>,
+            _texts: Vec<String>,
+            _attrs: HashMap<String, String>,
+            _hash_on: Vec<String>,
+            _parent_ids: Vec<Option<String>>,
+        ) -> Result<(), VectorDbError> {
+            Ok(())
+        }
+
+        async fn search(
+            &self,
+            _index: String,
+            _query_embedding: Vec<f32>,
+            k: u64,
+        ) -> Result<Vec<SearchResult>, VectorDbError> {
+            Ok(self.results.iter().take(k as usize).cloned().collect())
+        }
+
+        async fn get_chunks(
+            &self,
+            _index: &str,
+            _document_id: &str,
+            _chunks: RangeInclusive<u64>,
+        ) -> Result<Vec<SearchResult>, VectorDbError> {
+            Ok(vec![])
+        }
+
+        async fn drop_index(&self, _index: String) -> Result<(), VectorDbError> {
+            Ok(())
+        }
+
+        async fn num_vectors(&self, _index: &str) -> Result<u64, VectorDbError> {
+            Ok(self.results.len() as u64)
+        }
+
+        fn name(&self) -> String {
+            "mock".into()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rerank() {
+        let result = |text: &str, score| SearchResult {
+            texts: text.into(),
+            score,
+            ..Default::default()
+        };
+        let vectordb = Arc::new(MockVectorDb {
+            results: vec![result("a", 0.9), result("b", 0.8), result("c", 0.7)],
+        });
+        let index = |rerank_scores| async {
+            let generator: EmbeddingGeneratorTS = Arc::new(MockGenerator {
+                rerank_scores: Some(rerank_scores),
+                ..Default::default()
+            });
+            Index::new(
+                "reranked".into(),
+                vectordb.clone(),
+                generator.clone(),
+                "mock".into(),
+                text_splitters::get_splitter(TextSplitterKind::Noop, generator, "mock".into())
+                    .unwrap(),
+                vec![],
+                Arc::new(Respository::new_with_db(create_db().await.unwrap())),
+                ChunkingParams::default(),
+                Some(MetricKind::Cosine),
+                None,
+            )
+            .await
+            .unwrap()
+            .unwrap()
+        };
+        let options = SearchOptions {
+            rerank: Some(RerankOptions {
+                model: "cross-encoder".into(),
+                candidates: 3,
+            }),
+            ..Default::default()
+        };
+
+        // The cross-encoder scores the candidates, and the best ones are kept
+        let hits = index(vec![-1.0, 2.0, 0.0])
+            .await
+            .search("query".into(), 2, options.clone())
+            .await
+            .unwrap();
+        let texts: Vec<&str> = hits.iter().map(|h| h.result.texts.as_str()).collect();
+        assert_eq!(texts, vec!["b", "c"]);
+        assert_eq!(hits[0].rerank_score, Some(2.0));
+        assert_eq!(hits[0].score, 1.0 / (1.0 + (-2.0f32).exp()));
+
+        let err = index(vec![1.0, 2.0])
+            .await
+            .search("query".into(), 2, options)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("returned 2 scores for 3 results"));
+    }
+
+    #[tokio::test]
+    async fn test_replace_parent_chunks() {
+        let repository = Respository::new_with_db(create_db().await.unwrap());
+        let parent = |id: &str| (id.to_string(), format!("text of {}", id));
+        repository
+            .add_parent_chunks("index", "doc", vec![parent("a"), parent("b")])
+            .await
+            .unwrap();
+        repository
+            .add_parent_chunks("index", "other", vec![parent("c")])
+            .await
+            .unwrap();
+
+        // The parents of the previous version of the document are replaced
+        repository
+            .add_parent_chunks("index", "doc", vec![parent("b"), parent("d")])
+            .await
+            .unwrap();
+        let ids = vec!["a", "b", "c", "d"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let chunks = repository.get_parent_chunks("index", ids).await.unwrap();
+        let mut ids: Vec<&str> = chunks.keys().map(String::as_str).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["b", "c", "d"]);
+    }
+
     async fn create_db() -> Result<DatabaseConnection, DbErr> {
         let db = Database::connect("sqlite::memory:").await?;
 
@@ -306,8 +952,11 @@ mod tests {
         // Derive from Entity
         let stmt1: TableCreateStatement = schema.create_table_from_entity(IndexEntity);
 
+        let stmt2: TableCreateStatement = schema.create_table_from_entity(ParentChunkEntity);
+
         // Execute create table statement
         db.execute(db.get_database_backend().build(&stmt1)).await?;
+        db.execute(db.get_database_backend().build(&stmt2)).await?;
         Ok(())
     }
 }
@@ -1,9 +1,13 @@
+mod boosting;
 mod embeddings;
 mod entity;
+mod fusion;
 mod index;
 mod persistence;
 mod server;
 mod server_config;
+#[cfg(test)]
+mod test_util;
 mod text_splitters;
 mod vectordbs;
 
@@ -1,15 +1,17 @@
 use anyhow::Result;
 use entity::index::Entity as IndexEntity;
 use entity::index::Model as IndexModel;
+use entity::parent_chunk::Entity as ParentChunkEntity;
+use sea_orm::sea_query::OnConflict;
 use sea_orm::ColumnTrait;
 use sea_orm::QueryFilter;
-use sea_orm::{
-    ActiveValue::NotSet, Database, DatabaseConnection, DbErr, EntityTrait, Set, TransactionTrait,
-};
+use sea_orm::{Database, DatabaseConnection, DbErr, EntityTrait, Set, TransactionTrait};
+use std::collections::HashMap;
 use thiserror::Error;
 
 use crate::entity;
-use crate::entity::index;
+use crate::entity::{index, parent_chunk};
+use crate::index::ChunkingParams;
 use crate::vectordbs::{self, CreateIndexParams};
 
 #[derive(Debug, Error)]
@@ -50,6 +52,7 @@ impl Respository {
         index_params: CreateIndexParams,
         vectordb: vectordbs::VectorDBTS,
         text_splitter: String,
+        chunking_params: ChunkingParams,
     ) -> Result<(), RespositoryError> {
         let mut unique_params = None;
         if let Some(u_params) = &index_params.unique_params {
@@ -60,8 +63,9 @@ impl Respository {
             embedding_model: Set(embedding_model),
             text_splitter: Set(text_splitter),
             vector_db: Set(vectordb.name()),
-            vector_db_params: NotSet,
+            vector_db_params: Set(Some(serde_json::to_string(&index_params)?)),
             unique_params: Set(unique_params),
+            chunking_params: Set(Some(serde_json::to_string(&chunking_params)?)),
         };
         let tx = self.conn.begin().await?;
         let insert_result = IndexEntity::insert(index).exec(&tx).await;
@@ -90,4 +94,57 @@ impl Respository {
             .ok_or(RespositoryError::IndexNotFound(index));
         result
     }
+
+    /// Stores the parent chunks of a document of an index, skipping the ones that are already
+    /// stored, and deletes the parent chunks of its previous version that it no longer has.
+    pub async fn add_parent_chunks(
+        &self,
+        index: &str,
+        document_id: &str,
+        chunks: Vec<(String, String)>,
+    ) -> Result<(), RespositoryError> {
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<String> = chunks.iter().map(|(id, _)| id.clone()).collect();
+        let models = chunks
+            .into_iter()
+            .map(|(id, text)| parent_chunk::ActiveModel {
+                id: Set(id),
+                index_name: Set(index.into()),
+                document_id: Set(document_id.into()),
+                text: Set(text),
+            });
+        let tx = self.conn.begin().await?;
+        ParentChunkEntity::insert_many(models)
+            .on_conflict(
+                OnConflict::column(parent_chunk::Column::Id)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&tx)
+            .await?;
+        ParentChunkEntity::delete_many()
+            .filter(parent_chunk::Column::IndexName.eq(index))
+            .filter(parent_chunk::Column::DocumentId.eq(document_id))
+            .filter(parent_chunk::Column::Id.is_not_in(ids))
+            .exec(&tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Returns the text of the parent chunks with the given ids, keyed by id.
+    pub async fn get_parent_chunks(
+        &self,
+        index: &str,
+        ids: Vec<String>,
+    ) -> Result<HashMap<String, String>, RespositoryError> {
+        let chunks = ParentChunkEntity::find()
+            .filter(parent_chunk::Column::IndexName.eq(index))
+            .filter(parent_chunk::Column::Id.is_in(ids))
+            .all(&self.conn)
+            .await?;
+        Ok(chunks.into_iter().map(|c| (c.id, c.text)).collect())
+    }
 }
@@ -1,11 +1,19 @@
-use crate::index::{IndexManager, Text};
-use crate::text_splitters::TextSplitterKind;
-use crate::{CreateIndexParams, EmbeddingRouter, MetricKind, ServerConfig};
+use crate::boosting::{ScoreBoost, ScoreComponents};
+use crate::index::{
+    ChunkingParams, IndexError, IndexManager, RerankOptions, SearchHit, SearchOptions, Text,
+};
+use crate::persistence::RespositoryError;
+use crate::text_splitters::{TextSplitterError, TextSplitterKind};
+use crate::{
+    post_processed_dimensions, CacheStats, CreateIndexParams, EmbeddingCache, EmbeddingRouter,
+    LongInputPolicy, MetricKind, ModelStatus, PostProcessingConfig, ServerConfig,
+};
 
-use super::embeddings::EmbeddingGenerator;
-use anyhow::Result;
+use super::embeddings::{EmbeddingGenerator, EmbeddingGeneratorError, EmbeddingRole};
+use anyhow::{anyhow, Result};
 use axum::http::StatusCode;
 use axum::{extract::State, routing::get, routing::post, Json, Router};
+use base64::Engine;
 use tracing::info;
 
 use serde::{Deserialize, Serialize};
@@ -23,6 +31,14 @@ struct GenerateEmbeddingRequest {
     inputs: Vec<String>,
     /// Name of the model to use for generating embeddings.
     model: String,
+    /// Role of the inputs, `query` or `document`, which applies the template of the model
+    /// for that role. Inputs are embedded as they are by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    role: Option<EmbeddingRole>,
+    /// How inputs longer than the model accepts are embedded, overriding the policy of the
+    /// model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    long_input_policy: Option<LongInputPolicy>,
 }
 
 /// Response payload for generating text embeddings.
@@ -30,6 +46,211 @@ struct GenerateEmbeddingRequest {
 struct GenerateEmbeddingResponse {
     /// Generated embeddings, if successful.
     embeddings: Option<Vec<Vec<f32>>>,
+    /// Indexes of the inputs that were truncated to fit the model.
+    #[serde(default)]
+    truncated: Vec<usize>,
+    /// Error message, if an error occurred.
+    error: Option<String>,
+}
+
+/// Input of an OpenAI embeddings request, a single text or a list of texts.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum OpenAIEmbeddingInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+impl From<OpenAIEmbeddingInput> for Vec<String> {
+    fn from(input: OpenAIEmbeddingInput) -> Self {
+        match input {
+            OpenAIEmbeddingInput::Single(input) => vec![input],
+            OpenAIEmbeddingInput::Batch(inputs) => inputs,
+        }
+    }
+}
+
+/// Encoding of the embeddings of an OpenAI embeddings response.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OpenAIEncodingFormat {
+    #[default]
+    Float,
+    /// The little-endian bytes of the 32-bit floats of an embedding, in base64.
+    Base64,
+}
+
+/// Request payload of the OpenAI-compatible embeddings API.
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenAIEmbeddingRequest {
+    input: OpenAIEmbeddingInput,
+    model: String,
+    #[serde(default)]
+    encoding_format: OpenAIEncodingFormat,
+    /// Identifier of the end user, accepted for compatibility and ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+/// An embedding of the OpenAI-compatible embeddings API, a list of floats or base64.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum OpenAIEmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+impl OpenAIEmbeddingVector {
+    fn new(embedding: Vec<f32>, format: OpenAIEncodingFormat) -> Self {
+        match format {
+            OpenAIEncodingFormat::Float => Self::Float(embedding),
+            OpenAIEncodingFormat::Base64 => {
+                let bytes: Vec<u8> = embedding.iter().flat_map(|v| v.to_le_bytes()).collect();
+                Self::Base64(base64::engine::general_purpose::STANDARD.encode(bytes))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenAIEmbedding {
+    object: String,
+    embedding: OpenAIEmbeddingVector,
+    index: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenAIUsage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+}
+
+/// Response payload of the OpenAI-compatible embeddings API.
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenAIEmbeddingResponse {
+    object: String,
+    data: Vec<OpenAIEmbedding>,
+    model: String,
+    usage: OpenAIUsage,
+}
+
+/// Error payload of the OpenAI-compatible APIs.
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenAIErrorResponse {
+    error: OpenAIError,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenAIError {
+    message: String,
+    #[serde(rename = "type")]
+    kind: String,
+    param: Option<String>,
+    code: Option<String>,
+}
+
+/// Request payload for tokenizing texts, or counting their tokens.
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenizeRequest {
+    /// Input texts to tokenize.
+    inputs: Vec<String>,
+    /// Name of the model whose tokenizer is used.
+    model: String,
+    /// Whether to return the text of each token along with its id.
+    #[serde(default)]
+    include_text: bool,
+}
+
+/// Response payload for tokenizing texts.
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenizeResponse {
+    /// Token ids of each input, if successful.
+    tokens: Option<Vec<Vec<i64>>>,
+    /// Text of each token of each input, if requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_texts: Option<Vec<Vec<String>>>,
+    /// Error message, if an error occurred.
+    error: Option<String>,
+}
+
+/// Request payload for turning token ids back into texts.
+#[derive(Debug, Serialize, Deserialize)]
+struct DetokenizeRequest {
+    /// Token ids of each text.
+    tokens: Vec<Vec<i64>>,
+    /// Name of the model whose tokenizer is used.
+    model: String,
+}
+
+/// Response payload for turning token ids back into texts.
+#[derive(Debug, Serialize, Deserialize)]
+struct DetokenizeResponse {
+    /// Text of each list of token ids, if successful.
+    texts: Option<Vec<String>>,
+    /// Error message, if an error occurred.
+    error: Option<String>,
+}
+
+/// Response payload for counting the tokens of texts.
+#[derive(Debug, Serialize, Deserialize)]
+struct CountTokensResponse {
+    /// Number of tokens of each input, if successful.
+    counts: Option<Vec<usize>>,
+    /// Number of tokens of all the inputs.
+    total: usize,
+    /// Error message, if an error occurred.
+    error: Option<String>,
+}
+
+/// A model that is loaded in memory.
+#[derive(Debug, Serialize, Deserialize)]
+struct ResidentModel {
+    name: String,
+    status: ModelStatus,
+    /// Memory in megabytes the model takes.
+    memory_mb: u64,
+    /// Seconds since the model was last loaded or requested.
+    idle_secs: u64,
+}
+
+/// Response payload for listing the models that are loaded in memory.
+#[derive(Debug, Serialize, Deserialize)]
+struct ResidentModelsResponse {
+    models: Vec<ResidentModel>,
+    /// Memory in megabytes the loaded models take.
+    memory_mb: u64,
+}
+
+/// Request payload for loading a model in memory, or unloading it.
+#[derive(Debug, Serialize, Deserialize)]
+struct ModelLoadingRequest {
+    model: String,
+}
+
+/// Response payload for loading a model in memory, or unloading it.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct ModelLoadingResponse {
+    /// Whether the model was loaded before it was unloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unloaded: Option<bool>,
+    /// Error message, if an error occurred.
+    error: Option<String>,
+}
+
+/// Response payload for the readiness of the server.
+#[derive(Debug, Serialize, Deserialize)]
+struct ReadinessResponse {
+    /// Whether every model is ready.
+    ready: bool,
+    /// Status of each model, by name.
+    models: HashMap<String, ModelStatus>,
+}
+
+/// Response payload for the statistics of the cache of embeddings.
+#[derive(Debug, Serialize, Deserialize)]
+struct EmbeddingCacheStatsResponse {
+    /// Statistics of the cache, if the cache is configured.
+    stats: Option<CacheStats>,
     /// Error message, if an error occurred.
     error: Option<String>,
 }
@@ -94,6 +315,14 @@ struct IndexCreateRequest {
 
     /// Hash on these paramters
     hash_on: Option<Vec<String>>,
+
+    /// Size of the chunks that are embedded, and of their parents if any.
+    #[serde(default)]
+    chunking: ChunkingParams,
+
+    /// Transforms of the embeddings stored in the index, for models without post-processing.
+    #[serde(default)]
+    post_processing: Option<PostProcessingConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -118,17 +347,86 @@ struct IndexAdditionResponse {
     errors: Vec<String>,
 }
 
+/// Re-ranking of search results with a cross-encoder model.
+#[derive(Debug, Serialize, Deserialize)]
+struct ApiRerank {
+    /// Name of the cross-encoder model.
+    model: String,
+    /// Number of candidates to score, defaults to a multiple of k.
+    candidates: Option<u64>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct SearchRequest {
     index: String,
     query: String,
     k: u64,
+    /// Number of neighbouring chunks to return before and after each hit.
+    #[serde(default)]
+    context_window: u64,
+    /// Re-ranks the results with a cross-encoder, if set.
+    rerank: Option<ApiRerank>,
+    /// Adjustments of the similarity score, applied in order.
+    #[serde(default)]
+    boosts: Vec<ScoreBoost>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FederatedSearchRequest {
+    /// Names of the indexes to search.
+    indexes: Vec<String>,
+    query: String,
+    k: u64,
+    #[serde(default)]
+    context_window: u64,
+    rerank: Option<ApiRerank>,
+    #[serde(default)]
+    boosts: Vec<ScoreBoost>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct DocumentFragment {
     text: String,
     metadata: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    context_before: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    context_after: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    /// Index the fragment comes from, in federated searches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    score: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rerank_score: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    score_components: Option<ScoreComponents>,
+}
+
+impl From<&SearchHit> for DocumentFragment {
+    fn from(hit: &SearchHit) -> Self {
+        Self {
+            text: hit.result.texts.to_owned(),
+            metadata: hit.result.metadata.to_owned(),
+            context_before: hit
+                .context_before
+                .iter()
+                .map(|c| c.texts.to_owned())
+                .collect(),
+            context_after: hit
+                .context_after
+                .iter()
+                .map(|c| c.texts.to_owned())
+                .collect(),
+            parent: hit.parent.to_owned(),
+            score: Some(hit.score),
+            rerank_score: hit.rerank_score,
+            score_components: hit.score_components.to_owned(),
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -164,12 +462,29 @@ impl Server {
     ///
     /// * A result indicating success or failure of the operation.
     pub async fn run(&self) -> Result<()> {
-        let embedding_router = Arc::new(EmbeddingRouter::new(self.config.clone())?);
+        let mut embedding_router = EmbeddingRouter::new(self.config.clone())?;
+        if let Some(cache_config) = &self.config.embedding_cache {
+            let db_url = cache_config
+                .db_url
+                .clone()
+                .or(self.config.index_config.as_ref().map(|c| c.db_url.clone()))
+                .ok_or(anyhow!(
+                    "the embedding cache needs a `db_url`, or an index configuration"
+                ))?;
+            info!("embedding cache: using database: {:?}", db_url);
+            let cache = EmbeddingCache::new(&db_url, cache_config.max_entries).await?;
+            embedding_router = embedding_router.with_cache(cache);
+        }
+        let embedding_router = Arc::new(embedding_router);
         let index_manager = Arc::new(
             IndexManager::new(self.config.index_config.clone(), embedding_router.clone()).await?,
         );
         let app = Router::new()
             .route("/", get(root))
+            .route(
+                "/ready",
+                get(readiness).with_state(embedding_router.clone()),
+            )
             .route(
                 "/embeddings/models",
                 get(list_embedding_models).with_state(embedding_router.clone()),
@@ -178,6 +493,38 @@ impl Server {
                 "/embeddings/generate",
                 get(generate_embedding).with_state(embedding_router.clone()),
             )
+            .route(
+                "/v1/embeddings",
+                post(openai_embeddings).with_state(embedding_router.clone()),
+            )
+            .route(
+                "/tokenize",
+                post(tokenize).with_state(embedding_router.clone()),
+            )
+            .route(
+                "/detokenize",
+                post(detokenize).with_state(embedding_router.clone()),
+            )
+            .route(
+                "/tokens/count",
+                post(count_tokens).with_state(embedding_router.clone()),
+            )
+            .route(
+                "/embeddings/cache",
+                get(embedding_cache_stats).with_state(embedding_router.clone()),
+            )
+            .route(
+                "/admin/models",
+                get(list_resident_models).with_state(embedding_router.clone()),
+            )
+            .route(
+                "/admin/models/load",
+                post(load_model).with_state(embedding_router.clone()),
+            )
+            .route(
+                "/admin/models/unload",
+                post(unload_model).with_state(embedding_router.clone()),
+            )
             .route(
                 "/index/create",
                 post(index_create).with_state((index_manager.clone(), embedding_router.clone())),
@@ -189,6 +536,10 @@ impl Server {
             .route(
                 "/index/search",
                 get(index_search).with_state((index_manager.clone(), embedding_router.clone())),
+            )
+            .route(
+                "/index/federated_search",
+                get(federated_search).with_state((index_manager.clone(), embedding_router.clone())),
             );
 
         info!("server is listening at addr {:?}", &self.addr.to_string());
@@ -206,6 +557,118 @@ async fn root() -> &'static str {
     "Indexify Server"
 }
 
+/// A handler for the readiness of the server, which is ready when every model is. Models that
+/// failed to load, or crashed, are reloaded in the background.
+///
+/// # Returns
+///
+/// * A tuple containing an HTTP status code, which is `503 Service Unavailable` unless every
+///   model is ready, and a JSON response payload with the status of each model.
+#[axum_macros::debug_handler]
+async fn readiness(
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
+) -> (StatusCode, Json<ReadinessResponse>) {
+    let models: HashMap<String, ModelStatus> = embedding_router
+        .list_models()
+        .into_iter()
+        .map(|model| {
+            let status = embedding_router
+                .status(model.clone())
+                .unwrap_or_else(|e| ModelStatus::Failed(e.to_string()));
+            (model, status)
+        })
+        .collect();
+    let ready = models.values().all(ModelStatus::is_available);
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(ReadinessResponse { ready, models }))
+}
+
+/// A handler for listing the models that are loaded in memory, among the models that are
+/// loaded on demand, and the memory they take.
+#[axum_macros::debug_handler]
+async fn list_resident_models(
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
+) -> Json<ResidentModelsResponse> {
+    let models: Vec<ResidentModel> = embedding_router
+        .list_models()
+        .into_iter()
+        .filter_map(|model| {
+            let residency = embedding_router.residency(model.clone())?;
+            if !residency.resident {
+                return None;
+            }
+            let status = embedding_router
+                .status(model.clone())
+                .unwrap_or_else(|e| ModelStatus::Failed(e.to_string()));
+            Some(ResidentModel {
+                name: model,
+                status,
+                memory_mb: residency.memory_mb,
+                idle_secs: residency.idle_secs,
+            })
+        })
+        .collect();
+    let memory_mb = models.iter().map(|model| model.memory_mb).sum();
+    Json(ResidentModelsResponse { models, memory_mb })
+}
+
+/// A handler for loading a model in memory, which returns once the model is ready. Models
+/// that don't fit in the memory budget unload the least recently used ones.
+///
+/// # Returns
+///
+/// * A tuple containing an HTTP status code and a JSON response payload. The response payload
+///   contains an error message if the model couldn't be loaded.
+#[axum_macros::debug_handler]
+async fn load_model(
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
+    Json(payload): Json<ModelLoadingRequest>,
+) -> (StatusCode, Json<ModelLoadingResponse>) {
+    match embedding_router.load_model(payload.model).await {
+        Ok(()) => (StatusCode::OK, Json(ModelLoadingResponse::default())),
+        Err(err) => (
+            embedding_error_status(&err, StatusCode::EXPECTATION_FAILED),
+            Json(ModelLoadingResponse {
+                unloaded: None,
+                error: Some(err.to_string()),
+            }),
+        ),
+    }
+}
+
+/// A handler for unloading a model from memory, once its queued requests are served. The
+/// model is loaded again on its next request.
+///
+/// # Returns
+///
+/// * A tuple containing an HTTP status code and a JSON response payload. The response payload
+///   contains whether the model was loaded, or an error message if an error occurred.
+#[axum_macros::debug_handler]
+async fn unload_model(
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
+    Json(payload): Json<ModelLoadingRequest>,
+) -> (StatusCode, Json<ModelLoadingResponse>) {
+    match embedding_router.unload_model(payload.model) {
+        Ok(unloaded) => (
+            StatusCode::OK,
+            Json(ModelLoadingResponse {
+                unloaded: Some(unloaded),
+                error: None,
+            }),
+        ),
+        Err(err) => (
+            embedding_error_status(&err, StatusCode::BAD_REQUEST),
+            Json(ModelLoadingResponse {
+                unloaded: None,
+                error: Some(err.to_string()),
+            }),
+        ),
+    }
+}
+
 /// A handler for creating a new vector index in the vector database. This handler is responsible
 /// for processing incoming requests to create new vector indices, which are used to store and
 /// query vector embeddings. The request payload contains the name of the new index, the name of
@@ -233,7 +696,31 @@ async fn index_create(
             }),
         );
     }
-    let try_dim = index_args.1.dimensions(payload.embedding_model.clone());
+    // The embeddings of models that are post-processed aren't post-processed again
+    if payload.post_processing.is_some()
+        && index_args
+            .1
+            .post_processing(&payload.embedding_model)
+            .is_some()
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(IndexCreateResponse {
+                errors: vec![format!(
+                    "model `{}` already post-processes its embeddings, the index can't set `post_processing`",
+                    payload.embedding_model
+                )],
+            }),
+        );
+    }
+    // The dimensions of the model are the ones after its own post-processing
+    let try_dim = index_args
+        .1
+        .dimensions(payload.embedding_model.clone())
+        .and_then(|dim| match &payload.post_processing {
+            Some(config) => post_processed_dimensions(config, dim),
+            None => Ok(dim),
+        });
     if let Err(err) = try_dim {
         return (
             StatusCode::BAD_REQUEST,
@@ -251,17 +738,28 @@ async fn index_create(
             IndexMetric::Euclidean => MetricKind::Euclidean,
         },
         unique_params: payload.hash_on,
+        quantization: payload
+            .post_processing
+            .as_ref()
+            .or_else(|| index_args.1.post_processing(&payload.embedding_model))
+            .and_then(|config| config.quantization),
+        post_processing: payload.post_processing,
     };
     let index_manager = index_args.0.as_ref();
     let splitter_kind = TextSplitterKind::from_str(&payload.text_splitter.to_string()).unwrap();
     let result = index_manager
         .as_ref()
         .unwrap()
-        .create_index(index_params, payload.embedding_model, splitter_kind)
+        .create_index(
+            index_params,
+            payload.embedding_model,
+            splitter_kind,
+            payload.chunking,
+        )
         .await;
     if let Err(err) = result {
         return (
-            StatusCode::INTERNAL_SERVER_ERROR,
+            index_error_status(&err, StatusCode::INTERNAL_SERVER_ERROR),
             Json(IndexCreateResponse {
                 errors: vec![err.to_string()],
             }),
@@ -313,7 +811,7 @@ async fn add_texts(
     let result = index.add_texts(texts).await;
     if let Err(err) = result {
         return (
-            StatusCode::BAD_REQUEST,
+            index_error_status(&err, StatusCode::BAD_REQUEST),
             Json(IndexAdditionResponse {
                 errors: vec![err.to_string()],
             }),
@@ -323,6 +821,54 @@ async fn add_texts(
     (StatusCode::OK, Json(IndexAdditionResponse::default()))
 }
 
+/// Returns the status of a request that failed to generate embeddings: an overloaded or
+/// unavailable model is reported as such, so that clients know to retry, a failure of the
+/// provider of a model is a bad gateway, an input that is too long is a bad request, and
+/// other errors get the `default` status.
+fn embedding_error_status(err: &EmbeddingGeneratorError, default: StatusCode) -> StatusCode {
+    match err {
+        EmbeddingGeneratorError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+        EmbeddingGeneratorError::Unavailable(..) => StatusCode::SERVICE_UNAVAILABLE,
+        EmbeddingGeneratorError::ProviderError(..) => StatusCode::BAD_GATEWAY,
+        EmbeddingGeneratorError::InputTooLong(..) => StatusCode::BAD_REQUEST,
+        _ => default,
+    }
+}
+
+/// Returns the status of a request that failed on an index, see `embedding_error_status`.
+fn index_error_status(err: &IndexError, default: StatusCode) -> StatusCode {
+    match err {
+        IndexError::EmbeddingGenerator(err)
+        | IndexError::TextSplitter(TextSplitterError::TokenizerError(err)) => {
+            embedding_error_status(err, default)
+        }
+        IndexError::InvalidChunking(_)
+        | IndexError::Persistence(RespositoryError::IndexNotFound(_)) => StatusCode::BAD_REQUEST,
+        _ => default,
+    }
+}
+
+/// Number of candidates re-ranked for each requested result, unless specified.
+const RERANK_CANDIDATES_PER_RESULT: u64 = 4;
+
+fn search_options(
+    context_window: u64,
+    rerank: Option<ApiRerank>,
+    boosts: Vec<ScoreBoost>,
+    k: u64,
+) -> SearchOptions {
+    SearchOptions {
+        context_window,
+        rerank: rerank.map(|r| RerankOptions {
+            model: r.model,
+            candidates: r
+                .candidates
+                .unwrap_or(k.saturating_mul(RERANK_CANDIDATES_PER_RESULT)),
+        }),
+        boosts,
+    }
+}
+
 #[axum_macros::debug_handler]
 async fn index_search(
     State(index_args): State<IndexEndpointState>,
@@ -359,10 +905,11 @@ async fn index_search(
         );
     }
     let index = try_index.unwrap().unwrap();
-    let results = index.search(query.query, query.k).await;
+    let options = search_options(query.context_window, query.rerank, query.boosts, query.k);
+    let results = index.search(query.query, query.k, options).await;
     if let Err(err) = results {
         return (
-            StatusCode::INTERNAL_SERVER_ERROR,
+            index_error_status(&err, StatusCode::INTERNAL_SERVER_ERROR),
             Json(IndexSearchResponse {
                 results: vec![],
                 errors: vec![err.to_string()],
@@ -372,9 +919,62 @@ async fn index_search(
     let document_fragments: Vec<DocumentFragment> = results
         .unwrap()
         .iter()
-        .map(|text| DocumentFragment {
-            text: text.texts.to_owned(),
-            metadata: text.metadata.to_owned(),
+        .map(DocumentFragment::from)
+        .collect();
+    (
+        StatusCode::OK,
+        Json(IndexSearchResponse {
+            results: document_fragments,
+            errors: vec![],
+        }),
+    )
+}
+
+#[axum_macros::debug_handler]
+async fn federated_search(
+    State(index_args): State<IndexEndpointState>,
+    Json(query): Json<FederatedSearchRequest>,
+) -> (StatusCode, Json<IndexSearchResponse>) {
+    if index_args.0.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(IndexSearchResponse {
+                errors: vec!["server is not configured to have indexes".into()],
+                ..Default::default()
+            }),
+        );
+    }
+    if query.indexes.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(IndexSearchResponse {
+                errors: vec!["at least one index must be searched".into()],
+                ..Default::default()
+            }),
+        );
+    }
+
+    let index_manager = index_args.0.as_ref().as_ref().unwrap();
+    let options = search_options(query.context_window, query.rerank, query.boosts, query.k);
+    let results = index_manager
+        .federated_search(query.indexes, query.query, query.k, options)
+        .await;
+    if let Err(err) = results {
+        return (
+            index_error_status(&err, StatusCode::INTERNAL_SERVER_ERROR),
+            Json(IndexSearchResponse {
+                results: vec![],
+                errors: vec![err.to_string()],
+            }),
+        );
+    }
+    let document_fragments: Vec<DocumentFragment> = results
+        .unwrap()
+        .iter()
+        .map(|result| DocumentFragment {
+            index: Some(result.index.to_owned()),
+            score: Some(result.score),
+            ..DocumentFragment::from(&result.hit)
         })
         .collect();
     (
@@ -423,37 +1023,302 @@ async fn list_embedding_models(
 ///
 /// # Parameters
 ///
-/// * `State(embedding_generator)`: The current state of the embedding generator.
+/// * `State(embedding_router)`: The router of the embedding models.
 /// * `Json(payload)`: The request payload containing the input texts and model name.
 ///
 /// # Returns
 ///
 /// * A tuple containing an HTTP status code and a JSON response payload. The response payload
-///   contains the generated embeddings if successful, or an error message if an error occurred.
+///   contains the generated embeddings, and the inputs that were truncated, if successful, or
+///   an error message if an error occurred.
 #[axum_macros::debug_handler]
 async fn generate_embedding(
-    State(embedding_generator): State<Arc<dyn EmbeddingGenerator + Sync + Send>>,
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
     Json(payload): Json<GenerateEmbeddingRequest>,
 ) -> (StatusCode, Json<GenerateEmbeddingResponse>) {
-    let embeddings = embedding_generator
-        .generate_embeddings(payload.inputs, payload.model)
+    let embeddings = embedding_router
+        .embed(
+            payload.inputs,
+            payload.model,
+            payload.role,
+            payload.long_input_policy,
+            false,
+        )
         .await;
 
-    if let Err(err) = embeddings {
-        return (
-            StatusCode::EXPECTATION_FAILED,
+    match embeddings {
+        Ok(embeddings) => (
+            StatusCode::OK,
+            Json(GenerateEmbeddingResponse {
+                embeddings: Some(embeddings.embeddings),
+                truncated: embeddings.truncated,
+                error: None,
+            }),
+        ),
+        Err(err) => (
+            embedding_error_status(&err, StatusCode::EXPECTATION_FAILED),
             Json(GenerateEmbeddingResponse {
                 embeddings: None,
+                truncated: vec![],
                 error: Some(err.to_string()),
             }),
-        );
+        ),
     }
+}
 
-    (
-        StatusCode::OK,
-        Json(GenerateEmbeddingResponse {
-            embeddings: Some(embeddings.unwrap()),
-            error: None,
-        }),
-    )
+/// A handler for generating text embeddings with the request and response of the embeddings
+/// API of OpenAI, so that OpenAI clients can use the models of the server. Usage is the number
+/// of tokens of the inputs counted while embedding them.
+///
+/// # Parameters
+///
+/// * `State(embedding_router)`: The router of the embedding models.
+/// * `Json(payload)`: The request payload containing the input texts, model name and the
+///   encoding of the embeddings.
+///
+/// # Returns
+///
+/// * The embeddings and token usage if successful, or an OpenAI error payload with the status
+///   of the error.
+#[axum_macros::debug_handler]
+async fn openai_embeddings(
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
+    Json(payload): Json<OpenAIEmbeddingRequest>,
+) -> Result<Json<OpenAIEmbeddingResponse>, (StatusCode, Json<OpenAIErrorResponse>)> {
+    let inputs: Vec<String> = payload.input.into();
+    let error = |err: EmbeddingGeneratorError| {
+        let (status, kind, code) = match err {
+            EmbeddingGeneratorError::ModelNotFound(_) => (
+                StatusCode::NOT_FOUND,
+                "invalid_request_error",
+                Some("model_not_found".to_string()),
+            ),
+            EmbeddingGeneratorError::InputTooLong(..) => {
+                (StatusCode::BAD_REQUEST, "invalid_request_error", None)
+            }
+            _ => (
+                embedding_error_status(&err, StatusCode::EXPECTATION_FAILED),
+                "server_error",
+                None,
+            ),
+        };
+        (
+            status,
+            Json(OpenAIErrorResponse {
+                error: OpenAIError {
+                    message: err.to_string(),
+                    kind: kind.into(),
+                    param: None,
+                    code,
+                },
+            }),
+        )
+    };
+    let embeddings = embedding_router
+        .embed(inputs, payload.model.clone(), None, None, true)
+        .await
+        .map_err(error)?;
+    let prompt_tokens = embeddings.tokens.iter().flatten().sum();
+    let data = embeddings
+        .embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| OpenAIEmbedding {
+            object: "embedding".into(),
+            embedding: OpenAIEmbeddingVector::new(embedding, payload.encoding_format),
+            index,
+        })
+        .collect();
+    Ok(Json(OpenAIEmbeddingResponse {
+        object: "list".into(),
+        data,
+        model: payload.model,
+        usage: OpenAIUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    }))
+}
+
+/// A handler for tokenizing texts with the tokenizer of a model, the one that indexes using
+/// the model split texts with.
+///
+/// # Parameters
+///
+/// * `State(embedding_router)`: The router of the embedding models.
+/// * `Json(payload)`: The request payload containing the input texts and model name.
+///
+/// # Returns
+///
+/// * A tuple containing an HTTP status code and a JSON response payload. The response payload
+///   contains the token ids of each input, and the text of each token if requested, or an
+///   error message if an error occurred.
+#[axum_macros::debug_handler]
+async fn tokenize(
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
+    Json(payload): Json<TokenizeRequest>,
+) -> (StatusCode, Json<TokenizeResponse>) {
+    // The texts of the tokens come from the same encoding as their ids, so that they line up
+    let tokenized = match payload.include_text {
+        true => embedding_router
+            .tokenize_encode_text(payload.inputs, payload.model)
+            .await
+            .map(|tokenized| {
+                let (tokens, token_texts) = tokenized
+                    .into_iter()
+                    .map(|tokens| (tokens.ids, tokens.texts))
+                    .unzip();
+                (tokens, Some(token_texts))
+            }),
+        false => embedding_router
+            .tokenize_encode(payload.inputs, payload.model)
+            .await
+            .map(|tokens| (tokens, None)),
+    };
+    match tokenized {
+        Ok((tokens, token_texts)) => (
+            StatusCode::OK,
+            Json(TokenizeResponse {
+                tokens: Some(tokens),
+                token_texts,
+                error: None,
+            }),
+        ),
+        Err(err) => (
+            embedding_error_status(&err, StatusCode::EXPECTATION_FAILED),
+            Json(TokenizeResponse {
+                tokens: None,
+                token_texts: None,
+                error: Some(err.to_string()),
+            }),
+        ),
+    }
+}
+
+/// A handler for turning token ids of a model back into texts.
+///
+/// # Parameters
+///
+/// * `State(embedding_router)`: The router of the embedding models.
+/// * `Json(payload)`: The request payload containing the token ids and model name.
+///
+/// # Returns
+///
+/// * A tuple containing an HTTP status code and a JSON response payload. The response payload
+///   contains the text of each list of token ids, or an error message if an error occurred.
+#[axum_macros::debug_handler]
+async fn detokenize(
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
+    Json(payload): Json<DetokenizeRequest>,
+) -> (StatusCode, Json<DetokenizeResponse>) {
+    match embedding_router
+        .tokenize_decode(payload.tokens, payload.model)
+        .await
+    {
+        Ok(texts) => (
+            StatusCode::OK,
+            Json(DetokenizeResponse {
+                texts: Some(texts),
+                error: None,
+            }),
+        ),
+        Err(err) => (
+            embedding_error_status(&err, StatusCode::EXPECTATION_FAILED),
+            Json(DetokenizeResponse {
+                texts: None,
+                error: Some(err.to_string()),
+            }),
+        ),
+    }
+}
+
+/// A handler for counting the tokens of texts with the tokenizer of a model, without
+/// returning the tokens.
+///
+/// # Parameters
+///
+/// * `State(embedding_router)`: The router of the embedding models.
+/// * `Json(payload)`: The request payload containing the input texts and model name.
+///
+/// # Returns
+///
+/// * A tuple containing an HTTP status code and a JSON response payload. The response payload
+///   contains the number of tokens of each input and their total, or an error message if an
+///   error occurred.
+#[axum_macros::debug_handler]
+async fn count_tokens(
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
+    Json(payload): Json<TokenizeRequest>,
+) -> (StatusCode, Json<CountTokensResponse>) {
+    match embedding_router
+        .count_tokens(payload.inputs, payload.model)
+        .await
+    {
+        Ok(counts) => (
+            StatusCode::OK,
+            Json(CountTokensResponse {
+                total: counts.iter().sum(),
+                counts: Some(counts),
+                error: None,
+            }),
+        ),
+        Err(err) => (
+            embedding_error_status(&err, StatusCode::EXPECTATION_FAILED),
+            Json(CountTokensResponse {
+                counts: None,
+                total: 0,
+                error: Some(err.to_string()),
+            }),
+        ),
+    }
+}
+
+/// A handler for the statistics of the cache of embeddings: its hits and misses since the
+/// server started, and the number of embeddings it holds.
+///
+/// # Returns
+///
+/// * A tuple containing an HTTP status code and a JSON response payload. The response payload
+///   contains no statistics if the cache is not configured, or an error message if an error
+///   occurred.
+#[axum_macros::debug_handler]
+async fn embedding_cache_stats(
+    State(embedding_router): State<Arc<EmbeddingRouter>>,
+) -> (StatusCode, Json<EmbeddingCacheStatsResponse>) {
+    match embedding_router.cache_stats().await {
+        Ok(stats) => (
+            StatusCode::OK,
+            Json(EmbeddingCacheStatsResponse { stats, error: None }),
+        ),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(EmbeddingCacheStatsResponse {
+                stats: None,
+                error: Some(err.to_string()),
+            }),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openai_embedding_request() {
+        let request: OpenAIEmbeddingRequest =
+            serde_json::from_str(r#"{"input": "hello", "model": "all-minilm-l12-v2"}"#).unwrap();
+        assert_eq!(Vec::<String>::from(request.input), vec!["hello"]);
+        let request: OpenAIEmbeddingRequest = serde_json::from_str(
+            r#"{"input": ["a", "b"], "model": "hash", "encoding_format": "base64"}"#,
+        )
+        .unwrap();
+        assert_eq!(Vec::<String>::from(request.input), vec!["a", "b"]);
+
+        let embedding = OpenAIEmbeddingVector::new(vec![1.0, -2.0], request.encoding_format);
+        assert_eq!(
+            embedding,
+            OpenAIEmbeddingVector::Base64("AACAPwAAAMA=".into())
+        );
+    }
 }
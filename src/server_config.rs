@@ -4,39 +4,43 @@ use figment::{
     Figment,
 };
 use serde::{Deserialize, Serialize};
+use smart_default::SmartDefault;
+use std::collections::HashMap;
 use std::fs;
 
 const OPENAI_DUMMY_KEY: &str = "xxxxx";
 
 /// Enum representing the different kinds of text embedding models available for use.
-/// Each variant is associated with specific dimensions, which represent the size of the embeddings.
+/// Each variant is associated with specific dimensions, which represent the size of the embeddings,
+/// with the maximum number of tokens of its inputs, and with the memory its weights take.
 #[derive(
-    Debug, Clone, Serialize, Deserialize, strum_macros::Display, strum_macros::EnumProperty,
+    Debug, Clone, Default, Serialize, Deserialize, strum_macros::Display, strum_macros::EnumProperty,
 )]
 #[strum(serialize_all = "kebab-case")]
 pub enum EmbeddingModelKind {
-    #[strum(props(dimensions = "384"))]
+    #[default]
+    #[strum(props(dimensions = "384", max_sequence_length = "128", memory_mb = "134"))]
     #[strum(serialize = "all-minilm-l12-v2")]
     #[serde(rename = "all-minilm-l12-v2")]
     AllMiniLmL12V2,
 
-    #[strum(props(dimensions = "384"))]
+    #[strum(props(dimensions = "384", max_sequence_length = "256", memory_mb = "91"))]
     #[strum(serialize = "all-minilm-l6-v2")]
     #[serde(rename = "all-minilm-l6-v2")]
     AllMiniLmL6V2,
 
-    #[strum(props(dimensions = "768"))]
+    #[strum(props(dimensions = "768", max_sequence_length = "384", memory_mb = "438"))]
     #[strum(serialize = "all-mpnet-base-v2")]
     #[serde(rename = "all-mpnet-base-v2")]
     AllMpnetBaseV2,
 
-    #[strum(props(dimensions = "768"))]
+    #[strum(props(dimensions = "768", max_sequence_length = "512", memory_mb = "329"))]
     #[strum(serialize = "all-distilroberta-v1")]
     #[serde(rename = "all-distilroberta-v1")]
     AllDistilrobertaV1,
 
     /// T5 Model
-    #[strum(props(dimensions = "768"))]
+    #[strum(props(dimensions = "768", max_sequence_length = "256", memory_mb = "440"))]
     #[strum(serialize = "t5-base")]
     #[serde(rename = "t5-base")]
     T5Base,
@@ -46,13 +50,37 @@ pub enum EmbeddingModelKind {
     #[strum(serialize = "text-embedding-ada-002")]
     #[serde(rename = "text-embedding-ada-002")]
     OpenAIAda02,
+
+    /// MS MARCO cross-encoder, which scores (query, passage) pairs for re-ranking
+    /// instead of generating embeddings.
+    #[strum(props(memory_mb = "91"))]
+    #[strum(serialize = "ms-marco-minilm-l6-v2")]
+    #[serde(rename = "ms-marco-minilm-l6-v2")]
+    MsMarcoMiniLmL6V2,
+
+    /// A model served by a server implementing the OpenAI embeddings API, under its `name`.
+    #[strum(serialize = "openai-compatible")]
+    #[serde(rename = "openai-compatible")]
+    OpenAICompatible,
+
+    /// A sentence-transformer model described by its configuration, served under its `name`.
+    #[strum(serialize = "custom")]
+    #[serde(rename = "custom")]
+    Custom,
+
+    /// A model that embeds hashed words and character n-grams, which needs no weights and
+    /// no network. Meant for tests, its embeddings have `dimensions` dimensions, 384 by default.
+    #[strum(serialize = "hash")]
+    #[serde(rename = "hash")]
+    Hash,
 }
 
 /// Enum representing the different kinds of devices on which the text embedding models can be run.
 /// The available options are CPU, GPU, and Remote (for remote services such as OpenAI).
-#[derive(Debug, Clone, Serialize, Deserialize, strum_macros::Display)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, strum_macros::Display)]
 #[strum(serialize_all = "kebab-case")]
 pub enum DeviceKind {
+    #[default]
     #[serde(rename = "cpu")]
     Cpu,
     #[serde(rename = "gpu")]
@@ -63,21 +91,335 @@ pub enum DeviceKind {
 
 /// Struct representing the configuration of a text embedding model.
 /// It includes the kind of model being used (e.g., AllMiniLmL12V2) and the kind of device on which the model will run (e.g., CPU).
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct EmbeddingModel {
     #[serde(rename = "model")]
     pub model_kind: EmbeddingModelKind,
     #[serde(rename = "device")]
     pub device_kind: DeviceKind,
+    /// Name the model is served under, required for custom models.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Local directory the model is loaded from, instead of downloading it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Architecture of the transformer of a custom model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<ModelArchitecture>,
+    /// Expected number of dimensions of the embeddings, checked against the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u64>,
+    /// Maximum number of tokens of an input, longer inputs are truncated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sequence_length: Option<usize>,
+    /// Pooling of the token embeddings, overriding the one of the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pooling: Option<PoolingKind>,
+    /// Runs the model with ONNX Runtime instead of libtorch, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub onnx: Option<OnnxModelConfig>,
+    /// Server of an OpenAI-compatible model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub openai_compatible: Option<OpenAICompatibleConfig>,
+    /// Batching of concurrent requests of a model run with libtorch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batching: Option<BatchingConfig>,
+    /// Inference workers of a model run with libtorch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workers: Option<WorkersConfig>,
+    /// OpenAI configuration of an OpenAI model, overriding the one of the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub openai: Option<OpenAIConfig>,
+    /// Templates queries and documents are embedded with, for models trained with them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub templates: Option<TemplatesConfig>,
+    /// How inputs longer than `max_sequence_length` are embedded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub long_input_policy: Option<LongInputPolicy>,
+    /// Transforms of the embeddings generated by the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_processing: Option<PostProcessingConfig>,
+    /// Tokenizer the texts of the model are tokenized with, instead of the model itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokenizer: Option<TokenizerConfig>,
+    /// Memory in megabytes a replica of the model takes once loaded, counted against the
+    /// memory budget of the models. Defaults to the size of the weights of the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<u64>,
 }
 
-/// Struct representing the configuration for OpenAI.
-/// It includes the API key required for accessing OpenAI's services.
+impl EmbeddingModel {
+    /// Returns the name the model is served under, which is the name of its kind unless
+    /// the configuration names it.
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| self.model_kind.to_string())
+    }
+}
+
+/// Enum representing how inputs with more tokens than a model accepts are embedded.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LongInputPolicy {
+    /// Fail the request.
+    Error,
+    /// Embed the beginning of the input, dropping its end.
+    #[default]
+    TruncateTail,
+    /// Embed the end of the input, dropping its beginning.
+    TruncateHead,
+    /// Embed consecutive windows of the input, and average their embeddings.
+    SlidingWindow,
+}
+
+/// Struct representing the transforms applied to embeddings once they're generated, in
+/// order: truncation to fewer dimensions, L2 normalization and quantization.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct PostProcessingConfig {
+    /// Number of dimensions embeddings are truncated to, for models trained with Matryoshka
+    /// representation learning, whose first dimensions hold most of the information.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u64>,
+    /// Scale embeddings to unit length, so that the dot product is their cosine similarity.
+    pub normalize: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<Quantization>,
+}
+
+/// Enum representing a tokenizer that is loaded on its own, without the weights of its
+/// model, so that texts are split and counted without running the model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenizerConfig {
+    /// Path of the Hugging Face `tokenizer.json` of the model.
+    Path(String),
+    /// Name of a tiktoken encoding, such as `cl100k_base`.
+    Tiktoken(String),
+}
+
+/// Enum representing how the values of embeddings are quantized.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantization {
+    /// Values between -1 and 1 are mapped to integers between -127 and 127.
+    Int8,
+    /// Values are mapped to their sign, 1 or -1.
+    Binary,
+}
+
+/// Struct representing the templates of the texts embedded by a model, such as
+/// `query: {text}` and `passage: {text}` for E5 models. `{text}` is replaced by the text,
+/// and a template without it is a prefix of the text.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct TemplatesConfig {
+    /// Template of search queries.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Template of the texts added to indexes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<String>,
+}
+
+/// Struct representing how concurrent requests to embed texts with a model are coalesced
+/// into batches, which run as a single forward pass of the model.
+#[derive(Debug, Clone, PartialEq, SmartDefault, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct BatchingConfig {
+    /// Maximum number of texts of a batch. A single request with more texts runs alone.
+    #[default(32)]
+    pub max_batch_size: usize,
+    /// Maximum time in milliseconds a batch waits for more requests once it has one.
+    /// Requests queued while the model runs are batched without waiting.
+    #[default(5)]
+    pub max_wait_ms: u64,
+}
+
+/// Struct representing the inference workers of a model. Each model has its own queue of
+/// requests, served by replicas of the model which each run in their own thread.
+#[derive(Debug, Clone, PartialEq, SmartDefault, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct WorkersConfig {
+    /// Number of replicas of the model, which each hold a copy of its weights in memory.
+    #[default(1)]
+    pub replicas: usize,
+    /// Maximum number of requests waiting for a replica of the model. Requests are rejected
+    /// once the queue is full.
+    #[default(100)]
+    pub queue_size: usize,
+    /// Maximum time in milliseconds a request waits for its result, queued or running.
+    #[default(30_000)]
+    pub request_timeout_ms: u64,
+}
+
+/// Struct representing when the models run with libtorch are loaded in memory, and unloaded.
+/// Unloaded models are loaded again on their next request.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct ModelLoadingConfig {
+    /// Load models on their first request, rather than at startup.
+    pub lazy: bool,
+    /// Unload models that served no request for this many seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_unload_secs: Option<u64>,
+    /// Maximum memory in megabytes of the loaded models. The least recently used models are
+    /// unloaded to make room for the models that are requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_budget_mb: Option<u64>,
+}
+
+/// Enum representing the transformer architectures custom models can be based on.
+/// The names are the `model_type` of the Hugging Face `config.json` of the model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, strum_macros::Display)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ModelArchitecture {
+    Bert,
+    DistilBert,
+    Roberta,
+    Albert,
+    T5,
+}
+
+/// Enum representing the ways token embeddings are pooled into a single text embedding.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolingKind {
+    /// Average of the token embeddings, ignoring padding.
+    #[default]
+    Mean,
+    /// Embedding of the first token.
+    Cls,
+}
+
+/// Struct representing a sentence-transformer model exported to ONNX.
+/// It includes the paths of the model file and of the Hugging Face `tokenizer.json`,
+/// and whether the embeddings are normalized. Token embeddings are pooled as configured
+/// by the `pooling` of the model, mean pooling by default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct OnnxModelConfig {
+    pub model_path: String,
+    pub tokenizer_path: String,
+    #[serde(default = "default_normalize")]
+    pub normalize: bool,
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
+/// Struct representing a server implementing the OpenAI embeddings API, such as vLLM or
+/// text-embeddings-inference. It includes the base URL of the API, the name of the model on
+/// the server, and how requests are authenticated.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
+pub struct OpenAICompatibleConfig {
+    /// Base URL of the API, e.g. `http://localhost:8080/v1`.
+    pub base_url: String,
+    /// Name of the model in requests, defaults to the name of the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Header the API key is sent in as is. By default, it's sent as a bearer token in the
+    /// `Authorization` header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_header: Option<String>,
+    /// Time in milliseconds a request has to complete before it's retried.
+    #[serde(default = "default_openai_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+/// Struct representing the configuration for OpenAI.
+/// It includes the API key required for accessing OpenAI's services, the Azure OpenAI
+/// resource that serves the models, if they are used through Azure, and the limits that
+/// requests are sent within.
+#[derive(Debug, Clone, SmartDefault, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub struct OpenAIConfig {
     pub api_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azure: Option<AzureOpenAIConfig>,
+    /// Maximum number of texts of a request, larger calls are split into several requests.
+    #[default(2048)]
+    #[serde(default = "default_openai_max_inputs_per_request")]
+    pub max_inputs_per_request: usize,
+    /// Maximum number of tokens of the texts of a request.
+    #[default(100_000)]
+    #[serde(default = "default_openai_max_tokens_per_request")]
+    pub max_tokens_per_request: usize,
+    /// Number of tokens sent per minute at most, usually the rate limit of the account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_per_minute: Option<u64>,
+    /// Number of times a request that is rate limited, or fails on the server, is retried.
+    #[default(5)]
+    #[serde(default = "default_openai_max_retries")]
+    pub max_retries: u32,
+    /// Time in milliseconds a request has to complete before it's retried.
+    #[default(60_000)]
+    #[serde(default = "default_openai_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_openai_max_inputs_per_request() -> usize {
+    OpenAIConfig::default().max_inputs_per_request
+}
+
+fn default_openai_max_tokens_per_request() -> usize {
+    OpenAIConfig::default().max_tokens_per_request
+}
+
+fn default_openai_max_retries() -> u32 {
+    OpenAIConfig::default().max_retries
+}
+
+fn default_openai_timeout_ms() -> u64 {
+    OpenAIConfig::default().timeout_ms
+}
+
+/// Struct representing an Azure OpenAI resource.
+/// It includes the endpoint of the resource, the version of the API, and the name of the
+/// deployment of each model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct AzureOpenAIConfig {
+    /// Endpoint of the resource, e.g. `https://my-resource.openai.azure.com`.
+    pub endpoint: String,
+    pub api_version: String,
+    /// Deployment of each model, by name of the model.
+    pub deployments: HashMap<String, String>,
+}
+
+/// Struct representing a model name served by an ordered list of models, which generate
+/// compatible embeddings. Requests go to the first model that serves them, and a model that
+/// keeps failing is skipped until its circuit breaker lets requests through again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FallbackChainConfig {
+    pub name: String,
+    /// Names of the models of the chain, in the order they're tried.
+    pub models: Vec<String>,
+    /// Time in milliseconds a model has to serve a request before the next one is tried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+/// Struct representing when a failing model of a fallback chain stops being called.
+/// After `failure_threshold` consecutive failures, the model is skipped for `open_ms`
+/// milliseconds, then called again, and skipped again if it still fails.
+#[derive(Debug, Clone, PartialEq, SmartDefault, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct CircuitBreakerConfig {
+    #[default(5)]
+    pub failure_threshold: u32,
+    #[default(30_000)]
+    pub open_ms: u64,
 }
 
 /// Enum representing the different kinds of index stores available for use.
@@ -106,6 +448,24 @@ pub struct VectorIndexConfig {
     pub db_url: String,
 }
 
+/// Struct representing the configuration of the cache of embeddings.
+/// Embeddings are stored by model and hash of the text, and the least recently used ones are
+/// evicted once the cache holds `max_entries` embeddings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct EmbeddingCacheConfig {
+    /// Database storing the cache, defaults to the database of the index configuration.
+    /// A local SQLite file such as `sqlite://embeddings.db?mode=rwc` keeps the cache on disk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub db_url: Option<String>,
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: u64,
+}
+
+fn default_cache_max_entries() -> u64 {
+    100_000
+}
+
 /// Struct representing the server configuration.
 /// It includes the address on which the server will listen, the available text embedding models, the OpenAI configuration (if applicable), and the vector index configuration.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -113,9 +473,27 @@ pub struct VectorIndexConfig {
 pub struct ServerConfig {
     pub listen_addr: String,
     pub available_models: Vec<EmbeddingModel>,
+    /// Never download models. Models that run locally must have a `path` or be ONNX models.
+    #[serde(default)]
+    pub offline: bool,
+    /// Start even if models fail to load, which are then reported as failed until they load.
+    #[serde(default)]
+    pub allow_unavailable_models: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub openai: Option<OpenAIConfig>,
     pub index_config: Option<VectorIndexConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_cache: Option<EmbeddingCacheConfig>,
+    /// Model names served by the first working model of a list.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback_chains: Vec<FallbackChainConfig>,
+    /// When models are loaded in memory, and unloaded.
+    #[serde(default)]
+    pub model_loading: ModelLoadingConfig,
+    /// Number of threads libtorch runs an operation with, shared by all the replicas of
+    /// the models. Defaults to the number of threads of libtorch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub intra_op_threads: Option<usize>,
 }
 
 impl Default for ServerConfig {
@@ -128,16 +506,25 @@ impl Default for ServerConfig {
                 EmbeddingModel {
                     model_kind: EmbeddingModelKind::AllMiniLmL12V2,
                     device_kind: DeviceKind::Cpu,
+                    ..Default::default()
                 },
                 EmbeddingModel {
                     model_kind: EmbeddingModelKind::OpenAIAda02,
                     device_kind: DeviceKind::Remote,
+                    ..Default::default()
                 },
             ],
+            offline: false,
+            allow_unavailable_models: false,
             openai: Some(OpenAIConfig {
                 api_key: OPENAI_DUMMY_KEY.into(),
+                ..Default::default()
             }),
             index_config: None,
+            embedding_cache: None,
+            fallback_chains: Vec::new(),
+            model_loading: ModelLoadingConfig::default(),
+            intra_op_threads: None,
         }
     }
 }
@@ -167,6 +554,7 @@ impl ServerConfig {
         if let Ok(openai_api_key) = std::env::var("OPENAI_API_KEY") {
             let openai_config = OpenAIConfig {
                 api_key: openai_api_key,
+                ..config.openai.unwrap_or_default()
             };
             config.openai = Some(openai_config);
         }
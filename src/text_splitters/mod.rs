@@ -299,4 +299,29 @@ mod tests {
         assert_eq!(chunks1[0], doc1);
         assert_eq!(chunks1.len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_splitter_with_hash_model() {
+        let config = ServerConfig {
+            available_models: vec![crate::EmbeddingModel {
+                model_kind: crate::EmbeddingModelKind::Hash,
+                ..Default::default()
+            }],
+            openai: None,
+            ..Default::default()
+        };
+        let embedding_router = Arc::new(EmbeddingRouter::new(Arc::new(config)).unwrap());
+        let splitter =
+            get_splitter(TextSplitterKind::NewLine, embedding_router, "hash".into()).unwrap();
+        let chunks = splitter
+            .split(
+                "embiid is
+the mvp",
+                6,
+                2,
+            )
+            .await
+            .unwrap();
+        assert_eq!(chunks, vec!["embiid", "id ist", "sthe m", " mvp"]);
+    }
 }
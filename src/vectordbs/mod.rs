@@ -1,37 +1,69 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, ops::RangeInclusive, sync::Arc};
 
 use anyhow::Result;
 use async_trait::async_trait;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
 
 use thiserror::Error;
 
-use crate::VectorIndexConfig;
+use crate::{PostProcessingConfig, Quantization, VectorIndexConfig};
 
 pub mod qdrant;
 
 use qdrant::QdrantDb;
 
 /// The type of distance metric to use when comparing vectors in the vector database.
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MetricKind {
     Dot,
     Euclidean,
     Cosine,
 }
 
+impl MetricKind {
+    /// Maps a score returned by the vector database to a similarity between 0 and 1,
+    /// so that scores of indexes using different metrics can be compared.
+    pub fn normalize_score(&self, score: f32) -> f32 {
+        match self {
+            MetricKind::Cosine => ((score + 1.0) / 2.0).clamp(0.0, 1.0),
+            MetricKind::Dot => 1.0 / (1.0 + (-score).exp()),
+            // Euclidean scores are distances, where lower is better
+            MetricKind::Euclidean => 1.0 / (1.0 + score.max(0.0)),
+        }
+    }
+}
+
 /// A request to create a new vector index in the vector database.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CreateIndexParams {
     pub name: String,
     pub vector_dim: u64,
     pub metric: MetricKind,
     pub unique_params: Option<Vec<String>>,
+    /// Transforms of the embeddings of the index, for models without post-processing.
+    /// `vector_dim` is the number of dimensions once they're applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_processing: Option<PostProcessingConfig>,
+    /// Quantization of the stored embeddings, by the index or else by its model, which the
+    /// vector database can store in less memory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<Quantization>,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct SearchResult {
     pub texts: String,
     pub metadata: serde_json::Value,
+    /// Identifier shared by all the chunks of the same document.
+    pub document_id: String,
+    /// Ordinal of the chunk within its document.
+    pub chunk: u64,
+    /// Identifier of the parent chunk in the document store, if the chunk has one.
+    pub parent_id: Option<String>,
+    /// Score of the result against the query, as returned by the vector database.
+    pub score: f32,
 }
 
 /// An enumeration of possible errors that can occur while interacting with the vector database.
@@ -65,6 +97,7 @@ pub trait VectorDb {
     async fn create_index(&self, index: CreateIndexParams) -> Result<(), VectorDbError>;
 
     /// Adds a vector embedding to the specified index, along with associated attributes.
+    /// `parent_ids` links each text to its parent chunk, and can be empty if there are none.
     async fn add_embedding(
         &self,
         index: &str,
@@ -72,6 +105,7 @@ pub trait VectorDb {
         texts: Vec<String>,
         attrs: HashMap<String, String>,
         hash_on: Vec<String>,
+        parent_ids: Vec<Option<String>>,
     ) -> Result<(), VectorDbError>;
 
     /// Searches for the nearest neighbors of a query vector in the specified index.
@@ -82,6 +116,14 @@ pub trait VectorDb {
         k: u64,
     ) -> Result<Vec<SearchResult>, VectorDbError>;
 
+    /// Returns the chunks of a document whose ordinals fall within `chunks`, ordered by ordinal.
+    async fn get_chunks(
+        &self,
+        index: &str,
+        document_id: &str,
+        chunks: RangeInclusive<u64>,
+    ) -> Result<Vec<SearchResult>, VectorDbError>;
+
     /// Deletes the specified vector index from the vector database.
     async fn drop_index(&self, index: String) -> Result<(), VectorDbError>;
 
@@ -91,9 +133,48 @@ pub trait VectorDb {
     fn name(&self) -> String;
 }
 
+/// Derives a stable identifier for a document from the metadata values it is
+/// hashed on, falling back to the content of the document when none of them are present.
+pub fn document_id(
+    texts: &[String],
+    attrs: &HashMap<String, String>,
+    hash_on: &[String],
+) -> String {
+    let mut hasher = Md5::new();
+    let mut hashed_attrs = false;
+    for key in hash_on {
+        if let Some(value) = attrs.get(key) {
+            hasher.update(value);
+            hashed_attrs = true;
+        }
+    }
+    if !hashed_attrs {
+        for text in texts {
+            hasher.update(text);
+        }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
 /// Creates a new vector database based on the specified configuration.
 pub fn create_vectordb(config: VectorIndexConfig) -> Result<VectorDBTS, VectorDbError> {
     match config.index_store {
         crate::IndexStoreKind::Qdrant => Ok(Arc::new(QdrantDb::new(config.qdrant_config.unwrap()))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::MetricKind;
+
+    #[test]
+    fn test_normalize_score() {
+        assert_eq!(MetricKind::Cosine.normalize_score(1.0), 1.0);
+        assert_eq!(MetricKind::Cosine.normalize_score(-1.0), 0.0);
+        assert_eq!(MetricKind::Dot.normalize_score(0.0), 0.5);
+        assert_eq!(MetricKind::Euclidean.normalize_score(0.0), 1.0);
+        assert!(
+            MetricKind::Euclidean.normalize_score(1.0) > MetricKind::Euclidean.normalize_score(2.0)
+        );
+    }
+}
@@ -3,18 +3,22 @@ use md5::{Digest, Md5};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
 
 use qdrant_client::{
     client::QdrantClient,
     client::{Payload, QdrantClientConfig},
     qdrant::{
-        vectors_config::Config, with_payload_selector::SelectorOptions, CreateCollection, Distance,
-        PointStruct, SearchPoints, VectorParams, VectorsConfig, WithPayloadSelector,
+        condition::ConditionOneOf, points_selector::PointsSelectorOneOf, quantization_config,
+        r#match::MatchValue, vectors_config::Config, with_payload_selector::SelectorOptions,
+        Condition, CreateCollection, Distance, FieldCondition, Filter, Match, PointStruct,
+        PointsSelector, QuantizationConfig, QuantizationType, Range, ScalarQuantization,
+        ScrollPoints, SearchPoints, VectorParams, VectorsConfig, WithPayloadSelector,
     },
 };
 
 use super::{CreateIndexParams, MetricKind, VectorDb, VectorDbError};
-use crate::{QdrantConfig, SearchResult};
+use crate::{QdrantConfig, Quantization, SearchResult};
 
 pub struct QdrantDb {
     qdrant_config: QdrantConfig,
@@ -25,6 +29,10 @@ pub struct QdrantPayload {
     pub text: String,
     pub chunk: u64,
     pub metadata: serde_json::Value,
+    #[serde(default)]
+    pub document_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
 }
 
 impl QdrantDb {
@@ -42,6 +50,48 @@ impl QdrantDb {
         Ok(client)
     }
 
+    /// Matches the points of all the chunks of a document.
+    fn document_condition(document_id: &str) -> Condition {
+        Condition {
+            condition_one_of: Some(ConditionOneOf::Field(FieldCondition {
+                key: "document_id".into(),
+                r#match: Some(Match {
+                    match_value: Some(MatchValue::Keyword(document_id.into())),
+                }),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Matches the points of the chunks of a document in a range of chunks.
+    fn chunk_condition(range: Range) -> Condition {
+        Condition {
+            condition_one_of: Some(ConditionOneOf::Field(FieldCondition {
+                key: "chunk".into(),
+                range: Some(range),
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn to_search_result(
+        payload: HashMap<String, qdrant_client::qdrant::Value>,
+        score: f32,
+    ) -> Result<SearchResult, VectorDbError> {
+        let json_value = serde_json::to_value(payload)
+            .map_err(|e| VectorDbError::IndexReadError(e.to_string()))?;
+        let qdrant_payload: QdrantPayload = serde_json::from_value(json_value)
+            .map_err(|e| VectorDbError::IndexReadError(e.to_string()))?;
+        Ok(SearchResult {
+            texts: qdrant_payload.text,
+            metadata: qdrant_payload.metadata,
+            document_id: qdrant_payload.document_id,
+            chunk: qdrant_payload.chunk,
+            parent_id: qdrant_payload.parent_id,
+            score,
+        })
+    }
+
     fn to_distance(metric_kind: MetricKind) -> Distance {
         match metric_kind {
             MetricKind::Cosine => Distance::Cosine,
@@ -49,6 +99,22 @@ impl QdrantDb {
             MetricKind::Euclidean => Distance::Euclid,
         }
     }
+
+    /// Stores quantized embeddings as 8-bit integers. Binary embeddings only take two values,
+    /// which scalar quantization keeps exactly as long as its quantile covers all of them.
+    fn to_quantization_config(quantization: Quantization) -> QuantizationConfig {
+        match quantization {
+            Quantization::Int8 | Quantization::Binary => QuantizationConfig {
+                quantization: Some(quantization_config::Quantization::Scalar(
+                    ScalarQuantization {
+                        r#type: QuantizationType::Int8.into(),
+                        quantile: Some(1.0),
+                        always_ram: Some(true),
+                    },
+                )),
+            },
+        }
+    }
 }
 
 #[async_trait]
@@ -68,7 +134,7 @@ impl VectorDb for QdrantDb {
                         size: index.vector_dim,
                         distance: Self::to_distance(index.metric).into(),
                         hnsw_config: None,
-                        quantization_config: None,
+                        quantization_config: index.quantization.map(Self::to_quantization_config),
                     })),
                 }),
                 ..Default::default()
@@ -85,36 +151,52 @@ impl VectorDb for QdrantDb {
         texts: Vec<String>,
         attrs: HashMap<String, String>,
         hash_on: Vec<String>,
+        parent_ids: Vec<Option<String>>,
     ) -> Result<(), VectorDbError> {
+        let document_id = super::document_id(&texts, &attrs, &hash_on);
         let mut points = Vec::<PointStruct>::new();
         for (i, text) in texts.iter().enumerate() {
             let payload: Payload = json!(QdrantPayload {
                 text: text.to_string(),
                 chunk: i as u64,
                 metadata: json!(attrs.clone()),
+                document_id: document_id.clone(),
+                parent_id: parent_ids.get(i).cloned().flatten(),
             })
             .try_into()
             .unwrap();
             let mut hasher = Md5::new();
-            if attrs.is_empty() {
-                hasher.update(text);
-            } else {
-                for (key, value) in attrs.iter() {
-                    if hash_on.contains(key) {
-                        hasher.update(value);
-                    }
-                }
-            }
+            hasher.update(&document_id);
+            hasher.update(i.to_string());
             let id = format!("{:x}", hasher.finalize());
 
             points.push(PointStruct::new(id, embeddings[i].clone(), payload));
         }
-        let _result = self
-            .create_client()
-            .await?
+        let chunks = points.len();
+        let client = self.create_client().await?;
+        let _result = client
             .upsert_points(&index, points, None)
             .await
-            .map_err(|e| VectorDbError::IndexCreationError(e.to_string()))?;
+            .map_err(|e| VectorDbError::IndexWriteError(e.to_string()))?;
+        // Points are identified by chunk, so the chunks of a previous version of the
+        // document past the last one of this version are deleted once it's written, and
+        // the previous version stays whole if it can't be
+        let stale_points = PointsSelector {
+            points_selector_one_of: Some(PointsSelectorOneOf::Filter(Filter {
+                must: vec![
+                    Self::document_condition(&document_id),
+                    Self::chunk_condition(Range {
+                        gte: Some(chunks as f64),
+                        ..Default::default()
+                    }),
+                ],
+                ..Default::default()
+            })),
+        };
+        client
+            .delete_points(index, &stale_points, None)
+            .await
+            .map_err(|e| VectorDbError::IndexWriteError(e.to_string()))?;
         Ok(())
     }
 
@@ -140,18 +222,54 @@ impl VectorDb for QdrantDb {
             .map_err(|e| VectorDbError::IndexReadError(e.to_string()))?;
         let mut documents: Vec<SearchResult> = Vec::new();
         for point in result.result {
-            let json_value = serde_json::to_value(point.payload)
-                .map_err(|e| VectorDbError::IndexReadError(e.to_string()))?;
-            let qdrant_payload: QdrantPayload = serde_json::from_value(json_value)
-                .map_err(|e| VectorDbError::IndexReadError(e.to_string()))?;
-            documents.push(SearchResult {
-                texts: qdrant_payload.text,
-                metadata: qdrant_payload.metadata,
-            });
+            documents.push(Self::to_search_result(point.payload, point.score)?);
         }
         Ok(documents)
     }
 
+    async fn get_chunks(
+        &self,
+        index: &str,
+        document_id: &str,
+        chunks: RangeInclusive<u64>,
+    ) -> Result<Vec<SearchResult>, VectorDbError> {
+        let filter = Filter {
+            must: vec![
+                Self::document_condition(document_id),
+                Self::chunk_condition(Range {
+                    gte: Some(*chunks.start() as f64),
+                    lte: Some(*chunks.end() as f64),
+                    ..Default::default()
+                }),
+            ],
+            ..Default::default()
+        };
+        let result = self
+            .create_client()
+            .await?
+            .scroll(&ScrollPoints {
+                collection_name: index.into(),
+                filter: Some(filter),
+                limit: Some(
+                    u32::try_from(chunks.end() - chunks.start())
+                        .unwrap_or(u32::MAX)
+                        .saturating_add(1),
+                ),
+                with_payload: Some(WithPayloadSelector {
+                    selector_options: Some(SelectorOptions::Enable(true)),
+                }),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| VectorDbError::IndexReadError(e.to_string()))?;
+        let mut documents: Vec<SearchResult> = Vec::new();
+        for point in result.result {
+            documents.push(Self::to_search_result(point.payload, 0.0)?);
+        }
+        documents.sort_by_key(|d| d.chunk);
+        Ok(documents)
+    }
+
     async fn drop_index(&self, index: String) -> Result<(), VectorDbError> {
         let result = self
             .create_client()
@@ -202,6 +320,8 @@ mod tests {
                 vector_dim: 2,
                 metric: crate::MetricKind::Cosine,
                 unique_params: None,
+                post_processing: None,
+                quantization: None,
             })
             .await
             .unwrap();
@@ -213,6 +333,7 @@ mod tests {
                 vec!["test".into()],
                 attrs,
                 vec![],
+                vec![],
             )
             .await
             .unwrap();
@@ -239,6 +360,8 @@ mod tests {
                 vector_dim: 2,
                 metric: crate::MetricKind::Cosine,
                 unique_params: Some(hash_on.clone()),
+                post_processing: None,
+                quantization: None,
             })
             .await
             .unwrap();
@@ -253,6 +376,7 @@ mod tests {
                 vec!["test".into()],
                 attrs.clone(),
                 hash_on.clone(),
+                vec![],
             )
             .await
             .unwrap();
@@ -263,6 +387,7 @@ mod tests {
                 vec!["test1".into()],
                 attrs,
                 hash_on.clone(),
+                vec![],
             )
             .await
             .unwrap();
@@ -271,4 +396,101 @@ mod tests {
 
         assert_eq!(num_elements, 1);
     }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_reinsertion_with_fewer_chunks() {
+        let index_name = "reinsertion-index";
+        let hash_on = vec!["url".to_string()];
+        let qdrant: VectorDBTS = Arc::new(QdrantDb::new(crate::QdrantConfig {
+            addr: "http://localhost:6334".into(),
+        }));
+        qdrant.drop_index(index_name.into()).await.unwrap();
+        qdrant
+            .create_index(CreateIndexParams {
+                name: index_name.into(),
+                vector_dim: 2,
+                metric: crate::MetricKind::Cosine,
+                unique_params: Some(hash_on.clone()),
+                post_processing: None,
+                quantization: None,
+            })
+            .await
+            .unwrap();
+        let attrs: HashMap<String, String> =
+            HashMap::from([("url".into(), "https://google.com".into())]);
+        qdrant
+            .add_embedding(
+                index_name,
+                vec![vec![0., 2.], vec![1., 2.], vec![2., 2.]],
+                vec!["a".into(), "b".into(), "c".into()],
+                attrs.clone(),
+                hash_on.clone(),
+                vec![],
+            )
+            .await
+            .unwrap();
+        qdrant
+            .add_embedding(
+                index_name,
+                vec![vec![1., 3.]],
+                vec!["d".into()],
+                attrs,
+                hash_on,
+                vec![],
+            )
+            .await
+            .unwrap();
+
+        let num_elements = qdrant.num_vectors(index_name).await.unwrap();
+
+        assert_eq!(num_elements, 1);
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_get_chunks() {
+        let index_name = "chunks-index";
+        let qdrant: VectorDBTS = Arc::new(QdrantDb::new(crate::QdrantConfig {
+            addr: "http://localhost:6334".into(),
+        }));
+        qdrant.drop_index(index_name.into()).await.unwrap();
+        qdrant
+            .create_index(CreateIndexParams {
+                name: index_name.into(),
+                vector_dim: 2,
+                metric: crate::MetricKind::Cosine,
+                unique_params: None,
+                post_processing: None,
+                quantization: None,
+            })
+            .await
+            .unwrap();
+        let texts: Vec<String> = (0..5).map(|i| format!("chunk {}", i)).collect();
+        let embeddings = (0..5).map(|i| vec![i as f32, 1.]).collect();
+        qdrant
+            .add_embedding(
+                index_name,
+                embeddings,
+                texts,
+                HashMap::new(),
+                vec![],
+                vec![],
+            )
+            .await
+            .unwrap();
+
+        let hit = qdrant
+            .search(index_name.into(), vec![2., 1.], 1)
+            .await
+            .unwrap()
+            .remove(0);
+        let chunks = qdrant
+            .get_chunks(index_name, &hit.document_id, 1..=3)
+            .await
+            .unwrap();
+        let ordinals: Vec<u64> = chunks.iter().map(|c| c.chunk).collect();
+        assert_eq!(ordinals, vec![1, 2, 3]);
+        assert_eq!(chunks[0].texts, "chunk 1");
+    }
 }